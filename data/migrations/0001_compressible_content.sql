@@ -0,0 +1,52 @@
+-- Allows tweets.content to hold zstd-compressed JSON as a BLOB.
+-- Queries must read the content through tweet_content(), which is registered by phog.
+-- The user ID and the screen name are kept in their own columns, so that seen_tweets and queries
+-- on them work in any SQLite client without decompressing the content.
+
+DROP VIEW IF EXISTS seen_tweets;
+
+CREATE TABLE tweets_new (
+    id INTEGER PRIMARY KEY,
+    status_id TEXT NOT NULL UNIQUE,
+    content TEXT NOT NULL CHECK (typeof(content) = 'blob' OR json_valid(content)),
+    user_id TEXT,
+    screen_name TEXT,
+    in_timeline BOOLEAN NOT NULL CHECK (in_timeline IN (0, 1)),
+    recorded_at DATETIME NOT NULL,
+    photos_downloaded_at DATETIME
+);
+INSERT INTO tweets_new (
+    id, status_id, content, user_id, screen_name, in_timeline, recorded_at, photos_downloaded_at
+)
+SELECT
+    id,
+    status_id,
+    content,
+    json_extract(content, '$.user.id_str'),
+    json_extract(content, '$.user.screen_name'),
+    in_timeline,
+    recorded_at,
+    photos_downloaded_at
+FROM tweets;
+DROP TABLE tweets;
+ALTER TABLE tweets_new RENAME TO tweets;
+CREATE INDEX IF NOT EXISTS index_on_tweets_status_id ON tweets (status_id);
+CREATE INDEX IF NOT EXISTS index_on_tweets_in_timeline ON tweets (in_timeline);
+CREATE INDEX IF NOT EXISTS index_on_tweets_photos_downloaded_at ON tweets (photos_downloaded_at);
+
+CREATE VIEW seen_tweets (
+    status_id,
+    user_id,
+    in_timeline
+) AS
+SELECT
+    status_id,
+    user_id,
+    in_timeline
+FROM tweets
+UNION
+SELECT
+    status_id,
+    user_id,
+    in_timeline
+FROM pruned_tweets;
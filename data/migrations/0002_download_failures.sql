@@ -0,0 +1,3 @@
+-- Counts how many download runs failed to download the photos of a tweet.
+
+ALTER TABLE tweets ADD COLUMN download_failures INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Records when the downloaded photos of a tweet were removed by `phog forget --media`.
+
+ALTER TABLE tweets ADD COLUMN media_removed_at DATETIME;
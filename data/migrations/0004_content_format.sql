@@ -0,0 +1,4 @@
+-- Records the shape of the JSON in tweets.content so that it can be read correctly if phog
+-- fetches tweets from other API versions in the future. All existing tweets are from the v1.1 API.
+
+ALTER TABLE tweets ADD COLUMN content_format TEXT NOT NULL DEFAULT 'v1.1';
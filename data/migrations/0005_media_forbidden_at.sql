@@ -0,0 +1,4 @@
+-- Records when the media of a tweet was refused with HTTP 403 Forbidden, e.g. because it is
+-- protected and the current login cannot access it. `phog download --skip-protected` skips them.
+
+ALTER TABLE tweets ADD COLUMN media_forbidden_at DATETIME;
@@ -0,0 +1,7 @@
+-- Stores the tweet text as the `text` column for tools that expect the `text` field of the
+-- compatibility mode. phog fetches tweets in the extended mode, which has only `full_text`.
+-- It is a column of its own so that it can be read without decompressing the content.
+
+ALTER TABLE tweets ADD COLUMN text TEXT;
+
+UPDATE tweets SET text = json_extract(tweet_content(content), '$.full_text');
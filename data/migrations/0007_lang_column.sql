@@ -0,0 +1,6 @@
+-- Stores the language of each tweet detected by Twitter, e.g. "en", "ja" or "und" (undetermined),
+-- so that `phog download --lang` can filter by it without decoding the content.
+
+ALTER TABLE tweets ADD COLUMN lang TEXT;
+
+UPDATE tweets SET lang = json_extract(tweet_content(content), '$.lang');
@@ -0,0 +1,7 @@
+-- Stores how each tweet was first recorded: "likes", "user" (from a user timeline) or "url" (from
+-- a URL or status ID), so that media can be downloaded into a directory per source.
+-- Tweets recorded before are known to be from a timeline only if they are in one.
+
+ALTER TABLE tweets ADD COLUMN source TEXT;
+
+UPDATE tweets SET source = 'user' WHERE in_timeline;
@@ -0,0 +1,4 @@
+-- Records when `phog record --refetch` found a recorded tweet gone upstream, i.e. deleted or
+-- protected. The content is kept, and such tweets are never pruned.
+
+ALTER TABLE tweets ADD COLUMN deleted_at DATETIME;
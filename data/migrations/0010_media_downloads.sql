@@ -0,0 +1,19 @@
+-- Records each downloaded photo of a tweet so that a photoset that failed partway is resumed
+-- from the missing photos. media_index is the 1-based index of the media entity in the tweet,
+-- not the index in the file names, which shifts when download.media-types changes.
+-- tweets.photos_downloaded_at is still set once all photos of a tweet are downloaded.
+-- Tweets downloaded before get a row per media entity, without the path and the hash.
+
+CREATE TABLE IF NOT EXISTS media_downloads (
+    status_id TEXT NOT NULL,
+    media_index INTEGER NOT NULL,
+    downloaded_at DATETIME NOT NULL,
+    path TEXT,
+    sha256 TEXT,
+    PRIMARY KEY (status_id, media_index)
+);
+
+INSERT OR IGNORE INTO media_downloads (status_id, media_index, downloaded_at)
+SELECT tweets.status_id, media.key + 1, tweets.photos_downloaded_at
+FROM tweets, json_each(tweet_media(tweet_content(tweets.content))) AS media
+WHERE tweets.photos_downloaded_at IS NOT NULL AND media.key IS NOT NULL;
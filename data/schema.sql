@@ -5,7 +5,7 @@ CREATE TABLE IF NOT EXISTS metadata (
     value TEXT NOT NULL CHECK (json_valid(value))
 );
 
-INSERT OR REPLACE INTO metadata (key, value) VALUES ('schema_version', json_quote(0));
+INSERT OR IGNORE INTO metadata (key, value) VALUES ('schema_version', json_quote(0));
 
 CREATE TABLE IF NOT EXISTS tweets (
     id INTEGER PRIMARY KEY,
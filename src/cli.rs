@@ -1,8 +1,10 @@
 use std::env;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 
 use crate::commands;
+use crate::common::{has_reached_deadline, set_deadline, set_dry_run};
 use crate::result::*;
 
 pub static APP_NAME: &str = clap::crate_name!();
@@ -15,6 +17,20 @@ pub fn run() -> Result<()> {
 #[derive(Debug, Parser)]
 #[clap(name = APP_NAME)]
 pub struct Cli {
+    #[clap(
+        long,
+        global = true,
+        help = "Prints what would be done without changing anything"
+    )]
+    dry_run: bool,
+    #[clap(
+        long,
+        global = true,
+        value_name = "duration",
+        parse(try_from_str = parse_deadline),
+        help = "Stops the whole command after the duration, e.g. 90s, 30m or 1h30m"
+    )]
+    deadline: Option<Instant>,
     #[clap(subcommand)]
     command: Option<Command>,
 }
@@ -22,16 +38,32 @@ pub struct Cli {
 impl Cli {
     pub fn new() -> Result<Self> {
         if env::args().count() < 2 {
-            Cli::parse_from(&[APP_NAME, "--help"]);
+            Cli::parse_from([APP_NAME, "--help"]);
             unreachable!("parse_from will exit because of --help");
         }
         Ok(Self::parse())
     }
 
     pub fn run(self) -> Result<()> {
-        log::trace!("command: {:?}", self.command);
+        log::trace!(
+            "command: {:?}, dry_run={}, deadline={:?}",
+            self.command,
+            self.dry_run,
+            self.deadline
+        );
+        set_dry_run(self.dry_run);
+        if self.dry_run {
+            eprintln!("Dry run: nothing will be changed.");
+        }
+        if let Some(deadline) = self.deadline {
+            set_deadline(deadline);
+        }
         if let Some(command) = self.command {
-            return command.run();
+            command.run()?;
+        }
+        if has_reached_deadline() {
+            return Err(format_err!("Stopped at the deadline before finishing"))
+                .wrap_err(ErrorKind::Deadline);
         }
         Ok(())
     }
@@ -46,13 +78,21 @@ enum Command {
     #[clap(about = "Runs record and download at once")]
     Get(commands::get::Args),
     #[clap(about = "Prints the database info")]
-    Info,
+    Info(commands::info::Args),
     #[clap(about = "Logs in to Twitter")]
     Login(commands::login::Args),
     #[clap(about = "Logs out from Twitter")]
-    Logout,
+    Logout(commands::logout::Args),
+    #[clap(about = "Backs up the database and migrates it to the latest schema")]
+    Migrate(commands::migrate::Args),
+    #[clap(about = "Lists inactive users and removes them from the config")]
+    PruneUsers(commands::prune_users::Args),
     #[clap(about = "Records tweets from various sources")]
     Record(commands::record::Args),
+    #[clap(about = "Checks the database and recovers it if corrupted")]
+    Repair(commands::repair::Args),
+    #[clap(about = "Prints the stored JSON of tweets")]
+    Tweets(commands::tweets::Args),
 }
 
 impl Command {
@@ -62,10 +102,81 @@ impl Command {
             Self::Download(args) => download::run(args),
             Self::Forget(args) => forget::run(args),
             Self::Get(args) => get::run(args),
-            Self::Info => info::run(),
+            Self::Info(args) => info::run(args),
             Self::Login(args) => login::run(args),
-            Self::Logout => logout::run(),
+            Self::Logout(args) => logout::run(args),
+            Self::Migrate(args) => migrate::run(args),
+            Self::PruneUsers(args) => prune_users::run(args),
             Self::Record(args) => commands::record::run(args),
+            Self::Repair(args) => repair::run(args),
+            Self::Tweets(args) => tweets::run(args),
+        }
+    }
+}
+
+/// Parses a duration with `parse_duration` and returns the time that long from now.
+fn parse_deadline(s: &str) -> std::result::Result<Instant, String> {
+    Instant::now()
+        .checked_add(parse_duration(s)?)
+        .ok_or_else(|| "duration is too long".to_owned())
+}
+
+/// Parses a duration made of numbers with a unit of h, m or s each, e.g. "1h30m". A bare number
+/// is in seconds.
+fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    if s.is_empty() {
+        return Err("duration is empty".to_owned());
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut secs: u64 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
         }
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(format!("unknown unit {:?} in duration", c)),
+        };
+        let n: u64 = number
+            .parse()
+            .map_err(|_| format!("missing number before {:?} in duration", c))?;
+        secs = n
+            .checked_mul(unit)
+            .and_then(|n| secs.checked_add(n))
+            .ok_or_else(|| "duration is too long".to_owned())?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err("missing unit at the end of duration".to_owned());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_deadline, parse_duration};
+
+    #[test]
+    fn durations_parsed() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("1h30").is_err());
+        assert!(parse_duration("18446744073709551615h").is_err());
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+        assert!(parse_deadline("18446744073709551615").is_err());
     }
 }
@@ -1,7 +1,6 @@
+use std::fmt::Display;
 use std::iter;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver};
-use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -10,44 +9,35 @@ use copypasta::{nop_clipboard::NopClipboardContext as ClipboardContext, Clipboar
 #[cfg(any(windows, target_os = "macos"))]
 use copypasta::{ClipboardContext, ClipboardProvider};
 
+use crate::common::is_past_deadline;
 use crate::result::*;
+use crate::shutdown::ShutdownFlag;
+
+/// Some clipboard backends fail now and then, e.g. while another app is writing to it.
+const READ_ATTEMPTS: u32 = 3;
+const READ_RETRY_INTERVAL: Duration = Duration::from_millis(50);
 
 pub fn spawn_watcher() -> Receiver<Option<String>> {
     let mut changes_iter = {
-        let mut text = String::new();
-        iter::from_fn(move || {
-            let new_text = read().unwrap_or_else(|e| {
-                log::error!("clipboard error: {}", e);
-                String::new()
-            });
-            if new_text != text {
-                text = new_text;
-                Some(text.clone())
-            } else {
-                None
-            }
-        })
+        let mut detector = ChangeDetector::default();
+        iter::from_fn(move || detector.update(read()))
     };
 
-    let stopped = Arc::new(AtomicBool::new(false));
-    let stop = stopped.clone();
-    let handle = unsafe {
-        signal_hook::low_level::register(signal_hook::consts::SIGINT, move || {
-            stop.store(true, Ordering::SeqCst);
-        })
-    }
-    .expect("Failed to set Ctrl-C handler");
+    // Dropped when the thread exits, which restores the default signal handlers.
+    let shutdown = ShutdownFlag::register().expect("Failed to set signal handlers");
 
     let (tx, rx) = channel();
 
     thread::spawn(move || loop {
         if let Some(text) = changes_iter.next() {
-            tx.send(Some(text)).expect("send must succeed");
+            if tx.send(Some(text)).is_err() {
+                // The receiver has stopped watching.
+                break;
+            }
         }
 
-        if stopped.load(Ordering::SeqCst) {
+        if shutdown.is_requested() || is_past_deadline() {
             tx.send(None).expect("send must succeed");
-            signal_hook::low_level::unregister(handle);
             break;
         }
 
@@ -57,8 +47,106 @@ pub fn spawn_watcher() -> Receiver<Option<String>> {
     rx
 }
 
+/// Reads the text in the clipboard, retrying a few times before giving up.
 pub fn read() -> Result<String> {
     let mut context = ClipboardContext::new()
         .map_err(|e| format_err!("Could not get clipboard context: {}", e))?;
-    Ok(context.get_contents().unwrap_or_else(|_| "".to_owned()))
+    retry(|| context.get_contents())
+}
+
+fn retry<E: Display>(
+    mut get_contents: impl FnMut() -> std::result::Result<String, E>,
+) -> Result<String> {
+    let mut attempts = 1;
+    loop {
+        match get_contents() {
+            Ok(text) => return Ok(text),
+            Err(e) if attempts < READ_ATTEMPTS => {
+                log::debug!(
+                    "retrying clipboard read; attempts={}, error={}",
+                    attempts,
+                    e
+                );
+                attempts += 1;
+                thread::sleep(READ_RETRY_INTERVAL);
+            }
+            Err(e) => bail!("Could not read the clipboard: {}", e),
+        }
+    }
+}
+
+/// Tells changes of the clipboard. A failed read is not a change, so the text before and after it
+/// is not reported twice.
+#[derive(Default)]
+struct ChangeDetector {
+    text: String,
+}
+
+impl ChangeDetector {
+    fn update(&mut self, read: Result<String>) -> Option<String> {
+        match read {
+            Ok(new_text) if new_text != self.text => {
+                self.text = new_text;
+                Some(self.text.clone())
+            }
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("clipboard error: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{retry, ChangeDetector};
+    use crate::result::*;
+
+    #[test]
+    fn read_retried() {
+        let mut failures = 2;
+        let result = retry(|| {
+            if failures > 0 {
+                failures -= 1;
+                Err("busy")
+            } else {
+                Ok("text".to_owned())
+            }
+        });
+        assert_eq!(result.unwrap(), "text");
+
+        assert!(retry(|| Err::<String, _>("busy")).is_err());
+    }
+
+    #[test]
+    fn failed_read_is_not_a_change() {
+        let mut detector = ChangeDetector::default();
+        assert_eq!(detector.update(Ok("a".to_owned())), Some("a".to_owned()));
+        assert_eq!(detector.update(Err(format_err!("busy"))), None);
+        assert_eq!(detector.update(Ok("a".to_owned())), None);
+        assert_eq!(detector.update(Ok(String::new())), Some(String::new()));
+    }
+}
+
+#[cfg(all(test, unix))]
+mod watcher_tests {
+    use std::time::Duration;
+
+    use signal_hook::consts::SIGTERM;
+
+    use super::spawn_watcher;
+
+    #[test]
+    fn sigterm_stops_watcher() {
+        let rx = spawn_watcher();
+        signal_hook::low_level::raise(SIGTERM).unwrap();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Some(_text)) => continue,
+                Ok(None) => break,
+                Err(e) => panic!("watcher must stop on SIGTERM: {}", e),
+            }
+        }
+    }
 }
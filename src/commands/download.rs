@@ -1,77 +1,890 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::{ArgEnum, Parser};
+use indicatif::HumanBytes;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::Serialize;
 
 use crate::commands;
-use crate::common::count;
+use crate::common::{
+    self, count, has_passed_deadline, is_dry_run, mark_deadline_reached, open_database,
+};
 use crate::config;
-use crate::database::Connection;
-use crate::downloader::{build_photo_path, Downloader};
+use crate::database::{Connection, Photoset, TweetSource};
+use crate::downloader::{
+    build_photo_path, DirLayout, DownloadOutcome, Downloader, DEFAULT_MAX_RETRIES,
+};
 use crate::result::*;
+use crate::shutdown::ShutdownFlag;
 
 static AUTO_GC_THRESHOLD: u64 = 4096;
 
+/// `--large-only` skips photosets whose photos are all shorter than this on the long edge.
+const LARGE_ONLY_MIN_LONG_EDGE: u64 = 600;
+
+/// A rough size of a photo to tell if the pending ones may not fit in the free space.
+const ESTIMATED_BYTES_PER_PHOTO: u64 = 512 * 1024;
+
+/// `--interactive` cuts tweet texts to this many characters in the list.
+const INTERACTIVE_SNIPPET_CHARS: usize = 50;
+
 #[derive(Debug, Parser)]
 pub struct Args {
     #[clap(long, help = "Sets download directory")]
     pub dir: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Retries photosets excluded after failing to download repeatedly"
+    )]
+    pub retry_failed: bool,
+    #[clap(
+        long,
+        help = "Prints the results as a JSON array instead of progress messages (skips auto gc)"
+    )]
+    pub json: bool,
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "recorded",
+        value_name = "order",
+        help = "Sets the order to download photosets in (newest and oldest are by tweet time)"
+    )]
+    pub order: Order,
+    #[clap(
+        long,
+        value_name = "n",
+        help = "Shuffles with the seed for --order random so that the order can be replayed"
+    )]
+    pub seed: Option<u64>,
+    #[clap(
+        long,
+        value_name = "bytes",
+        help = "Exits without downloading if the download directory has less free space than this"
+    )]
+    pub require_space: Option<u64>,
+    #[clap(long, help = "Exits with an error if any photoset fails to download")]
+    pub strict: bool,
+    #[clap(
+        long,
+        help = "Skips photosets whose photos are all smaller than 600px on the long edge"
+    )]
+    pub large_only: bool,
+    #[clap(
+        long,
+        requires = "large-only",
+        help = "Marks photosets skipped by --large-only as downloaded so they are never downloaded"
+    )]
+    pub skip_small_permanently: bool,
+    #[clap(
+        long,
+        help = "Skips photosets whose media was refused with HTTP 403 (e.g. protected) before"
+    )]
+    pub skip_protected: bool,
+    #[clap(
+        long,
+        require_value_delimiter = true,
+        use_value_delimiter = true,
+        value_name = "lang",
+        help = "Downloads only photosets of tweets in the languages, e.g. en,ja (und for undetermined)"
+    )]
+    pub lang: Option<Vec<String>>,
+    #[clap(
+        long,
+        value_name = "file",
+        help = "Writes each file saved in this run as a line of JSON to the file"
+    )]
+    pub manifest: Option<PathBuf>,
+    #[clap(
+        long,
+        requires = "manifest",
+        help = "Appends to the manifest instead of overwriting it"
+    )]
+    pub append_manifest: bool,
+    #[clap(
+        long,
+        conflicts_with = "json",
+        help = "Lists pending photosets and asks which of them to download"
+    )]
+    pub interactive: bool,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Order {
+    /// In the order the tweets were recorded.
+    Recorded,
+    Newest,
+    Oldest,
+    Random,
 }
 
 pub fn run(args: Args) -> Result<()> {
-    let dir = set_download_dir(args.dir)?;
-    println!("Downloading photos to {:?}.", dir);
+    ensure!(
+        args.seed.is_none() || args.order == Order::Random,
+        "--seed requires --order random"
+    );
+    // Checked before --retry-failed and --skip-small-permanently change the database.
+    ensure!(
+        !args.interactive || (atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stdout)),
+        "--interactive requires a terminal"
+    );
+    let json = args.json;
+    let dirs = DownloadDirs::resolve(args.dir)?;
+    if !json {
+        println!("Downloading photos to {:?}.", dirs.default);
+        if let Some(dir) = &dirs.likes {
+            println!("Downloading photos from likes to {:?}.", dir);
+        }
+        if let Some(dir) = &dirs.user {
+            println!("Downloading photos from user timelines to {:?}.", dir);
+        }
+    }
+
+    let db = Rc::new(open_database()?);
+
+    let retried_rowids = if args.retry_failed && !is_dry_run() {
+        let rowids = db.reset_download_failures()?;
+        if !rowids.is_empty() && !json {
+            println!(
+                "Retrying {} that failed previously.",
+                count(rowids.len(), "photoset")
+            );
+        }
+        rowids
+    } else {
+        vec![]
+    };
+
+    let selection = db.select_not_downloaded_photos(args.skip_protected)?;
+    if selection.unparseable > 0 {
+        eprintln!(
+            "Warning: Skipped {} with unparseable media.",
+            count(selection.unparseable, "tweet")
+        );
+    }
+    if selection.excluded_failed > 0 {
+        eprintln!(
+            "Warning: Skipped {} that failed to download repeatedly. Use --retry-failed to retry.",
+            count(selection.excluded_failed, "photoset")
+        );
+    }
+    if selection.excluded_retweets > 0 && !json {
+        println!(
+            "Skipped {} (download.skip-retweets is on).",
+            count(selection.excluded_retweets, "retweet")
+        );
+    }
+    if selection.excluded_forbidden > 0 && !json {
+        println!(
+            "Skipped {}.",
+            count(selection.excluded_forbidden, "protected photoset")
+        );
+    }
+    if selection.duplicates > 0 && !json {
+        println!(
+            "Merged {} with the same photos as others.",
+            count(selection.duplicates, "photoset")
+        );
+    }
+    let mut photosets = selection.photosets;
+    sort_photosets(&mut photosets, args.order, args.seed);
+
+    if let Some(langs) = &args.lang {
+        let (matched, others) = partition_by_lang(photosets, langs);
+        photosets = matched;
+        if !others.is_empty() && !json {
+            println!(
+                "Skipped {} in other languages.",
+                count(others.len(), "photoset")
+            );
+        }
+    }
+
+    if args.large_only {
+        let (large, small) = partition_by_size(photosets, LARGE_ONLY_MIN_LONG_EDGE);
+        photosets = large;
+        if !small.is_empty() {
+            if args.skip_small_permanently && !is_dry_run() {
+                for photoset in &small {
+                    for rowid in photoset.rowids() {
+                        db.set_photos_downloaded_at(rowid)?;
+                    }
+                }
+                if !json {
+                    println!(
+                        "Skipped {} permanently.",
+                        count(small.len(), "small photoset")
+                    );
+                }
+            } else if !json {
+                println!("Skipped {}.", count(small.len(), "small photoset"));
+            }
+        }
+    }
 
-    let db = Connection::open(config::database_path())?;
-    db.create()?;
+    if args.interactive && !photosets.is_empty() {
+        photosets = select_interactively(&db, photosets)?;
+    }
 
-    let photosets = db.select_not_downloaded_photos()?;
+    if is_dry_run() && json {
+        let planned: Vec<_> = photosets
+            .iter()
+            .map(|photoset| PlannedPhotoset {
+                status_id: &photoset.id_str,
+                screen_name: &photoset.screen_name,
+                files: photoset
+                    .pending_photos()
+                    .map(|(index, photo_url)| PlannedFile {
+                        index,
+                        media_index: photoset.media_index(index),
+                        url: photo_url,
+                        path: dirs.photo_path(photoset, photo_url, index),
+                    })
+                    .collect(),
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&planned).expect("plan must be serializable")
+        );
+        return Ok(());
+    }
+    if is_dry_run() {
+        if args.retry_failed && selection.excluded_failed > 0 {
+            println!(
+                "Would retry {} that failed previously.",
+                count(selection.excluded_failed, "photoset")
+            );
+        }
+        println!("Would download {}.", count(photosets.len(), "photoset"));
+        for photoset in &photosets {
+            for (index, photo_url) in photoset.pending_photos() {
+                let path = dirs.relative_photo_path(photoset, photo_url, index);
+                println!("  {}", path.to_string_lossy());
+            }
+        }
+        return Ok(());
+    }
 
     if photosets.is_empty() {
-        println!("No photos to download.");
+        if !json {
+            println!("No photos to download.");
+        }
+        // Writes the manifest anyway so that it never lists the files of a previous run.
+        save_manifest(args.manifest.as_deref(), args.append_manifest, &[], json)?;
+        if json {
+            println!("[]");
+            return Ok(());
+        }
         run_gc_if_needed(db.count_tweets()?)?;
         return Ok(());
     }
 
-    println!("Downloading {}.", count(photosets.len(), "photoset"));
+    check_free_space(&dirs, &photosets, args.require_space)?;
+
+    if !json {
+        println!("Downloading {}.", count(photosets.len(), "photoset"));
+    }
+
+    let settings = config::settings()?;
+    // The earlier of download.deadline-secs and the global --deadline.
+    let deadline = settings
+        .download
+        .deadline_secs
+        .map(|secs| Instant::now() + Duration::from_secs(secs))
+        .into_iter()
+        .chain(common::deadline())
+        .min();
 
     let downloader = Downloader::new(
         photosets,
-        Box::new(move |photoset| {
-            for (index, photo_url) in (1..).zip(photoset.photo_urls.iter()) {
-                let path = build_photo_path(photoset, photo_url, index);
-                println!("Downloaded {}", path.to_string_lossy());
+        Box::new({
+            let dirs = dirs.clone();
+            move |photoset, photo_url, index| dirs.photo_path(photoset, photo_url, index)
+        }),
+        Box::new({
+            let db = db.clone();
+            let dirs = dirs.clone();
+            move |photoset| {
+                if !json {
+                    for (index, photo_url) in photoset.pending_photos() {
+                        let path = dirs.relative_photo_path(photoset, photo_url, index);
+                        println!("Downloaded {}", path.to_string_lossy());
+                    }
+                }
+                for rowid in photoset.rowids() {
+                    if let Err(e) = db.set_photos_downloaded_at(rowid) {
+                        log::debug!("set_photos_downloaded_at failed; error={:?}", e);
+                        eprintln!(
+                            "Warning: Failed to mark photoset as downloaded. (status_id = {})",
+                            photoset.id_str
+                        );
+                    }
+                }
+            }
+        }),
+    )
+    .with_on_downloaded_photo(Box::new({
+        let db = db.clone();
+        move |photoset, file| {
+            let sha256 = file.sha256.as_deref();
+            if let Err(e) =
+                db.set_media_downloaded(&photoset.id_str, file.media_index, &file.path, sha256)
+            {
+                log::debug!("set_media_downloaded failed; error={:?}", e);
             }
-            if let Err(e) = db.set_photos_downloaded_at(photoset.rowid) {
-                log::debug!("set_photos_downloaded_at failed; error={:?}", e);
+        }
+    }))
+    .with_dir(dirs.default)
+    .with_max_rate_bytes(settings.download.max_rate_bytes)
+    .with_max_file_bytes(settings.download.max_file_bytes)
+    .with_max_retries(settings.download.max_retries.unwrap_or(DEFAULT_MAX_RETRIES))
+    .with_deadline(deadline)
+    .with_shutdown_flag(ShutdownFlag::register()?);
+    let summary = downloader.start()?;
+    // Releases the shutdown flag so that Ctrl-C during the maintenance below is handled by its own
+    // prompt instead of the downloader's handler.
+    drop(downloader);
+    // download.deadline-secs alone stops only the download, not the whole command.
+    if summary.skipped_by_deadline > 0 && has_passed_deadline() {
+        mark_deadline_reached();
+    }
+
+    for rowid in summary.failed_rowids() {
+        if let Err(e) = db.increment_download_failures(rowid) {
+            log::debug!("increment_download_failures failed; error={:?}", e);
+        }
+    }
+    let forbidden_rowids = summary.forbidden_rowids();
+    for &rowid in &forbidden_rowids {
+        if let Err(e) = db.set_media_forbidden_at(rowid) {
+            log::debug!("set_media_forbidden_at failed; error={:?}", e);
+        }
+    }
+    save_manifest(
+        args.manifest.as_deref(),
+        args.append_manifest,
+        &summary.outcomes,
+        json,
+    )?;
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&summary.outcomes).expect("outcomes must be serializable")
+        );
+        if summary.skipped_by_deadline > 0 {
+            eprintln!(
+                "Skipped {} due to the deadline.",
+                count(summary.skipped_by_deadline, "photoset")
+            );
+        }
+        if summary.skipped_by_shutdown > 0 {
+            eprintln!(
+                "Stopped. Skipped {}.",
+                count(summary.skipped_by_shutdown, "photoset")
+            );
+        }
+        if summary.skipped_by_size > 0 {
+            eprintln!(
+                "Skipped {} with files larger than download.max-file-bytes.",
+                count(summary.skipped_by_size, "photoset")
+            );
+        }
+        if args.strict && summary.failed > 0 {
+            return Err(format_err!(
+                "Failed to download {}",
+                count(summary.failed, "photoset")
+            ))
+            .wrap_err(ErrorKind::PartialFailure);
+        }
+        return Ok(());
+    }
+
+    if summary.failed > 0 {
+        // Report inaccessible media separately from missing media.
+        let not_forbidden = summary.failed - forbidden_rowids.len();
+        if not_forbidden > 0 {
+            eprintln!(
+                "Warning: Failed to download {}.",
+                count(not_forbidden, "photoset")
+            );
+        }
+        if !forbidden_rowids.is_empty() {
+            eprintln!(
+                "Warning: Could not access {} (HTTP 403). They may be protected; use --skip-protected to skip them.",
+                count(forbidden_rowids.len(), "photoset")
+            );
+        }
+        for failure in &summary.failures {
+            eprintln!(
+                "  {}: {} ({})",
+                failure.path.to_string_lossy(),
+                failure.reason,
+                failure.url
+            );
+        }
+    }
+    if summary.skipped_by_deadline > 0 {
+        println!(
+            "Skipped {} due to the deadline.",
+            count(summary.skipped_by_deadline, "photoset")
+        );
+    }
+    if summary.skipped_by_shutdown > 0 {
+        println!(
+            "Stopped. Skipped {}.",
+            count(summary.skipped_by_shutdown, "photoset")
+        );
+    }
+    if summary.skipped_by_size > 0 {
+        println!(
+            "Skipped {} with files larger than download.max-file-bytes.",
+            count(summary.skipped_by_size, "photoset")
+        );
+    }
+    if !retried_rowids.is_empty() {
+        let succeeded = db.count_downloaded(&retried_rowids)?;
+        println!(
+            "Retried {}: {} succeeded, {} did not.",
+            count(retried_rowids.len(), "previously failed photoset"),
+            succeeded,
+            retried_rowids.len() - succeeded
+        );
+    }
+    println!("Done.");
+
+    // The user asked to stop, so don't start the maintenance either.
+    if summary.skipped_by_shutdown == 0 {
+        run_gc_if_needed(db.count_tweets()?)?;
+    }
+
+    if args.strict && summary.failed > 0 {
+        return Err(format_err!(
+            "Failed to download {}",
+            count(summary.failed, "photoset")
+        ))
+        .wrap_err(ErrorKind::PartialFailure);
+    }
+
+    Ok(())
+}
+
+/// `seed` makes `Order::Random` reproducible with the same version of phog.
+fn sort_photosets(photosets: &mut [Photoset], order: Order, seed: Option<u64>) {
+    // Status IDs are Snowflake IDs, which increase with the time the tweets were created, so
+    // they can be used for ordering by time without the created_at column.
+    fn status_id(photoset: &Photoset) -> u64 {
+        photoset.id_str.parse().unwrap_or(0)
+    }
+
+    match order {
+        Order::Recorded => (),
+        Order::Newest => photosets.sort_by_key(|s| std::cmp::Reverse(status_id(s))),
+        Order::Oldest => photosets.sort_by_key(status_id),
+        Order::Random => match seed {
+            Some(seed) => photosets.shuffle(&mut StdRng::seed_from_u64(seed)),
+            None => photosets.shuffle(&mut rand::thread_rng()),
+        },
+    }
+}
+
+/// Splits photosets into ones with a photo at least `min_long_edge` pixels on the long edge and
+/// the rest. Photosets with unknown dimensions are kept.
+fn partition_by_size(
+    photosets: Vec<Photoset>,
+    min_long_edge: u64,
+) -> (Vec<Photoset>, Vec<Photoset>) {
+    photosets.into_iter().partition(|s| {
+        s.max_long_edge
+            .map(|edge| edge >= min_long_edge)
+            .unwrap_or(true)
+    })
+}
+
+/// A photoset printed by `--dry-run --json`, shaped like the outcomes printed by `--json`.
+#[derive(Debug, Serialize)]
+struct PlannedPhotoset<'a> {
+    status_id: &'a str,
+    screen_name: &'a str,
+    files: Vec<PlannedFile<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct PlannedFile<'a> {
+    index: usize,
+    media_index: usize,
+    url: &'a str,
+    path: PathBuf,
+}
+
+/// A line of the manifest written by `--manifest`.
+#[derive(Debug, Serialize)]
+struct ManifestEntry<'a> {
+    status_id: &'a str,
+    screen_name: &'a str,
+    media_index: usize,
+    url: &'a str,
+    path: &'a Path,
+    bytes: Option<u64>,
+    sha256: Option<&'a str>,
+}
+
+fn save_manifest(
+    manifest: Option<&Path>,
+    appends: bool,
+    outcomes: &[DownloadOutcome],
+    json: bool,
+) -> Result<()> {
+    if let Some(path) = manifest {
+        let n = write_manifest(path, outcomes, appends)
+            .with_context(|| format!("Could not write the manifest to {:?}", path))?;
+        if !json {
+            println!("Listed {} in the manifest {:?}.", count(n, "file"), path);
+        }
+    }
+    Ok(())
+}
+
+/// Writes the saved files of the outcomes, including ones saved before a photoset failed, to the
+/// manifest as JSON Lines and returns the number of them. The manifest is replaced atomically, so a reader never sees a partial one.
+fn write_manifest(path: &Path, outcomes: &[DownloadOutcome], appends: bool) -> Result<usize> {
+    let mut content = if appends {
+        match fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        }
+    } else {
+        vec![]
+    };
+    if content.last().is_some_and(|&b| b != b'\n') {
+        content.push(b'\n');
+    }
+
+    let mut n = 0;
+    // Failed photosets may have saved some of their photos.
+    for outcome in outcomes {
+        for file in &outcome.files {
+            let entry = ManifestEntry {
+                status_id: &outcome.status_id,
+                screen_name: &outcome.screen_name,
+                media_index: file.media_index,
+                url: &file.url,
+                path: &file.path,
+                bytes: file.bytes,
+                sha256: file.sha256.as_deref(),
+            };
+            serde_json::to_writer(&mut content, &entry)?;
+            content.push(b'\n');
+            n += 1;
+        }
+    }
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    fs::write(&temp_path, &content)?;
+    fs::rename(&temp_path, path)?;
+    Ok(n)
+}
+
+/// Splits photosets into ones of tweets in any of `langs` and the rest, ignoring case. Tweets
+/// without a language, e.g. ones recorded before it was stored, count as `und` (undetermined).
+fn partition_by_lang(photosets: Vec<Photoset>, langs: &[String]) -> (Vec<Photoset>, Vec<Photoset>) {
+    photosets.into_iter().partition(|s| {
+        let lang = s.lang.as_deref().unwrap_or("und");
+        langs.iter().any(|l| l.eq_ignore_ascii_case(lang))
+    })
+}
+
+/// Prints the photosets numbered from 1 and returns those the user picks on stdin, which must be a
+/// terminal.
+fn select_interactively(db: &Connection, photosets: Vec<Photoset>) -> Result<Vec<Photoset>> {
+    let rowids: Vec<i64> = photosets.iter().map(|p| p.rowid).collect();
+    let texts = db.get_texts(&rowids)?;
+    for (number, photoset) in (1..).zip(photosets.iter()) {
+        let text = texts.get(&photoset.rowid).map(String::as_str).unwrap_or("");
+        println!(
+            "{:>4}. {} @{} ({}) {}",
+            number,
+            photoset.id_str,
+            photoset.screen_name,
+            count(photoset.photo_urls.len(), "photo"),
+            snippet(text, INTERACTIVE_SNIPPET_CHARS)
+        );
+    }
+
+    loop {
+        print!("Photosets to download (e.g. 1,3-5, all, or none): ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            bail!("No photosets selected");
+        }
+        match parse_selection(&line, photosets.len()) {
+            Ok(selected) => {
+                return Ok(photosets
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(i, _)| selected.contains(&(i + 1)))
+                    .map(|(_, photoset)| photoset)
+                    .collect());
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+/// Parses comma-separated numbers and ranges from 1 to `len`, or "all" or "none".
+fn parse_selection(input: &str, len: usize) -> Result<BTreeSet<usize>> {
+    let input = input.trim();
+    match input.to_lowercase().as_str() {
+        "all" => return Ok((1..=len).collect()),
+        "none" | "" => return Ok(BTreeSet::new()),
+        _ => (),
+    }
+
+    let parse_number = |s: &str| -> Result<usize> {
+        let n: usize = s
+            .trim()
+            .parse()
+            .map_err(|_| format_err!("Invalid number: {:?}", s.trim()))?;
+        ensure!(1 <= n && n <= len, "Out of range 1-{}: {}", len, n);
+        Ok(n)
+    };
+    let mut selected = BTreeSet::new();
+    for part in input.split(',').filter(|part| !part.trim().is_empty()) {
+        if let Some((start, end)) = part.split_once('-') {
+            let (start, end) = (parse_number(start)?, parse_number(end)?);
+            ensure!(start <= end, "Invalid range: {}", part.trim());
+            selected.extend(start..=end);
+        } else {
+            selected.insert(parse_number(part)?);
+        }
+    }
+    Ok(selected)
+}
+
+/// Returns the text on one line, cut to `max_chars` characters.
+fn snippet(text: &str, max_chars: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= max_chars {
+        text
+    } else {
+        let mut cut: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+        cut.push('…');
+        cut
+    }
+}
+
+/// Warns if the photos may not fit in the free space of their download directories, or fails if
+/// any directory has less free space than `required_bytes`.
+fn check_free_space(
+    dirs: &DownloadDirs,
+    photosets: &[Photoset],
+    required_bytes: Option<u64>,
+) -> Result<()> {
+    let mut photos_by_dir: BTreeMap<&Path, u64> = BTreeMap::new();
+    for photoset in photosets {
+        *photos_by_dir.entry(dirs.dir_for(photoset)).or_default() +=
+            photoset.pending_photos().count() as u64;
+    }
+    for (dir, photos) in photos_by_dir {
+        let Some(available) = available_bytes(dir) else {
+            if required_bytes.is_some() {
                 eprintln!(
-                    "Warning: Failed to mark photoset as downloaded. (status_id = {})",
-                    photoset.id_str
+                    "Warning: Could not check the free space in {:?}. --require-space is ignored.",
+                    dir
                 );
             }
-        }),
-    );
-    downloader.start()?;
+            continue;
+        };
+        log::trace!("free space; dir={:?}, available={}", dir, available);
+        if let Some(required) = required_bytes {
+            ensure!(
+                available >= required,
+                "Only {} is free in {:?}, less than the required {}.",
+                HumanBytes(available),
+                dir,
+                HumanBytes(required)
+            );
+        }
+        let estimated = photos * ESTIMATED_BYTES_PER_PHOTO;
+        if available < estimated {
+            eprintln!(
+                "Warning: Only {} is free in {:?}, but {} may need about {}.",
+                HumanBytes(available),
+                dir,
+                count(photos as usize, "photo"),
+                HumanBytes(estimated)
+            );
+        }
+    }
+    Ok(())
+}
 
-    println!("Done.");
+/// Returns the space available to unprivileged users on the filesystem of the directory, or
+/// `None` if it cannot be told.
+#[cfg(unix)]
+fn available_bytes(dir: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `path` is a valid C string and `stat` is a plain struct filled in by statvfs.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
 
-    run_gc_if_needed(Connection::open(config::database_path())?.count_tweets()?)?;
+#[cfg(not(unix))]
+fn available_bytes(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// Prints how many photos are waiting to be downloaded, without downloading them.
+pub fn print_plan(args: &Args, db: &Connection) -> Result<()> {
+    let selection = db.select_not_downloaded_photos(args.skip_protected)?;
+    let mut photosets = selection.photosets;
+    let mut excluded = vec![];
+    if selection.excluded_failed > 0 {
+        excluded.push(format!(
+            "{} that failed to download repeatedly",
+            count(selection.excluded_failed, "photoset")
+        ));
+    }
+    if selection.excluded_forbidden > 0 {
+        excluded.push(count(selection.excluded_forbidden, "protected photoset"));
+    }
+    if selection.excluded_retweets > 0 {
+        excluded.push(count(selection.excluded_retweets, "retweet"));
+    }
+    if let Some(langs) = &args.lang {
+        let (matched, others) = partition_by_lang(photosets, langs);
+        photosets = matched;
+        if !others.is_empty() {
+            excluded.push(format!(
+                "{} in other languages",
+                count(others.len(), "photoset")
+            ));
+        }
+    }
+    if args.large_only {
+        let (large, small) = partition_by_size(photosets, LARGE_ONLY_MIN_LONG_EDGE);
+        photosets = large;
+        if !small.is_empty() {
+            excluded.push(count(small.len(), "small photoset"));
+        }
+    }
 
+    let photos: usize = photosets
+        .iter()
+        .map(|photoset| photoset.pending_photos().count())
+        .sum();
+    println!(
+        "Pending downloads: {} in {}.",
+        count(photos, "photo"),
+        count(photosets.len(), "photoset")
+    );
+    for excluded in excluded {
+        println!("Excluded {}.", excluded);
+    }
     Ok(())
 }
 
-fn set_download_dir(dir_arg: Option<PathBuf>) -> Result<PathBuf> {
-    if let Some(dir) = dir_arg.or_else(|| config::settings().ok().and_then(|s| s.download.dir)) {
-        if !dir.is_dir() {
-            bail!("The download directory does not exist: {:?}", &dir);
+/// Download directories by how the tweets were recorded.
+#[derive(Clone, Debug)]
+pub struct DownloadDirs {
+    pub default: PathBuf,
+    pub likes: Option<PathBuf>,
+    pub user: Option<PathBuf>,
+    /// Subdirectories of the above to save photos in.
+    pub layout: Option<DirLayout>,
+}
+
+impl DownloadDirs {
+    /// Resolves the directories from the argument or the config. The argument overrides all of
+    /// them.
+    pub fn resolve(dir_arg: Option<PathBuf>) -> Result<Self> {
+        let settings = config::settings().ok().map(|s| s.download);
+        let (likes_dir, user_dir, layout) = settings
+            .map(|s| (s.likes_dir, s.user_dir, s.date_layout))
+            .unwrap_or_default();
+        if dir_arg.is_some() {
+            return Ok(DownloadDirs {
+                default: resolve_download_dir(dir_arg)?,
+                likes: None,
+                user: None,
+                layout,
+            });
+        }
+        Ok(DownloadDirs {
+            default: resolve_download_dir(None)?,
+            likes: likes_dir.map(|dir| canonicalize_dir(&dir)).transpose()?,
+            user: user_dir.map(|dir| canonicalize_dir(&dir)).transpose()?,
+            layout,
+        })
+    }
+
+    /// Returns the directory for the source of the photoset, falling back to the default one.
+    pub fn dir_for(&self, photoset: &Photoset) -> &Path {
+        let dir = match photoset.source {
+            Some(TweetSource::Likes) => self.likes.as_deref(),
+            Some(TweetSource::User) => self.user.as_deref(),
+            Some(TweetSource::Url) | None => None,
+        };
+        dir.unwrap_or(&self.default)
+    }
+
+    pub fn photo_path(&self, photoset: &Photoset, photo_url: &str, index: usize) -> PathBuf {
+        self.dir_for(photoset)
+            .join(self.relative_photo_path(photoset, photo_url, index))
+    }
+
+    /// Returns the path to the photo relative to `dir_for`, following the layout if any.
+    pub fn relative_photo_path(
+        &self,
+        photoset: &Photoset,
+        photo_url: &str,
+        index: usize,
+    ) -> PathBuf {
+        let file_name = build_photo_path(photoset, photo_url, index);
+        match &self.layout {
+            Some(layout) => layout.dir_for(photoset).join(file_name),
+            None => file_name,
         }
-        log::trace!("chdir to {:?}", &dir);
-        std::env::set_current_dir(&dir)?;
-        return Ok(dir);
+    }
+}
+
+/// Returns the download directory from the argument or the config, falling back to the current
+/// working directory.
+pub fn resolve_download_dir(dir_arg: Option<PathBuf>) -> Result<PathBuf> {
+    if let Some(dir) = dir_arg.or_else(|| config::settings().ok().and_then(|s| s.download.dir)) {
+        return canonicalize_dir(&dir);
     }
 
     Ok(std::env::current_dir()?)
 }
 
+fn canonicalize_dir(dir: &Path) -> Result<PathBuf> {
+    let dir = fs::canonicalize(dir)
+        .with_context(|| format!("Could not resolve the download directory: {:?}", dir))?;
+    if !dir.is_dir() {
+        bail!("The download directory is not a directory: {:?}", &dir);
+    }
+    log::trace!("download dir: {:?}", &dir);
+    Ok(dir)
+}
+
 fn run_gc_if_needed(tweets: u64) -> Result<()> {
     log::trace!(
         "checking if gc is needed; tweets={}, threshold={}",
@@ -79,7 +892,200 @@ fn run_gc_if_needed(tweets: u64) -> Result<()> {
         AUTO_GC_THRESHOLD
     );
     if tweets >= AUTO_GC_THRESHOLD {
+        println!("Running maintenance (this may take a while; press Ctrl-C to skip)...");
         commands::forget::run_gc()?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use tempfile::tempdir;
+
+    use super::{
+        available_bytes, check_free_space, parse_selection, partition_by_lang, partition_by_size,
+        snippet, sort_photosets, write_manifest, DownloadDirs, Order,
+    };
+    use crate::database::{Photoset, TweetSource};
+    use crate::downloader::fixtures::photoset;
+    use crate::downloader::{DownloadOutcome, DownloadedFile};
+    use std::collections::BTreeSet;
+
+    fn photosets(status_ids: &[&str]) -> Vec<Photoset> {
+        (1..)
+            .zip(status_ids)
+            .map(|(rowid, &id_str)| Photoset {
+                id_str: id_str.to_owned(),
+                ..photoset(rowid, vec![])
+            })
+            .collect()
+    }
+
+    fn status_ids(photosets: &[Photoset]) -> Vec<&str> {
+        photosets.iter().map(|s| s.id_str.as_str()).collect()
+    }
+
+    #[test]
+    fn dirs_by_source() {
+        let dirs = DownloadDirs {
+            default: PathBuf::from("/photos"),
+            likes: Some(PathBuf::from("/likes")),
+            user: None,
+            layout: None,
+        };
+        let mut sets = photosets(&["1", "2", "3", "4"]);
+        sets[0].source = Some(TweetSource::Likes);
+        sets[1].source = Some(TweetSource::User);
+        sets[2].source = Some(TweetSource::Url);
+        let dirs_for: Vec<_> = sets.iter().map(|s| dirs.dir_for(s).to_owned()).collect();
+        let expected = ["/likes", "/photos", "/photos", "/photos"].map(PathBuf::from);
+        assert_eq!(dirs_for, expected);
+        assert_eq!(
+            dirs.photo_path(&sets[0], "https://pbs.twimg.com/media/a.jpg", 1),
+            PathBuf::from("/likes/@user-1-img1-a.jpg")
+        );
+    }
+
+    #[test]
+    fn selection_parsed() {
+        let set = |v: &[usize]| v.iter().copied().collect::<BTreeSet<usize>>();
+        assert_eq!(parse_selection("1, 3-5\n", 5).unwrap(), set(&[1, 3, 4, 5]));
+        assert_eq!(parse_selection("all", 3).unwrap(), set(&[1, 2, 3]));
+        assert_eq!(parse_selection("none", 3).unwrap(), set(&[]));
+        assert_eq!(parse_selection("", 3).unwrap(), set(&[]));
+        assert!(parse_selection("0", 3).is_err());
+        assert!(parse_selection("4", 3).is_err());
+        assert!(parse_selection("3-1", 3).is_err());
+        assert!(parse_selection("x", 3).is_err());
+    }
+
+    #[test]
+    fn snippet_cut() {
+        assert_eq!(snippet("a\n  b", 10), "a b");
+        assert_eq!(snippet("abcdef", 4), "abc…");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn free_space_checked() {
+        let dir = tempdir().unwrap();
+        let dirs = DownloadDirs {
+            default: dir.path().to_owned(),
+            likes: None,
+            user: None,
+            layout: None,
+        };
+        let mut sets = photosets(&["1"]);
+        sets[0].photo_urls = vec!["https://pbs.twimg.com/media/a.jpg".to_owned()];
+
+        assert!(available_bytes(dir.path()).is_some_and(|n| n > 0));
+        assert!(check_free_space(&dirs, &sets, Some(1)).is_ok());
+        assert!(check_free_space(&dirs, &sets, Some(u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn order_photosets() {
+        let mut sets = photosets(&["20", "100", "3"]);
+        sort_photosets(&mut sets, Order::Recorded, None);
+        assert_eq!(status_ids(&sets), vec!["20", "100", "3"]);
+        sort_photosets(&mut sets, Order::Newest, None);
+        assert_eq!(status_ids(&sets), vec!["100", "20", "3"]);
+        sort_photosets(&mut sets, Order::Oldest, None);
+        assert_eq!(status_ids(&sets), vec!["3", "20", "100"]);
+        sort_photosets(&mut sets, Order::Random, None);
+        assert_eq!(sets.len(), 3);
+    }
+
+    #[test]
+    fn random_order_replayed_with_seed() {
+        let ids: Vec<String> = (1..=20).map(|i| i.to_string()).collect();
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let shuffled = |seed| {
+            let mut sets = photosets(&ids);
+            sort_photosets(&mut sets, Order::Random, Some(seed));
+            status_ids(&sets).join(",")
+        };
+        assert_eq!(shuffled(42), shuffled(42));
+        assert_ne!(shuffled(42), shuffled(43));
+    }
+
+    #[test]
+    fn large_only() {
+        let mut sets = photosets(&["1", "2", "3"]);
+        sets[0].max_long_edge = Some(599);
+        sets[1].max_long_edge = Some(600);
+        let (large, small) = partition_by_size(sets, 600);
+        assert_eq!(status_ids(&large), vec!["2", "3"]);
+        assert_eq!(status_ids(&small), vec!["1"]);
+    }
+
+    #[test]
+    fn manifest_lists_saved_files() {
+        let outcome = |status_id: &str, success: bool| DownloadOutcome {
+            status_id: status_id.to_owned(),
+            screen_name: "user".to_owned(),
+            success,
+            files: vec![DownloadedFile {
+                index: if success { 1 } else { 2 },
+                media_index: if success { 1 } else { 3 },
+                url: format!("https://example.com/{}.jpg", status_id),
+                path: PathBuf::from(format!("/photos/{}.jpg", status_id)),
+                bytes: Some(3),
+                sha256: Some("abc".to_owned()),
+            }],
+            error: None,
+        };
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("manifest.jsonl");
+
+        // The photo saved before the photoset failed is listed too.
+        let n = write_manifest(&path, &[outcome("10", true), outcome("20", false)], false).unwrap();
+        assert_eq!(n, 2);
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            content,
+            concat!(
+                r#"{"status_id":"10","screen_name":"user","media_index":1,"#,
+                r#""url":"https://example.com/10.jpg","path":"/photos/10.jpg","bytes":3,"#,
+                r#""sha256":"abc"}"#,
+                "\n",
+                r#"{"status_id":"20","screen_name":"user","media_index":3,"#,
+                r#""url":"https://example.com/20.jpg","path":"/photos/20.jpg","bytes":3,"#,
+                r#""sha256":"abc"}"#,
+                "\n"
+            )
+        );
+
+        write_manifest(&path, &[outcome("30", true)], true).unwrap();
+        let lines: Vec<String> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].contains(r#""status_id":"30""#));
+
+        write_manifest(&path, &[], false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        // Only the manifest is left.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn filter_by_lang() {
+        let mut sets = photosets(&["1", "2", "3", "4"]);
+        sets[0].lang = Some("ja".to_owned());
+        sets[1].lang = Some("en".to_owned());
+        sets[2].lang = Some("und".to_owned());
+        let langs = |langs: &[&str]| langs.iter().map(|&l| l.to_owned()).collect::<Vec<_>>();
+
+        let (matched, others) = partition_by_lang(photosets(&[]), &langs(&["ja"]));
+        assert!(matched.is_empty() && others.is_empty());
+        let (matched, others) = partition_by_lang(sets, &langs(&["JA", "und"]));
+        assert_eq!(status_ids(&matched), vec!["1", "3", "4"]);
+        assert_eq!(status_ids(&others), vec!["2"]);
+    }
+}
@@ -1,19 +1,75 @@
-use clap::Parser;
+use std::fs;
+use std::path::PathBuf;
 
-use crate::common::count;
+use chrono::NaiveDate;
+use clap::{ArgGroup, Parser};
+use indicatif::HumanBytes;
+
+use crate::commands::download::DownloadDirs;
+use crate::common::{count, is_dry_run, open_database};
 use crate::config;
-use crate::database::Connection;
+use crate::database::{self, Connection, MediaFilter};
+use crate::downloader::is_photo_file_name;
 use crate::result::*;
+use crate::shutdown::ShutdownFlag;
+use crate::spinner::new_spinner;
+use crate::twitter::extract_screen_names;
 
 #[derive(Debug, Parser)]
-#[clap(arg_required_else_help = true)]
+#[clap(
+    arg_required_else_help = true,
+    group(ArgGroup::new("media-target").multiple(true))
+)]
 pub struct Args {
     #[clap(long, help = "Performs housekeeping on the database")]
     pub gc: bool,
+    #[clap(
+        long,
+        requires = "media-target",
+        help = "Deletes downloaded photos but keeps the tweets recorded"
+    )]
+    pub media: bool,
+    #[clap(
+        long = "id",
+        requires = "media",
+        group = "media-target",
+        require_value_delimiter = true,
+        use_value_delimiter = true,
+        value_name = "status-id",
+        help = "Deletes photos of the tweets with the status IDs (separated by a comma)"
+    )]
+    pub ids: Option<Vec<String>>,
+    #[clap(
+        long,
+        requires = "media",
+        group = "media-target",
+        require_value_delimiter = true,
+        use_value_delimiter = true,
+        value_name = "screen-name",
+        help = "Deletes photos of the tweets from the users (separated by a comma)"
+    )]
+    pub user: Option<Vec<String>>,
+    #[clap(
+        long,
+        requires = "media",
+        group = "media-target",
+        validator = validate_date,
+        value_name = "YYYY-MM-DD",
+        help = "Deletes photos downloaded before the date (UTC)"
+    )]
+    pub before: Option<String>,
+    #[clap(
+        long,
+        requires = "media",
+        help = "Sets the directory the photos were downloaded to"
+    )]
+    pub dir: Option<PathBuf>,
 }
 
 pub fn run(args: Args) -> Result<()> {
-    if args.gc {
+    if args.media {
+        run_forget_media(args)
+    } else if args.gc {
         run_gc()
     } else {
         unreachable!("arg required");
@@ -21,16 +77,181 @@ pub fn run(args: Args) -> Result<()> {
 }
 
 pub fn run_gc() -> Result<()> {
-    let db = Connection::open(config::database_path())?;
-    db.create()?;
+    let compresses_content = config::settings()?.database.compress_content;
+    let db = open_database()?;
+
+    if is_dry_run() {
+        println!(
+            "Would prune {}.",
+            count(db.count_prunable_tweets()?, "tweet")
+        );
+        let convertible = db.count_convertible_contents()?;
+        if convertible > 0 {
+            println!(
+                "Would {} {}.",
+                if compresses_content {
+                    "compress"
+                } else {
+                    "decompress"
+                },
+                count(convertible, "tweet")
+            );
+        }
+        return Ok(());
+    }
+
+    // Each step is a transaction, so stopping with Ctrl-C keeps the steps already done.
+    let shutdown = ShutdownFlag::register()?;
+    let completed = match run_gc_steps(&db, compresses_content, &shutdown) {
+        Err(e) if database::is_interrupted(&e) => false,
+        result => result?,
+    };
+    if !completed {
+        println!("Stopped. The rest of the maintenance will run next time.");
+    }
+    Ok(())
+}
 
-    let n = db.prune_tweets()?;
+/// Returns false if stopped by SIGINT or SIGTERM before all steps are done.
+fn run_gc_steps(
+    db: &Connection,
+    compresses_content: bool,
+    shutdown: &ShutdownFlag,
+) -> Result<bool> {
+    let step = |msg: &str, f: &dyn Fn() -> Result<usize>| -> Result<Option<usize>> {
+        if shutdown.is_requested() {
+            return Ok(None);
+        }
+        let spinner = new_spinner(msg.to_owned());
+        let interrupt_handle = db.interrupt_handle();
+        let result = shutdown.run_interruptible(move || interrupt_handle.interrupt(), f);
+        spinner.finish_and_clear();
+        result.map(Some)
+    };
+
+    let Some(n) = step("Pruning tweets", &|| db.prune_tweets())? else {
+        return Ok(false);
+    };
     println!("Pruned {}.", count(n, "tweet"));
 
-    if n > 0 {
-        db.vacuum()?;
+    let (msg, done) = if compresses_content {
+        ("Compressing tweets", "Compressed")
+    } else {
+        ("Decompressing tweets", "Decompressed")
+    };
+    let Some(converted) = step(msg, &|| db.convert_contents())? else {
+        return Ok(false);
+    };
+    if converted > 0 {
+        println!("{} {}.", done, count(converted, "tweet"));
+    }
+
+    if n > 0 || converted > 0 {
+        if step("Vacuuming database", &|| db.vacuum().map(|()| 0))?.is_none() {
+            return Ok(false);
+        }
         println!("Vacuumed database.");
     }
 
+    Ok(true)
+}
+
+fn run_forget_media(args: Args) -> Result<()> {
+    let dirs = DownloadDirs::resolve(args.dir)?;
+    let db = open_database()?;
+
+    let mut status_ids = None;
+    if let Some(ids) = args.ids {
+        let mut parsed = vec![];
+        for id in ids {
+            match id.trim().parse::<u64>() {
+                Ok(status_id) => parsed.push(status_id),
+                Err(_) => eprintln!("Warning: Invalid status ID: {}", id),
+            }
+        }
+        status_ids = Some(parsed);
+    }
+    let filter = MediaFilter {
+        status_ids,
+        screen_names: args.user.map(|user| extract_screen_names(&user)),
+        downloaded_before: args.before,
+    };
+
+    let photosets = db.select_downloaded_photos(&filter)?;
+    if photosets.is_empty() {
+        println!("No downloaded photos to delete.");
+        return Ok(());
+    }
+
+    let mut files = 0;
+    let mut bytes = 0;
+    let mut missing = 0;
+    let mut kept_photosets = 0;
+    for photoset in &photosets {
+        let mut skipped = false;
+        for (index, photo_url) in (1..).zip(photoset.photo_urls.iter()) {
+            let path = dirs.photo_path(photoset, photo_url, index);
+            let is_phog_file = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(is_photo_file_name)
+                .unwrap_or(false);
+            if !is_phog_file {
+                eprintln!("Warning: Skipped {:?} as it is not named by phog.", &path);
+                skipped = true;
+                continue;
+            }
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_file() => {
+                    if !is_dry_run() {
+                        fs::remove_file(&path)
+                            .with_context(|| format!("Could not delete {:?}", &path))?;
+                        log::trace!("deleted {:?}", &path);
+                    }
+                    files += 1;
+                    bytes += metadata.len();
+                }
+                Ok(_) => {
+                    eprintln!("Warning: Skipped {:?} as it is not a regular file.", &path);
+                    skipped = true;
+                }
+                Err(_) => missing += 1,
+            }
+        }
+        // Photosets with files left behind stay downloaded, so that they can be forgotten again.
+        if skipped {
+            kept_photosets += 1;
+        } else if !is_dry_run() {
+            db.set_media_removed_at(photoset.rowid)?;
+        }
+    }
+
+    println!(
+        "{} {} ({}) from {}.",
+        if is_dry_run() {
+            "Would delete"
+        } else {
+            "Deleted"
+        },
+        count(files, "file"),
+        HumanBytes(bytes),
+        count(photosets.len(), "photoset")
+    );
+    if missing > 0 {
+        println!("Skipped {} already missing.", count(missing, "file"));
+    }
+    if kept_photosets > 0 {
+        println!(
+            "Kept {} with skipped files recorded as downloaded.",
+            count(kept_photosets, "photoset")
+        );
+    }
+
     Ok(())
 }
+
+fn validate_date(date: &str) -> std::result::Result<(), String> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map(|_| ())
+        .map_err(|_| "date should be in YYYY-MM-DD format".to_owned())
+}
@@ -1,6 +1,7 @@
 use clap::Parser;
 
 use crate::commands;
+use crate::common::open_database_readonly;
 use crate::result::*;
 
 #[derive(Debug, Parser)]
@@ -9,9 +10,25 @@ pub struct Args {
     pub download_args: commands::download::Args,
     #[clap(flatten)]
     pub record_args: commands::record::Args,
+    #[clap(
+        long,
+        help = "Prints what would be fetched and downloaded without going online"
+    )]
+    pub plan: bool,
 }
 
 pub fn run(args: Args) -> Result<()> {
+    if args.plan {
+        return run_plan(args);
+    }
     commands::record::run(args.record_args)?;
     commands::download::run(args.download_args)
 }
+
+fn run_plan(args: Args) -> Result<()> {
+    let db = open_database_readonly()?;
+    commands::record::print_plan(args.record_args, &db)?;
+    commands::download::print_plan(&args.download_args, &db)?;
+    println!("New tweets are not counted as that requires fetching them. Nothing was changed.");
+    Ok(())
+}
@@ -1,12 +1,61 @@
+use clap::{ArgEnum, Parser};
+
+use crate::common::{api_timeout, open_database_readonly, request_limiter};
 use crate::config;
-use crate::database::Connection;
 use crate::database_info::DatabaseInfo;
 use crate::result::*;
+use crate::twitter::Client;
+
+#[derive(Debug, Parser)]
+pub struct Args {
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "plain",
+        value_name = "format",
+        help = "Sets output format"
+    )]
+    pub format: Format,
+    #[clap(
+        long,
+        help = "Verifies the login information with Twitter and shows the logged-in user"
+    )]
+    pub check_login: bool,
+}
 
-pub fn run() -> Result<()> {
-    let db = Connection::open(config::database_path())?;
-    db.create()?;
-    let info: DatabaseInfo = db.into();
-    println!("{}", info.format());
+#[derive(ArgEnum, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Plain,
+    Tsv,
+    Json,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let login = if args.check_login {
+        Some(check_login()?)
+    } else {
+        None
+    };
+    let db = open_database_readonly()?;
+    let info = DatabaseInfo::from(db).with_login(login);
+    let output = match args.format {
+        Format::Plain => info.format(),
+        Format::Tsv => info.to_tsv(),
+        Format::Json => info.to_json(),
+    };
+    println!("{}", output);
     Ok(())
 }
+
+/// Returns the screen name of the logged-in user. Fails with a config error if not logged in or
+/// the login is rejected by Twitter.
+fn check_login() -> Result<String> {
+    let credentials = config::credentials()?;
+    let client = Client::new(credentials)
+        .with_api_timeout(api_timeout()?)
+        .with_request_limiter(request_limiter()?);
+    let screen_name = client
+        .verified_screen_name()
+        .context("The login information is invalid. Try logging in again.")?;
+    Ok(format!("@{}", screen_name))
+}
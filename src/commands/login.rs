@@ -1,12 +1,10 @@
-use std::io::Write;
-
 use clap::Parser;
 
 use crate::cli::APP_NAME;
+use crate::common::{api_timeout, is_dry_run, prompt, request_limiter};
 use crate::config::{self, Credentials, CONSUMER_KEY, CONSUMER_SECRET};
 use crate::result::*;
-use crate::rt::block_on;
-use crate::twitter::Client;
+use crate::twitter::{block_on_api, Client};
 
 #[derive(Debug, Parser)]
 pub struct Args {
@@ -15,6 +13,19 @@ pub struct Args {
 }
 
 pub fn run(args: Args) -> Result<()> {
+    if is_dry_run() {
+        let path = if args.with_credentials {
+            config::credentials_path()
+        } else {
+            config::access_token_path()
+        };
+        println!(
+            "Would log in to Twitter and save the login to {:?}. Login cannot be tried in a dry run.",
+            path
+        );
+        return Ok(());
+    }
+
     if args.with_credentials {
         return login_with_credentials();
     }
@@ -35,7 +46,11 @@ fn login_with_pin(consumer_key: String, consumer_secret: String) -> Result<()> {
     println!("Preparing login URL...");
 
     let consumer_token = egg_mode::KeyPair::new(consumer_key, consumer_secret);
-    let request_token = block_on(egg_mode::auth::request_token(&consumer_token, "oob"))?;
+    let timeout = api_timeout()?;
+    let request_token = block_on_api(
+        egg_mode::auth::request_token(&consumer_token, "oob"),
+        timeout,
+    )?;
     let auth_url = egg_mode::auth::authorize_url(&request_token);
 
     println!("Open the URL below and log in to Twitter to get a PIN code.");
@@ -43,11 +58,10 @@ fn login_with_pin(consumer_key: String, consumer_secret: String) -> Result<()> {
 
     let code = prompt("\nEnter the PIN code (Ctrl-C to quit): ")?;
 
-    let (access_token, ..) = block_on(egg_mode::auth::access_token(
-        consumer_token,
-        &request_token,
-        code,
-    ))
+    let (access_token, ..) = block_on_api(
+        egg_mode::auth::access_token(consumer_token, &request_token, code),
+        timeout,
+    )
     .context("Could not log in to Twitter")?;
 
     match access_token {
@@ -55,6 +69,7 @@ fn login_with_pin(consumer_key: String, consumer_secret: String) -> Result<()> {
             config::save_access_token(access.key.into(), access.secret.into())
                 .context("Could not save login information")?;
             println!("Logged in successfully.");
+            print_shared_app_note();
         }
         _ => panic!("expected access token but got bearer token"),
     }
@@ -62,6 +77,25 @@ fn login_with_pin(consumer_key: String, consumer_secret: String) -> Result<()> {
     Ok(())
 }
 
+/// Tells that the built-in API key pair is shared by all users of this build, so are its
+/// app-level rate limits.
+fn print_shared_app_note() {
+    let hides_note = config::settings()
+        .map(|s| s.login.hide_shared_app_note)
+        .unwrap_or(false);
+    if hides_note {
+        return;
+    }
+    println!(
+        "\nNote: {} logged in with its built-in Twitter app, whose rate limits are shared by all users.\n\
+        If you hit rate limits often, create your own app and log in with `{} login --with-credentials`.\n\
+        To hide this note, set login.hide-shared-app-note = true in {:?}.",
+        APP_NAME,
+        APP_NAME,
+        config::settings_path()
+    );
+}
+
 fn login_with_credentials() -> Result<()> {
     println!("Open https://developer.twitter.com/en/apps, create or select an app, and open the Keys and Tokens tab.");
     println!("Enter keys and tokens (Ctrl-C to quit)...");
@@ -78,7 +112,9 @@ fn login_with_credentials() -> Result<()> {
         access_token_secret,
     };
 
-    let client = Client::new(credentials.clone());
+    let client = Client::new(credentials.clone())
+        .with_api_timeout(api_timeout()?)
+        .with_request_limiter(request_limiter()?);
     client
         .verify_tokens()
         .context("Provided credentials are invalid")?;
@@ -88,12 +124,3 @@ fn login_with_credentials() -> Result<()> {
 
     Ok(())
 }
-
-fn prompt(msg: &str) -> Result<String> {
-    print!("{}", msg);
-    std::io::stdout().flush()?;
-
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input).unwrap();
-    Ok(input.trim().into())
-}
@@ -1,30 +1,62 @@
 use std::fs;
 
+use clap::Parser;
+
+use crate::common::{confirm, is_dry_run};
 use crate::config;
 use crate::result::*;
 
-pub fn run() -> Result<()> {
-    let mut removed_any = false;
+#[derive(Debug, Parser)]
+pub struct Args {
+    #[clap(short, long, help = "Logs out without confirmation")]
+    pub yes: bool,
+}
 
-    let path = config::access_token_path();
-    if path.exists() {
-        fs::remove_file(&path)?;
-        log::trace!("removed {:?}", &path);
-        removed_any = true;
+pub fn run(args: Args) -> Result<()> {
+    let (kept_paths, paths): (Vec<_>, Vec<_>) =
+        [config::access_token_path(), config::credentials_path()]
+            .into_iter()
+            .filter(|path| path.exists())
+            .partition(|path| config::is_overridden_login_path(path));
+
+    for path in &kept_paths {
+        println!(
+            "Keeping {:?} because it is set by an environment variable.",
+            path
+        );
+    }
+
+    if paths.is_empty() {
+        if kept_paths.is_empty() {
+            println!("Not logged in.");
+        }
+        return Ok(());
     }
 
-    let path = config::credentials_path();
-    if path.exists() {
+    let account = match config::saved_user_id() {
+        Some(user_id) => format!("user ID {}", user_id),
+        None => "unknown account".to_owned(),
+    };
+
+    if is_dry_run() {
+        for path in paths {
+            println!("Would remove {:?}.", &path);
+        }
+        println!("Would log out ({}).", account);
+        return Ok(());
+    }
+
+    if !confirm(&format!("Remove saved login for {}?", account), args.yes)? {
+        return Ok(());
+    }
+
+    for path in paths {
         fs::remove_file(&path)?;
         log::trace!("removed {:?}", &path);
-        removed_any = true;
+        println!("Removed {:?}.", &path);
     }
 
-    if removed_any {
-        println!("Logged out successfully.");
-    } else {
-        println!("Not logged in.");
-    }
+    println!("Logged out successfully ({}).", account);
 
     Ok(())
 }
@@ -0,0 +1,67 @@
+use chrono::Local;
+use clap::Parser;
+
+use crate::common::{count, is_dry_run, path_with_suffix};
+use crate::config;
+use crate::database::{latest_schema_version, Connection};
+use crate::result::*;
+
+#[derive(Debug, Parser)]
+pub struct Args {
+    #[clap(long, help = "Reports pending migrations without applying them")]
+    pub check: bool,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let path = config::database_path();
+    let latest = latest_schema_version();
+    if !path.exists() {
+        println!(
+            "No database found at {:?}. It will be created at schema version {}.",
+            path, latest
+        );
+        return Ok(());
+    }
+
+    let db = Connection::open(&path)?;
+    let version = db.schema_version()?;
+    println!("Schema version: {} (latest: {}).", version, latest);
+    ensure!(
+        version <= latest,
+        "The database was migrated by a newer version of phog."
+    );
+
+    let pending = db.pending_migrations()?;
+    if pending.is_empty() {
+        println!("The database is up to date.");
+        return Ok(());
+    }
+    println!("Found {}:", count(pending.len(), "pending migration"));
+    for migration in &pending {
+        println!("  {:04}: {}", migration.version, migration.description);
+    }
+
+    if args.check {
+        return Ok(());
+    }
+    if is_dry_run() {
+        println!("Would back up the database and apply the migrations.");
+        return Ok(());
+    }
+
+    let backup_path = path_with_suffix(
+        &path,
+        &format!(".v{}-{}", version, Local::now().format("%Y%m%d%H%M%S")),
+    )?;
+    db.backup_into(&backup_path)?;
+    println!("Backed up the database to {:?}.", backup_path);
+
+    // Each migration is applied in a transaction, so a failed one leaves the database at the
+    // version before it.
+    db.create()?;
+    println!(
+        "Migrated the database from schema version {} to {}.",
+        version, latest
+    );
+    Ok(())
+}
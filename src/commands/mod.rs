@@ -4,4 +4,8 @@ pub mod get;
 pub mod info;
 pub mod login;
 pub mod logout;
+pub mod migrate;
+pub mod prune_users;
 pub mod record;
+pub mod repair;
+pub mod tweets;
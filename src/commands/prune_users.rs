@@ -0,0 +1,116 @@
+use chrono::{Duration, Utc};
+use clap::Parser;
+
+use crate::common::{confirm, count, is_dry_run, open_database_readonly};
+use crate::config;
+use crate::result::*;
+use crate::twitter::{extract_screen_names, status_id_to_datetime};
+
+#[derive(Debug, Parser)]
+pub struct Args {
+    #[clap(
+        long,
+        value_name = "days",
+        help = "Lists users whose latest recorded tweet is older than this many days"
+    )]
+    pub inactive: u32,
+    #[clap(
+        long,
+        help = "Removes the inactive users from record.default-user in config.toml"
+    )]
+    pub apply: bool,
+    #[clap(short, long, requires = "apply", help = "Removes without confirmation")]
+    pub yes: bool,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let db = open_database_readonly()?;
+    let cutoff = Utc::now() - Duration::days(args.inactive.into());
+
+    let mut inactive_users: Vec<_> = db
+        .select_latest_status_ids()?
+        .into_iter()
+        .map(|user| (status_id_to_datetime(user.status_id), user))
+        .filter(|(datetime, _)| *datetime < cutoff)
+        .collect();
+    inactive_users.sort_by_key(|(datetime, _)| *datetime);
+
+    if inactive_users.is_empty() {
+        println!(
+            "No users have been inactive for {}.",
+            count(args.inactive as usize, "day")
+        );
+        return Ok(());
+    }
+
+    for (datetime, user) in &inactive_users {
+        let screen_name = user.screen_name.as_deref().unwrap_or("(unknown)");
+        println!(
+            "@{} (user ID {}): last tweet {}",
+            screen_name,
+            user.user_id,
+            datetime.format("%Y-%m-%d")
+        );
+    }
+    println!(
+        "Found {} inactive for {}.",
+        count(inactive_users.len(), "user"),
+        count(args.inactive as usize, "day")
+    );
+
+    // Entries are written in various forms, e.g. `user1`, `@user2` or a profile URL.
+    let entries: Vec<String> = config::settings()?
+        .record
+        .default_user
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|entry| {
+            extract_screen_names(std::slice::from_ref(entry))
+                .first()
+                .is_some_and(|name| {
+                    inactive_users.iter().any(|(_, user)| {
+                        user.screen_name
+                            .as_deref()
+                            .is_some_and(|s| s.eq_ignore_ascii_case(name))
+                    })
+                })
+        })
+        .collect();
+
+    if entries.is_empty() {
+        println!("No inactive users are in record.default-user.");
+        return Ok(());
+    }
+
+    if !args.apply {
+        println!(
+            "Run with --apply to remove {} from record.default-user.",
+            count(entries.len(), "user")
+        );
+        return Ok(());
+    }
+
+    if is_dry_run() {
+        for entry in &entries {
+            println!("Would remove {:?} from record.default-user.", entry);
+        }
+        return Ok(());
+    }
+
+    let msg = format!(
+        "Remove {} from record.default-user?",
+        count(entries.len(), "user")
+    );
+    if !confirm(&msg, args.yes)? {
+        return Ok(());
+    }
+
+    let removed = config::remove_default_user_entries(&entries)?;
+    println!(
+        "Removed {} from record.default-user in {:?}.",
+        count(removed, "user"),
+        config::settings_path()
+    );
+
+    Ok(())
+}
@@ -1,11 +1,16 @@
+use std::collections::HashSet;
+
 use clap::Parser;
 
+use crate::common::{
+    api_timeout, count, is_dry_run, open_database, print_api_calls, request_limiter,
+};
 use crate::config;
 use crate::database::Connection;
 use crate::input;
 use crate::recording::{fetch::MAX_DEPTH, Extract, Fetch};
 use crate::result::*;
-use crate::twitter::Client;
+use crate::twitter::{extract_screen_names, status_id_to_datetime, Client};
 
 #[derive(Debug, Default, Eq, PartialEq, Parser)]
 pub struct Args {
@@ -17,14 +22,33 @@ pub struct Args {
 
 #[derive(Debug, Default, Eq, PartialEq, Parser)]
 pub struct ExtractArgs {
+    #[clap(
+        long = "id",
+        require_value_delimiter = true,
+        use_value_delimiter = true,
+        value_name = "status-id",
+        help = "Records the tweets with the status IDs (separated by a comma)"
+    )]
+    pub ids: Option<Vec<String>>,
     #[clap(short, long, help = "Extracts tweet URLs from the clipboard")]
     pub paste: bool,
+    #[clap(
+        long,
+        help = "Fetches extracted tweets again even if recorded to refresh their content"
+    )]
+    pub refetch: bool,
     #[clap(
         short,
         long,
         help = "Watches the clipboard and extracts tweet URLs continuously"
     )]
     pub watch: bool,
+    #[clap(
+        long,
+        requires = "watch",
+        help = "Stops watching the clipboard after recording a tweet"
+    )]
+    pub once: bool,
 }
 
 #[derive(Debug, Default, Eq, PartialEq, Parser)]
@@ -44,6 +68,14 @@ pub struct FetchArgs {
         help = "Limits the number of paginated requests to the same source"
     )]
     pub depth: Option<usize>,
+    #[clap(
+        long,
+        validator = validate_limit,
+        requires = "fetch-source",
+        value_name = "n",
+        help = "Stops fetching each user timeline after n tweets, along with --depth or --all"
+    )]
+    pub limit: Option<usize>,
     #[clap(
         short = 'f',
         long = "fetch",
@@ -66,7 +98,8 @@ pub struct FetchArgs {
             Example: --likes user1,@user2,https://twitter.com/user3\n\
             \n\
             If <screen-name> is omitted and only the --likes flag is given,\n\
-            the record.default-likes variable in the config file is used as screen names."
+            the record.default-likes variable in the config file is used as screen names.\n\
+            If record.merge-defaults is true, the variable is used in addition to <screen-name>."
     )]
     pub likes: Option<Vec<String>>,
     #[clap(
@@ -84,14 +117,42 @@ pub struct FetchArgs {
             Example: --user user1,@user2,https://twitter.com/user3\n\
             \n\
             If <screen-name> is omitted and only the --user flag is given,\n\
-            the record.default-user variable in the config file is used as screen names."
+            the record.default-user variable in the config file is used as screen names.\n\
+            If record.merge-defaults is true, the variable is used in addition to <screen-name>."
     )]
     pub user: Option<Vec<String>>,
+    #[clap(
+        long,
+        group = "fetch-source",
+        help = "Fetches tweets from all users recorded in the database by their user IDs"
+    )]
+    pub user_id_from_db: bool,
+    #[clap(
+        long,
+        group = "fetch-source",
+        conflicts_with = "fetch-modifier",
+        help = "Fetches tweets newer than the recorded ones from record.default-user and \
+            record.default-likes"
+    )]
+    pub incremental: bool,
+    #[clap(
+        long,
+        require_value_delimiter = true,
+        use_value_delimiter = true,
+        value_name = "screen-name",
+        help = "Skips the users when fetching likes and timelines, e.g. ones in record.default-user"
+    )]
+    pub exclude: Option<Vec<String>>,
+    #[clap(
+        long,
+        help = "Hides the progress lines printed to stderr while fetching timelines"
+    )]
+    pub quiet: bool,
 }
 
 impl Args {
     pub fn is_empty(&self) -> bool {
-        self == &Self::default()
+        self.extract_args == ExtractArgs::default() && self.fetch_args.is_empty()
     }
 
     pub fn should_fetch(&self) -> bool {
@@ -103,8 +164,14 @@ impl Args {
 }
 
 impl FetchArgs {
+    /// Returns true if no sources or modifiers are specified. `--quiet` and `--exclude` do not
+    /// count.
     pub fn is_empty(&self) -> bool {
-        self == &Self::default()
+        self == &Self {
+            quiet: self.quiet,
+            exclude: self.exclude.clone(),
+            ..Self::default()
+        }
     }
 
     pub fn load_defaults(mut self, settings: config::Settings) -> Result<Self> {
@@ -112,22 +179,75 @@ impl FetchArgs {
             opt.as_ref().map(|v| v.len()) == Some(0)
         }
 
-        let no_targets = self.likes.is_none() && self.user.is_none();
+        /// Appends the defaults to the explicitly given screen names.
+        fn merge(opt: &mut Option<Vec<String>>, defaults: Option<Vec<String>>) {
+            if let (Some(names), Some(defaults)) = (opt.as_mut(), defaults) {
+                names.extend(defaults);
+            }
+        }
+
+        let no_targets = self.likes.is_none() && self.user.is_none() && !self.user_id_from_db;
+        let merges = settings.record.merge_defaults;
 
-        if no_targets || is_flag_only(&self.likes) {
+        // --incremental fills in whichever of the sources is not given.
+        if no_targets || is_flag_only(&self.likes) || (self.incremental && self.likes.is_none()) {
             self.likes = settings.record.default_likes;
+        } else if merges {
+            merge(&mut self.likes, settings.record.default_likes);
         }
-        if no_targets || is_flag_only(&self.user) {
+        if no_targets || is_flag_only(&self.user) || (self.incremental && self.user.is_none()) {
             self.user = settings.record.default_user;
+        } else if merges {
+            merge(&mut self.user, settings.record.default_user);
         }
 
+        self.likes = self.likes.map(dedup_screen_names);
+        self.user = self.user.map(dedup_screen_names);
         Ok(self)
     }
+
+    /// Removes the users given with `--exclude` from `likes` and `user`, whichever form their
+    /// entries are written in. Returns the screen names of the removed users.
+    pub fn remove_excluded(&mut self) -> Vec<String> {
+        let excluded: HashSet<String> = match &self.exclude {
+            Some(exclude) => extract_screen_names(exclude).into_iter().collect(),
+            None => return vec![],
+        };
+        let mut removed = vec![];
+        for entries in [&mut self.likes, &mut self.user].into_iter().flatten() {
+            entries.retain(
+                |entry| match extract_screen_names(std::slice::from_ref(entry)).pop() {
+                    Some(screen_name) if excluded.contains(&screen_name) => {
+                        if !removed.contains(&screen_name) {
+                            removed.push(screen_name);
+                        }
+                        false
+                    }
+                    _ => true,
+                },
+            );
+        }
+        removed
+    }
+}
+
+/// Drops entries naming the same user as an earlier one, e.g. `@User` after `user`, keeping the
+/// first-seen order.
+fn dedup_screen_names(entries: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    entries
+        .into_iter()
+        .filter(
+            |entry| match extract_screen_names(std::slice::from_ref(entry)).pop() {
+                Some(screen_name) => seen.insert(screen_name),
+                None => true,
+            },
+        )
+        .collect()
 }
 
 pub fn run(args: Args) -> Result<()> {
-    let db = Connection::open(config::database_path())?;
-    db.create()?;
+    let db = open_database()?;
     let should_fetch = args.should_fetch();
     // Extract should always be run as stdin may be provided at any time.
     run_extract(args.extract_args, &db)?;
@@ -139,9 +259,16 @@ pub fn run(args: Args) -> Result<()> {
 
 fn run_extract(args: ExtractArgs, db: &Connection) -> Result<()> {
     log::trace!("starting extraction; args={:?}", args);
-    let extract = Extract::new(db);
+    let extract = Extract::new(db).with_refetch(args.refetch);
+    if let Some(ids) = args.ids {
+        let (status_ids, invalid_ids) = parse_status_ids(&ids);
+        for id in invalid_ids {
+            eprintln!("Warning: Invalid status ID: {}", id);
+        }
+        extract.from_status_ids(&status_ids)?;
+    }
     if args.watch {
-        extract.from_clipboard_watcher()?;
+        extract.from_clipboard_watcher(args.once)?;
     } else if args.paste {
         extract.from_clipboard()?;
     }
@@ -149,30 +276,166 @@ fn run_extract(args: ExtractArgs, db: &Connection) -> Result<()> {
 }
 
 fn run_fetch(args: FetchArgs, db: &Connection) -> Result<()> {
-    let args = args.load_defaults(config::settings()?)?;
+    let mut args = args.load_defaults(config::settings()?)?;
+    print_excluded(&args.remove_excluded());
     log::trace!("starting fetch; args={:?}", args);
 
+    if is_dry_run() {
+        for screen_name in args.likes.iter().flatten() {
+            println!("Would fetch likes from {}.", screen_name);
+        }
+        for screen_name in args.user.iter().flatten() {
+            println!("Would fetch tweets from {}.", screen_name);
+        }
+        if args.user_id_from_db {
+            let user_ids = db.select_distinct_user_ids()?;
+            println!(
+                "Would fetch tweets from {} in the database.",
+                count(user_ids.len(), "user")
+            );
+        }
+        return Ok(());
+    }
+
     let credentials = config::credentials()?;
-    let client = Client::new(credentials);
+    let client = Client::new(credentials)
+        .with_api_timeout(api_timeout()?)
+        .with_request_limiter(request_limiter()?);
+    if let Some((max_requests, window)) = client.request_limit() {
+        log::debug!(
+            "request limit; max_requests={}, window={:?}",
+            max_requests,
+            window
+        );
+    }
     let uses_since_id = !args.all && args.depth.is_none();
     let depth = match args.depth {
-        Some(n) if n == 0 => MAX_DEPTH,
+        Some(0) => MAX_DEPTH,
         Some(n) => n,
         None => MAX_DEPTH,
     };
 
-    let fetch = Fetch::new(db, client);
+    let fetch = Fetch::new(db, client)
+        .with_quiet(args.quiet)
+        .with_limit(args.limit);
 
+    let mut recorded = 0;
     if let Some(likes) = args.likes {
-        fetch.from_likes(likes)?;
+        recorded += fetch.from_likes(likes)?;
     }
     if let Some(user) = args.user {
-        fetch.from_user(user, uses_since_id, depth)?;
+        recorded += fetch.from_user(user, uses_since_id, depth)?;
+    }
+    if args.user_id_from_db {
+        recorded += fetch.from_user_ids_in_db(uses_since_id, depth)?;
+    }
+    if args.incremental {
+        println!("Recorded {} in total.", count(recorded, "new tweet"));
+    }
+
+    print_api_calls(fetch.client());
+    for (endpoint, rate_limit) in fetch.client().rate_limits() {
+        log::debug!(
+            "rate limit after fetch; endpoint={}, remaining={}, limit={}, reset={}",
+            endpoint.path(),
+            rate_limit.remaining,
+            rate_limit.limit,
+            rate_limit.reset
+        );
     }
 
     Ok(())
 }
 
+/// Prints the sources `run` would fetch from with what is known offline: the last recorded tweet
+/// of each user. How many tweets are new cannot be told without asking Twitter.
+pub fn print_plan(args: Args, db: &Connection) -> Result<()> {
+    if !args.should_fetch() {
+        println!("No timelines or likes to fetch.");
+        return Ok(());
+    }
+    let mut args = args.fetch_args.load_defaults(config::settings()?)?;
+    print_excluded(&args.remove_excluded());
+    let latest_statuses = db.select_latest_status_ids()?;
+    // Mirrors how run_fetch fetches timelines.
+    let uses_since_id = !args.all && args.depth.is_none();
+    let history = match args.depth {
+        Some(n) if n > 0 => format!("up to {} of each timeline", count(n, "page")),
+        _ => "the whole timeline".to_owned(),
+    };
+    let limit = args
+        .limit
+        .map(|n| format!(", stopping after {}", count(n, "tweet")))
+        .unwrap_or_default();
+
+    for screen_name in extract_screen_names(args.likes.as_deref().unwrap_or_default()) {
+        println!("Likes from {}: would fetch the latest likes.", screen_name);
+    }
+    for screen_name in extract_screen_names(args.user.as_deref().unwrap_or_default()) {
+        let latest = latest_statuses.iter().find(|status| {
+            status
+                .screen_name
+                .as_ref()
+                .is_some_and(|name| name.eq_ignore_ascii_case(&screen_name))
+        });
+        match latest {
+            _ if !uses_since_id => println!(
+                "Tweets from {}: would fetch {} regardless of recorded tweets{}.",
+                screen_name, history, limit
+            ),
+            Some(status) => println!(
+                "Tweets from {}: would fetch tweets newer than the last recorded one ({}){}.",
+                screen_name,
+                status_id_to_datetime(status.status_id).format("%Y-%m-%d %H:%M"),
+                limit
+            ),
+            None => println!(
+                "Tweets from {}: nothing recorded yet; would fetch the whole timeline{}.",
+                screen_name, limit
+            ),
+        }
+    }
+    if args.user_id_from_db {
+        if uses_since_id {
+            println!(
+                "Tweets from {} in the database: would fetch tweets newer than the recorded ones{}.",
+                count(latest_statuses.len(), "user"),
+                limit
+            );
+        } else {
+            println!(
+                "Tweets from {} in the database: would fetch {} regardless of recorded tweets{}.",
+                count(latest_statuses.len(), "user"),
+                history,
+                limit
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_excluded(screen_names: &[String]) {
+    if !screen_names.is_empty() {
+        println!(
+            "Skipped {} with --exclude: {}.",
+            count(screen_names.len(), "user"),
+            screen_names.join(", ")
+        );
+    }
+}
+
+fn parse_status_ids(ids: &[String]) -> (Vec<u64>, Vec<&str>) {
+    let mut status_ids = vec![];
+    let mut invalid_ids = vec![];
+    for id in ids {
+        match id.trim().parse::<u64>() {
+            Ok(status_id) => status_ids.push(status_id),
+            Err(_) => invalid_ids.push(id.as_str()),
+        }
+    }
+    (status_ids, invalid_ids)
+}
+
 fn validate_depth(depth: &str) -> std::result::Result<(), String> {
     match depth.parse::<usize>() {
         Ok(n) if n <= MAX_DEPTH => Ok(()),
@@ -181,12 +444,22 @@ fn validate_depth(depth: &str) -> std::result::Result<(), String> {
     }
 }
 
+fn validate_limit(limit: &str) -> std::result::Result<(), String> {
+    match limit.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        Ok(_) => Err("limit should be > 0".to_owned()),
+        Err(_) => Err("limit should be a number".to_owned()),
+    }
+}
+
 #[cfg(test)]
 mod args_tests {
     use crate::config;
     use crate::input;
 
-    use super::{Args, FetchArgs};
+    use clap::Parser;
+
+    use super::{parse_status_ids, Args, FetchArgs};
 
     #[test]
     fn should_fetch() {
@@ -220,6 +493,28 @@ mod args_tests {
             args.fetch_args.likes = Some(vec![]);
             assert!(args.should_fetch());
         }
+        {
+            // --quiet alone does not make the extract options fetch.
+            let mut args = Args::default();
+            args.extract_args.paste = true;
+            args.fetch_args.quiet = true;
+            assert!(!args.should_fetch());
+        }
+    }
+
+    #[test]
+    fn status_ids() {
+        let args = Args::parse_from(["record", "--id", "123,x,456"]);
+        let ids = args.extract_args.ids.unwrap();
+        assert_eq!(parse_status_ids(&ids), (vec![123, 456], vec!["x"]));
+        assert!(!Args::parse_from(["record", "--id", "1"]).should_fetch());
+    }
+
+    #[test]
+    fn once_requires_watch() {
+        assert!(Args::try_parse_from(["record", "--once"]).is_err());
+        let args = Args::parse_from(["record", "--watch", "--once"]);
+        assert!(args.extract_args.watch && args.extract_args.once);
     }
 
     #[test]
@@ -236,5 +531,91 @@ mod args_tests {
 
         assert_eq!(fetch_args.likes, settings.record.default_likes);
         assert_eq!(fetch_args.user, settings.record.default_user);
+
+        let fetch_args = FetchArgs {
+            user_id_from_db: true,
+            ..FetchArgs::default()
+        };
+        let fetch_args = fetch_args.load_defaults(settings.clone()).unwrap();
+        assert!(fetch_args.likes.is_none());
+        assert!(fetch_args.user.is_none());
+
+        let fetch_args = FetchArgs {
+            incremental: true,
+            user: Some(vec!["user".to_owned()]),
+            ..FetchArgs::default()
+        };
+        let fetch_args = fetch_args.load_defaults(settings.clone()).unwrap();
+        assert_eq!(fetch_args.likes, settings.record.default_likes);
+        assert_eq!(fetch_args.user, Some(vec!["user".to_owned()]));
+    }
+
+    #[test]
+    fn fetch_args_merge_defaults() {
+        let mut settings = config::Settings::default();
+        settings.record.default_likes = Some(vec!["b".to_owned()]);
+        settings.record.default_user = Some(vec!["@B".to_owned(), "c".to_owned()]);
+        let explicit = || FetchArgs {
+            user: Some(vec!["a".to_owned(), "b".to_owned()]),
+            ..FetchArgs::default()
+        };
+
+        // Explicit screen names replace the defaults by default.
+        let fetch_args = explicit().load_defaults(settings.clone()).unwrap();
+        assert!(fetch_args.likes.is_none());
+        assert_eq!(fetch_args.user, Some(vec!["a".to_owned(), "b".to_owned()]));
+
+        settings.record.merge_defaults = true;
+        let fetch_args = explicit().load_defaults(settings.clone()).unwrap();
+        assert!(fetch_args.likes.is_none());
+        assert_eq!(
+            fetch_args.user,
+            Some(vec!["a".to_owned(), "b".to_owned(), "c".to_owned()])
+        );
+
+        // Duplicates within the defaults are dropped too.
+        settings.record.default_user =
+            Some(vec!["c".to_owned(), "https://twitter.com/C".to_owned()]);
+        let fetch_args = FetchArgs::default()
+            .load_defaults(settings.clone())
+            .unwrap();
+        assert_eq!(fetch_args.user, Some(vec!["c".to_owned()]));
+    }
+
+    #[test]
+    fn fetch_args_exclude() {
+        let mut settings = config::Settings::default();
+        settings.record.default_likes = Some(vec!["a".to_owned()]);
+        settings.record.default_user = Some(vec![
+            "@A".to_owned(),
+            "b".to_owned(),
+            "https://twitter.com/C".to_owned(),
+        ]);
+
+        let args = Args::parse_from(["record", "--exclude", "a,@c,d"]);
+        // --exclude alone fetches from the defaults but does not make extract options fetch.
+        assert!(args.fetch_args.is_empty());
+        let mut fetch_args = args.fetch_args.load_defaults(settings).unwrap();
+        assert_eq!(
+            fetch_args.remove_excluded(),
+            vec!["a".to_owned(), "c".to_owned()]
+        );
+        assert_eq!(fetch_args.likes, Some(vec![]));
+        assert_eq!(fetch_args.user, Some(vec!["b".to_owned()]));
+    }
+
+    #[test]
+    fn limit_combines_with_depth() {
+        let args = Args::parse_from(["record", "--user", "a", "--limit", "50", "--depth", "3"]);
+        assert_eq!(args.fetch_args.limit, Some(50));
+        assert_eq!(args.fetch_args.depth, Some(3));
+        assert!(Args::try_parse_from(["record", "--user", "a", "--limit", "0"]).is_err());
+        assert!(Args::try_parse_from(["record", "--limit", "50"]).is_err());
+    }
+
+    #[test]
+    fn incremental_conflicts_with_all() {
+        assert!(Args::try_parse_from(["record", "--incremental", "--all"]).is_err());
+        assert!(Args::parse_from(["record", "--incremental"]).should_fetch());
     }
 }
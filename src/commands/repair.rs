@@ -0,0 +1,78 @@
+use std::fs;
+
+use chrono::Local;
+use clap::Parser;
+
+use crate::common::{confirm, count, is_dry_run, path_with_suffix};
+use crate::config;
+use crate::database::Connection;
+use crate::result::*;
+
+#[derive(Debug, Parser)]
+pub struct Args {
+    #[clap(short, long, help = "Recovers the database without confirmation")]
+    pub yes: bool,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let path = config::database_path();
+    ensure!(path.exists(), "No database found at {:?}.", path);
+
+    let problems = {
+        let db = Connection::open_existing_readonly(&path)?;
+        db.integrity_check()?
+    };
+    if problems.is_empty() {
+        println!("No problems found in {:?}.", path);
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    println!("Found {} in {:?}.", count(problems.len(), "problem"), path);
+
+    if is_dry_run() {
+        println!("Would recover the readable tweets into a new database.");
+        return Ok(());
+    }
+
+    if !confirm("Recover the readable tweets into a new database?", args.yes)? {
+        return Ok(());
+    }
+
+    let repaired_path = path_with_suffix(&path, ".repaired")?;
+    if repaired_path.exists() {
+        fs::remove_file(&repaired_path)
+            .with_context(|| format!("Could not remove {:?}", repaired_path))?;
+    }
+    let counts = {
+        let db = Connection::open_existing_readonly(&path)?;
+        db.salvage_into(&repaired_path)?
+    };
+
+    // The corrupted database is kept in case more can be recovered from it by other means.
+    let backup_path = path_with_suffix(
+        &path,
+        &format!(".corrupted-{}", Local::now().format("%Y%m%d%H%M%S")),
+    )?;
+    fs::rename(&path, &backup_path)
+        .with_context(|| format!("Could not move {:?} to {:?}", path, backup_path))?;
+    for suffix in ["-wal", "-shm"] {
+        let mut stale = path.clone().into_os_string();
+        stale.push(suffix);
+        let _ = fs::remove_file(stale);
+    }
+    fs::rename(&repaired_path, &path)
+        .with_context(|| format!("Could not move {:?} to {:?}", repaired_path, path))?;
+
+    println!(
+        "Recovered {} of {} and {} of {}.",
+        counts.tweets,
+        count(counts.total_tweets, "tweet"),
+        counts.pruned_tweets,
+        count(counts.total_pruned_tweets, "pruned tweet"),
+    );
+    println!("The corrupted database was moved to {:?}.", backup_path);
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use clap::Parser;
+
+use crate::common::open_database_readonly;
+use crate::result::*;
+
+#[derive(Debug, Parser)]
+pub struct Args {
+    #[clap(
+        required = true,
+        value_name = "status-id",
+        help = "Status IDs of the tweets to print"
+    )]
+    pub status_ids: Vec<u64>,
+    #[clap(long, help = "Prints the JSON as stored instead of pretty-printing it")]
+    pub raw: bool,
+    #[clap(long, help = "Looks up pruned tweets, of which only the media is kept")]
+    pub pruned: bool,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let db = open_database_readonly()?;
+
+    let mut not_found = 0;
+    for &status_id in &args.status_ids {
+        let content = if args.pruned {
+            db.get_pruned_content(status_id)?
+        } else {
+            db.get_content(status_id)?
+        };
+        let content = match content {
+            Some(content) => content,
+            None => {
+                eprintln!("Warning: Tweet not found: {}", status_id);
+                not_found += 1;
+                continue;
+            }
+        };
+        if args.raw {
+            println!("{}", content);
+        } else {
+            let json: serde_json::Value = serde_json::from_str(&content)
+                .with_context(|| format!("Could not parse the tweet: {}", status_id))?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&json).expect("JSON value must be serializable")
+            );
+        }
+    }
+
+    ensure!(
+        not_found < args.status_ids.len(),
+        "No tweets found{}.",
+        if args.pruned { " in pruned tweets" } else { "" }
+    );
+    Ok(())
+}
@@ -1,10 +1,164 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use chrono::{TimeZone, Utc};
 use egg_mode::RateLimit;
+use once_cell::sync::OnceCell;
+
+use crate::config;
+use crate::database::{Connection, DEFAULT_BUSY_TIMEOUT, DEFAULT_MEDIA_TYPES};
+use crate::result::*;
+use crate::twitter::{Client, RequestLimiter, DEFAULT_API_TIMEOUT, DEFAULT_REQUEST_WINDOW};
+
+static DRY_RUN: OnceCell<bool> = OnceCell::new();
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.set(dry_run).expect("dry run flag must be set once");
+}
+
+/// Returns true if commands should print what they would do instead of changing anything.
+pub fn is_dry_run() -> bool {
+    DRY_RUN.get().copied().unwrap_or(false)
+}
+
+static DEADLINE: OnceCell<Instant> = OnceCell::new();
+static DEADLINE_REACHED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the time the whole command should stop by, given with the global `--deadline`.
+pub fn set_deadline(deadline: Instant) {
+    DEADLINE.set(deadline).expect("deadline must be set once");
+}
+
+/// Returns the time set with the global `--deadline`, if any.
+pub fn deadline() -> Option<Instant> {
+    DEADLINE.get().copied()
+}
+
+/// Returns true if the global `--deadline` has passed. Loops should stop when it returns true;
+/// the command then exits with a status telling that it did not finish.
+pub fn is_past_deadline() -> bool {
+    let past = has_passed_deadline();
+    if past {
+        mark_deadline_reached();
+    }
+    past
+}
+
+/// Returns true if the global `--deadline` has passed, without marking it reached.
+pub fn has_passed_deadline() -> bool {
+    deadline().is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Records that work was stopped because of the global `--deadline`.
+pub fn mark_deadline_reached() {
+    DEADLINE_REACHED.store(true, Ordering::SeqCst);
+}
+
+/// Returns true if any work was stopped because of the global `--deadline`.
+pub fn has_reached_deadline() -> bool {
+    DEADLINE_REACHED.load(Ordering::SeqCst)
+}
+
+/// Opens the database, creating or migrating it as needed. In dry-run mode, the database is
+/// opened as is.
+pub fn open_database() -> Result<Connection> {
+    let path = config::database_path();
+    let db = if is_dry_run() {
+        Connection::open_for_dry_run(path)?
+    } else {
+        let db = Connection::open(path)?;
+        db.create()?;
+        db
+    };
+    configure_database(db)
+}
+
+/// Opens the database read-only for commands that never write to it, so that they neither wait
+/// for nor block a running `record` or `download`.
+///
+/// A database behind the latest schema is migrated first, as opening it used to do, unless this is
+/// a dry run.
+pub fn open_database_readonly() -> Result<Connection> {
+    let path = config::database_path();
+    if !is_dry_run() {
+        Connection::migrate_if_needed(&path)?;
+    }
+    configure_database(Connection::open_readonly(path)?)
+}
+
+fn configure_database(db: Connection) -> Result<Connection> {
+    let settings = config::settings()?;
+    let media_types = settings
+        .download
+        .media_types
+        .unwrap_or_else(|| DEFAULT_MEDIA_TYPES.to_vec());
+    let busy_timeout = settings
+        .database
+        .busy_timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_BUSY_TIMEOUT);
+    db.with_compressed_content(settings.database.compress_content)
+        .with_media_types(media_types)
+        .with_video_quality(settings.download.video_quality)
+        .with_skipped_retweets(settings.download.skip_retweets)
+        .with_busy_timeout(busy_timeout)
+}
+
+/// Returns the timeout of each API request set by `network.api-timeout-secs`.
+pub fn api_timeout() -> Result<Duration> {
+    Ok(config::settings()?
+        .network
+        .api_timeout_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_API_TIMEOUT))
+}
+
+/// Returns the limiter set by `network.max-requests` and `network.request-window-secs`, or `None`
+/// if unlimited.
+pub fn request_limiter() -> Result<Option<Arc<RequestLimiter>>> {
+    let network = config::settings()?.network;
+    let window = network
+        .request_window_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_REQUEST_WINDOW);
+    Ok(RequestLimiter::new(network.max_requests.unwrap_or(0), window).map(Arc::new))
+}
 
 pub fn count(size: usize, word: &str) -> String {
     format!("{} {}{}", size, word, if size == 1 { "" } else { "s" })
 }
 
+/// Returns `path` with `suffix` appended to its file name, e.g. `phog.db.repaired` for `phog.db`
+/// and `.repaired`.
+pub fn path_with_suffix(path: &Path, suffix: &str) -> Result<PathBuf> {
+    let mut file_name = path
+        .file_name()
+        .ok_or_else(|| format_err!("{:?} lacks file name", path))?
+        .to_owned();
+    file_name.push(suffix);
+    Ok(path.with_file_name(file_name))
+}
+
+/// Prints how many API requests the client made, e.g. at the end of a run.
+pub fn print_api_calls(client: &Client) {
+    let api_calls = client.api_calls();
+    if api_calls.is_empty() {
+        return;
+    }
+    let by_endpoint: Vec<String> = api_calls
+        .iter()
+        .map(|(endpoint, n)| format!("{}: {}", endpoint.path(), n))
+        .collect();
+    println!(
+        "Made {} ({}).",
+        count(api_calls.values().sum(), "API request"),
+        by_endpoint.join(", ")
+    );
+}
+
 pub fn print_rate_limit(rate_limit: &RateLimit) {
     let reset_datetime = Utc.timestamp(rate_limit.reset as i64, 0);
     log::info!(
@@ -21,3 +175,27 @@ pub fn print_rate_limit(rate_limit: &RateLimit) {
         );
     }
 }
+
+pub fn prompt(msg: &str) -> Result<String> {
+    print!("{}", msg);
+    std::io::stdout().flush()?;
+
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    Ok(input.trim().into())
+}
+
+/// Asks `msg` with `[y/N]` on a TTY and returns whether the user agreed. Returns true without
+/// asking if `yes` is set or stdin is not a TTY. Prints "Canceled." if the user declined.
+pub fn confirm(msg: &str, yes: bool) -> Result<bool> {
+    if yes || !atty::is(atty::Stream::Stdin) {
+        return Ok(true);
+    }
+    let answer = prompt(&format!("{} [y/N] ", msg))?;
+    if answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes") {
+        Ok(true)
+    } else {
+        println!("Canceled.");
+        Ok(false)
+    }
+}
@@ -1,13 +1,16 @@
 use std::env;
 use std::fs::{self, File};
 use std::io::{self, BufWriter, Read};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::result::*;
-use once_cell::sync::OnceCell;
+use once_cell::sync::{Lazy, OnceCell};
+use regex::{Captures, Regex};
 use serde::{Deserialize, Serialize};
 
 use crate::cli::APP_NAME;
+use crate::database::{MediaType, VideoQuality};
+use crate::downloader::DirLayout;
 
 pub static CONSUMER_KEY: Option<&str> = option_env!("PHOG_COMPILE_ENV__CONSUMER_KEY");
 pub static CONSUMER_SECRET: Option<&str> = option_env!("PHOG_COMPILE_ENV__CONSUMER_SECRET");
@@ -32,16 +35,59 @@ pub struct Credentials {
 #[derive(Clone, Default, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Settings {
+    #[serde(default)]
+    pub database: DatabaseSettings,
     #[serde(default)]
     pub download: DownloadSettings,
+    #[serde(default)]
+    pub login: LoginSettings,
+    #[serde(default)]
+    pub network: NetworkSettings,
     #[serde(default, alias = "fetch")]
     pub record: RecordSettings,
 }
 
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DatabaseSettings {
+    #[serde(default)]
+    pub compress_content: bool,
+    pub busy_timeout_ms: Option<u64>,
+}
+
 #[derive(Clone, Default, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct DownloadSettings {
     pub dir: Option<PathBuf>,
+    pub likes_dir: Option<PathBuf>,
+    pub user_dir: Option<PathBuf>,
+    pub date_layout: Option<DirLayout>,
+    #[serde(default)]
+    pub max_rate_bytes: u64,
+    #[serde(default)]
+    pub max_file_bytes: u64,
+    pub max_retries: Option<u32>,
+    pub deadline_secs: Option<u64>,
+    pub media_types: Option<Vec<MediaType>>,
+    #[serde(default)]
+    pub video_quality: VideoQuality,
+    #[serde(default)]
+    pub skip_retweets: bool,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LoginSettings {
+    #[serde(default)]
+    pub hide_shared_app_note: bool,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NetworkSettings {
+    pub api_timeout_secs: Option<u64>,
+    pub max_requests: Option<u32>,
+    pub request_window_secs: Option<u64>,
 }
 
 #[derive(Clone, Default, Deserialize)]
@@ -49,6 +95,9 @@ pub struct DownloadSettings {
 pub struct RecordSettings {
     pub default_likes: Option<Vec<String>>,
     pub default_user: Option<Vec<String>>,
+    #[serde(default)]
+    pub merge_defaults: bool,
+    pub max_input_bytes: Option<usize>,
 }
 
 pub fn init() -> Result<()> {
@@ -71,12 +120,28 @@ pub fn init() -> Result<()> {
     Ok(())
 }
 
+/// Returns the path of the access token saved by `login`. `PHOG_ACCESS_TOKEN_FILE` overrides the
+/// default in the data directory, e.g. for secrets mounted at another path.
 pub fn access_token_path() -> PathBuf {
-    data_dir_path().join("access_token.json")
+    env::var("PHOG_ACCESS_TOKEN_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| data_dir_path().join("access_token.json"))
 }
 
+/// Returns the path of the credentials saved by `login --with-credentials`.
+/// `PHOG_CREDENTIALS_FILE` overrides the default in the data directory.
 pub fn credentials_path() -> PathBuf {
-    data_dir_path().join("credentials.json")
+    env::var("PHOG_CREDENTIALS_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| data_dir_path().join("credentials.json"))
+}
+
+/// Returns whether `PHOG_ACCESS_TOKEN_FILE` or `PHOG_CREDENTIALS_FILE` points at `path`. Such files
+/// may be secrets managed outside phog, so `logout` leaves them alone.
+pub fn is_overridden_login_path(path: &Path) -> bool {
+    ["PHOG_ACCESS_TOKEN_FILE", "PHOG_CREDENTIALS_FILE"]
+        .iter()
+        .any(|var| env::var_os(var).is_some_and(|p| Path::new(&p) == path))
 }
 
 pub fn config_dir_path() -> PathBuf {
@@ -137,12 +202,14 @@ pub fn settings_path() -> PathBuf {
 
 pub fn credentials() -> Result<Credentials> {
     CREDENTIALS
-        .get_or_try_init(load_credentials)
-        .map(|c| c.clone())
+        .get_or_try_init(|| load_credentials().wrap_err(ErrorKind::Config))
+        .cloned()
 }
 
 pub fn settings() -> Result<Settings> {
-    SETTINGS.get_or_try_init(load_settings).map(|s| s.clone())
+    SETTINGS
+        .get_or_try_init(|| load_settings().wrap_err(ErrorKind::Config))
+        .cloned()
 }
 
 pub fn save_access_token(token: String, secret: String) -> Result<()> {
@@ -151,25 +218,49 @@ pub fn save_access_token(token: String, secret: String) -> Result<()> {
         access_token_secret: secret,
     };
 
-    let mut f = File::create(access_token_path()).context("Could not create access_token.json")?;
+    let path = access_token_path();
+    let mut f = File::create(&path)
+        .with_context(|| format!("Could not create the access token file at {:?}", &path))?;
     let w = BufWriter::new(&mut f);
-    serde_json::to_writer(w, &access_token).context("Could not save access_token.json")?;
+    serde_json::to_writer(w, &access_token)
+        .with_context(|| format!("Could not save the access token to {:?}", &path))?;
     set_mode_600(&mut f)?;
 
     Ok(())
 }
 
 fn load_access_token() -> Result<AccessToken> {
-    let f = File::open(access_token_path()).context("Could not open access_token.json")?;
-    let access_token: AccessToken =
-        serde_json::from_reader(f).context("Could not load access_token.json")?;
+    let path = access_token_path();
+    let f = File::open(&path)
+        .with_context(|| format!("Could not open the access token file at {:?}", &path))?;
+    let access_token: AccessToken = serde_json::from_reader(f)
+        .with_context(|| format!("Could not load the access token from {:?}", &path))?;
     Ok(access_token)
 }
 
+/// Returns the user ID of the saved login. Twitter access tokens are prefixed with the user ID.
+pub fn saved_user_id() -> Option<String> {
+    let access_token = if credentials_path().is_file() {
+        let f = File::open(credentials_path()).ok()?;
+        let credentials: Credentials = serde_json::from_reader(f).ok()?;
+        credentials.access_token
+    } else {
+        load_access_token().ok()?.access_token
+    };
+    access_token
+        .split('-')
+        .next()
+        .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+        .map(|id| id.to_owned())
+}
+
 pub fn save_credentials(credentials: Credentials) -> Result<()> {
-    let mut f = File::create(access_token_path()).context("Could not create credentials.json")?;
+    let path = credentials_path();
+    let mut f = File::create(&path)
+        .with_context(|| format!("Could not create the credentials file at {:?}", &path))?;
     let w = BufWriter::new(&mut f);
-    serde_json::to_writer(w, &credentials).context("Could not save credentials.json")?;
+    serde_json::to_writer(w, &credentials)
+        .with_context(|| format!("Could not save the credentials to {:?}", &path))?;
     set_mode_600(&mut f)?;
     Ok(())
 }
@@ -177,8 +268,10 @@ pub fn save_credentials(credentials: Credentials) -> Result<()> {
 fn load_credentials() -> Result<Credentials> {
     let path = credentials_path();
     if path.is_file() {
-        let f = File::open(path).context("Could not open credentials.json")?;
-        let credentials = serde_json::from_reader(&f).context("Could not load credentials.json")?;
+        let f = File::open(&path)
+            .with_context(|| format!("Could not open the credentials file at {:?}", &path))?;
+        let credentials = serde_json::from_reader(&f)
+            .with_context(|| format!("Could not load the credentials from {:?}", &path))?;
         return Ok(credentials);
     }
 
@@ -209,27 +302,133 @@ fn load_credentials() -> Result<Credentials> {
     }
 }
 
+/// Removes the entries from `record.default-user` in config.toml, keeping the comments and the
+/// formatting of the rest of the file. Returns the number of removed entries.
+pub fn remove_default_user_entries(entries: &[String]) -> Result<usize> {
+    let path = settings_path();
+    let text = fs::read_to_string(&path).context("Could not read config.toml")?;
+    let (text, removed) = remove_array_values(&text, "default-user", entries)?;
+    if removed > 0 {
+        fs::write(&path, text).context("Could not save config.toml")?;
+    }
+    Ok(removed)
+}
+
+fn remove_array_values(toml: &str, key: &str, values: &[String]) -> Result<(String, usize)> {
+    let mut doc: toml_edit::DocumentMut = toml.parse().context("Could not load config.toml")?;
+    let mut removed = 0;
+    // `fetch` is the old name of the `record` table.
+    for table in ["record", "fetch"] {
+        let array = doc
+            .get_mut(table)
+            .and_then(|item| item.get_mut(key))
+            .and_then(|item| item.as_array_mut());
+        if let Some(array) = array {
+            let len = array.len();
+            // Keep the whitespace before the first element, e.g. a newline of a multiline array.
+            let first_prefix = array
+                .get(0)
+                .and_then(|value| value.decor().prefix().cloned());
+            array.retain(|value| {
+                !value
+                    .as_str()
+                    .is_some_and(|s| values.iter().any(|v| v == s))
+            });
+            if let (Some(first), Some(prefix)) = (array.get_mut(0), first_prefix) {
+                first.decor_mut().set_prefix(prefix);
+            }
+            removed += len - array.len();
+        }
+    }
+    Ok((doc.to_string(), removed))
+}
+
 fn load_settings() -> Result<Settings> {
     let mut f = File::open(settings_path()).context("Could not open config.toml")?;
     let mut buf = String::new();
     f.read_to_string(&mut buf)
         .context("Could not read config.toml")?;
     let mut settings = toml::from_str(&buf).context("Could not load config.toml")?;
-    expand_tilde_in_paths(&mut settings);
+    expand_paths(&mut settings);
+    validate_settings(&settings).context("Invalid config.toml")?;
     Ok(settings)
 }
 
-fn expand_tilde_in_paths(settings: &mut Settings) {
-    if let Some(dir) = settings.download.dir.as_ref().and_then(|p| p.to_str()) {
-        if dir.starts_with('~') {
-            let home = dirs::home_dir().expect("Could not locate the user's home directory");
-            if dir == "~" {
-                settings.download.dir = Some(home);
-            } else if let Some(stripped_dir) = dir.strip_prefix("~/") {
-                settings.download.dir = Some(home.join(stripped_dir));
-            }
-            // `~foo/` is not supported.
+fn validate_settings(settings: &Settings) -> Result<()> {
+    // curl takes the rate as a signed 64-bit integer.
+    ensure!(
+        settings.download.max_rate_bytes <= i64::MAX as u64,
+        "download.max-rate-bytes must be <= {}",
+        i64::MAX
+    );
+    ensure!(
+        settings.network.request_window_secs != Some(0),
+        "network.request-window-secs must be > 0"
+    );
+    Ok(())
+}
+
+/// Expands `~` and environment variables in all paths in the settings. New path settings should
+/// be added here.
+fn expand_paths(settings: &mut Settings) {
+    let home = dirs::home_dir();
+    let expand = |path: &mut Option<PathBuf>| {
+        if let Some(p) = path.as_mut() {
+            *p = expand_path(p, home.as_deref(), |name| env::var(name).ok());
         }
+    };
+
+    expand(&mut settings.download.dir);
+    expand(&mut settings.download.likes_dir);
+    expand(&mut settings.download.user_dir);
+}
+
+/// Expands a leading `~` or `~/` to the home directory and `$VAR` or `${VAR}` to the value of the
+/// variable. `~user` and unset variables are left as is with a warning.
+fn expand_path(
+    path: &Path,
+    home: Option<&Path>,
+    env_var: impl Fn(&str) -> Option<String>,
+) -> PathBuf {
+    static VAR_RE: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"\$(?:\{(\w+)\}|(\w+))").expect("regex must compile"));
+
+    let Some(s) = path.to_str() else {
+        return path.to_owned();
+    };
+    let s = VAR_RE.replace_all(s, |caps: &Captures| {
+        let name = caps
+            .get(1)
+            .or_else(|| caps.get(2))
+            .expect("name must match")
+            .as_str();
+        env_var(name).unwrap_or_else(|| {
+            eprintln!(
+                "Warning: ${} in {:?} is not set. It is left as is.",
+                name, path
+            );
+            caps[0].to_owned()
+        })
+    });
+
+    match (s.strip_prefix('~'), home) {
+        (Some(""), Some(home)) => home.to_owned(),
+        (Some(rest), Some(home)) if rest.starts_with('/') => home.join(&rest[1..]),
+        (Some(_), Some(_)) => {
+            eprintln!(
+                "Warning: ~user in {:?} is not supported. It is left as is.",
+                path
+            );
+            PathBuf::from(s.as_ref())
+        }
+        (Some(_), None) => {
+            eprintln!(
+                "Warning: Could not locate the home directory for {:?}.",
+                path
+            );
+            PathBuf::from(s.as_ref())
+        }
+        (None, _) => PathBuf::from(s.as_ref()),
     }
 }
 
@@ -245,3 +444,71 @@ fn set_mode_600(f: &mut File) -> io::Result<()> {
 fn set_mode_600(_f: &mut File) -> io::Result<()> {
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{expand_path, remove_array_values, validate_settings, Settings};
+
+    #[test]
+    fn paths_expanded() {
+        let home = Path::new("/home/me");
+        let expand = |path: &str| {
+            expand_path(Path::new(path), Some(home), |name| match name {
+                "HOME" => Some("/home/me".to_owned()),
+                "SUB" => Some("sub".to_owned()),
+                _ => None,
+            })
+        };
+
+        assert_eq!(expand("~"), PathBuf::from("/home/me"));
+        assert_eq!(expand("~/sub"), PathBuf::from("/home/me/sub"));
+        assert_eq!(expand("$HOME/sub"), PathBuf::from("/home/me/sub"));
+        assert_eq!(expand("${HOME}/${SUB}x"), PathBuf::from("/home/me/subx"));
+        assert_eq!(expand("/photos"), PathBuf::from("/photos"));
+        // Left as is.
+        assert_eq!(expand("~other/sub"), PathBuf::from("~other/sub"));
+        assert_eq!(expand("$UNSET/sub"), PathBuf::from("$UNSET/sub"));
+        assert_eq!(expand("/a/b~"), PathBuf::from("/a/b~"));
+    }
+
+    #[test]
+    fn zero_request_window_rejected() {
+        let mut settings = Settings::default();
+        assert!(validate_settings(&settings).is_ok());
+
+        settings.network.request_window_secs = Some(0);
+        assert!(validate_settings(&settings).is_err());
+
+        settings.network.request_window_secs = Some(1);
+        assert!(validate_settings(&settings).is_ok());
+    }
+
+    #[test]
+    fn array_values_removed() {
+        let toml = r#"# Comment
+record.default-user = ["user1", "@user2", "user3"]
+record.default-likes = ["user1"]
+"#;
+        let (result, removed) = remove_array_values(
+            toml,
+            "default-user",
+            &["user1".to_owned(), "user3".to_owned()],
+        )
+        .unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(
+            result,
+            r#"# Comment
+record.default-user = ["@user2"]
+record.default-likes = ["user1"]
+"#
+        );
+
+        let toml = "[record]\ndefault-user = [\"user1\"]\n";
+        let (_, removed) =
+            remove_array_values(toml, "default-user", &["user2".to_owned()]).unwrap();
+        assert_eq!(removed, 0);
+    }
+}
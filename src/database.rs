@@ -1,9 +1,12 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
 
-use rusqlite::{named_params, params};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{named_params, params, OpenFlags, OptionalExtension};
 use serde::Deserialize;
 
 use crate::result::*;
@@ -11,11 +14,180 @@ use crate::twitter::Tweet;
 
 static SCHEMA_SQL: &str = include_str!("../data/schema.sql");
 
+// MIGRATIONS[n] migrates the schema from version n to n + 1.
+static MIGRATIONS: &[&str] = &[
+    include_str!("../data/migrations/0001_compressible_content.sql"),
+    include_str!("../data/migrations/0002_download_failures.sql"),
+    include_str!("../data/migrations/0003_media_removed_at.sql"),
+    include_str!("../data/migrations/0004_content_format.sql"),
+    include_str!("../data/migrations/0005_media_forbidden_at.sql"),
+    include_str!("../data/migrations/0006_text_column.sql"),
+    include_str!("../data/migrations/0007_lang_column.sql"),
+    include_str!("../data/migrations/0008_source_column.sql"),
+    include_str!("../data/migrations/0009_deleted_at_column.sql"),
+    include_str!("../data/migrations/0010_media_downloads.sql"),
+];
+
+/// Returns the schema version databases are migrated to.
+pub fn latest_schema_version() -> usize {
+    MIGRATIONS.len()
+}
+
+/// A migration not applied to the database yet.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Migration {
+    /// The schema version after the migration.
+    pub version: usize,
+    /// The leading comment of the migration SQL.
+    pub description: String,
+}
+
+/// The format of the tweets fetched by `twitter::Client`.
+const TWEET_CONTENT_FORMAT: ContentFormat = ContentFormat::V1_1;
+
+/// Number of rows inserted into a temp table per statement, well under SQLite's limit on the
+/// number of parameters.
+const STATUS_IDS_PER_INSERT: usize = 500;
+
+/// Tweets whose photos failed to download this many times are excluded from downloads.
+pub const MAX_DOWNLOAD_FAILURES: u32 = 3;
+
+/// How long to wait for another phog process to release the database, unless
+/// `database.busy-timeout-ms` is set.
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Enough to hold every statement prepared with `prepare_cached`, so that none is evicted.
+const STATEMENT_CACHE_CAPACITY: usize = 32;
+
+/// A connection to the phog database.
+///
+/// Statements run repeatedly, e.g. for each batch recorded while watching the clipboard, are
+/// prepared once and cached on the connection.
+///
+/// The database is in WAL mode so that other phog processes can read while one writes. Writes
+/// wait up to the busy timeout for another process to finish writing. Write transactions are
+/// started with `BEGIN IMMEDIATE`, as SQLite cannot wait when a read transaction is upgraded.
+///
+/// Like `rusqlite::Connection`, this is `Send` but not `Sync`; use it from one thread at a time.
 pub struct Connection {
     conn: rusqlite::Connection,
+    compresses_content: bool,
+    media: MediaChoice,
+    skips_retweets: bool,
+}
+
+/// Types of media in tweets. Photosets consist of the media of the types to download.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaType {
+    Photo,
+    AnimatedGif,
+    Video,
+}
+
+impl MediaType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MediaType::Photo => "photo",
+            MediaType::AnimatedGif => "animated_gif",
+            MediaType::Video => "video",
+        }
+    }
+}
+
+pub const DEFAULT_MEDIA_TYPES: &[MediaType] = &[MediaType::Photo];
+
+/// Which MP4 variant of GIFs and videos to download, set by `download.video-quality`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub enum VideoQuality {
+    #[default]
+    Highest,
+    Lowest,
+    /// The highest bitrate up to this many kbps, or the lowest one if all are higher.
+    AtMostKbps(u64),
+}
+
+impl VideoQuality {
+    fn select(self, variants: Vec<VideoVariant>) -> Option<String> {
+        let mp4_variants = variants
+            .into_iter()
+            .filter(|v| v.content_type == "video/mp4");
+        let bitrate = |v: &VideoVariant| v.bitrate.unwrap_or(0);
+        let variant = match self {
+            VideoQuality::Highest => mp4_variants.max_by_key(bitrate),
+            VideoQuality::Lowest => mp4_variants.min_by_key(bitrate),
+            VideoQuality::AtMostKbps(kbps) => {
+                let (within, over): (Vec<_>, Vec<_>) =
+                    mp4_variants.partition(|v| bitrate(v) <= kbps.saturating_mul(1000));
+                match within.into_iter().max_by_key(bitrate) {
+                    Some(variant) => Some(variant),
+                    None => over.into_iter().min_by_key(bitrate),
+                }
+            }
+        };
+        variant.map(|v| v.url)
+    }
+}
+
+impl FromStr for VideoQuality {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let kbps = s
+            .strip_prefix("<=")
+            .and_then(|rest| rest.trim().strip_suffix("kbps"))
+            .map(|n| n.trim().parse::<u64>());
+        match (s, kbps) {
+            ("highest", _) => Ok(VideoQuality::Highest),
+            ("lowest", _) => Ok(VideoQuality::Lowest),
+            (_, Some(Ok(kbps))) => Ok(VideoQuality::AtMostKbps(kbps)),
+            _ => Err(format!(
+                "Invalid video quality: {:?} (expected \"highest\", \"lowest\" or \"<=Nkbps\")",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for VideoQuality {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Shapes of the JSON stored in `tweets.content`. Queries assume `V1_1`; other formats must be
+/// normalized to it when read.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ContentFormat {
+    /// Tweet objects of the Twitter API v1.1 with `tweet_mode=extended`.
+    V1_1,
+}
+
+impl ContentFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentFormat::V1_1 => "v1.1",
+        }
+    }
 }
 
 impl Connection {
+    fn from_inner(conn: rusqlite::Connection) -> Self {
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        Connection {
+            conn,
+            compresses_content: false,
+            media: MediaChoice {
+                media_types: DEFAULT_MEDIA_TYPES.to_vec(),
+                video_quality: VideoQuality::default(),
+            },
+            skips_retweets: false,
+        }
+    }
+
     pub fn open<P>(path: P) -> Result<Self>
     where
         P: AsRef<Path>,
@@ -24,14 +196,62 @@ impl Connection {
         log::trace!("opening database at {:?}", path);
         fs::create_dir_all(path.parent().expect("database path must have base dir"))?;
         let conn = rusqlite::Connection::open(path)?;
+        conn.busy_timeout(DEFAULT_BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        register_functions(&conn)?;
         log::trace!("opened database");
-        Ok(Connection { conn })
+        Ok(Connection::from_inner(conn))
     }
 
     #[cfg(test)]
     pub fn open_in_memory() -> Result<Self> {
         let conn = rusqlite::Connection::open_in_memory()?;
-        Ok(Connection { conn })
+        register_functions(&conn)?;
+        Ok(Connection::from_inner(conn))
+    }
+
+    /// Stores the content of newly inserted tweets compressed.
+    pub fn with_compressed_content(self, compresses_content: bool) -> Self {
+        Connection {
+            compresses_content,
+            ..self
+        }
+    }
+
+    /// Excludes retweets from the photosets to download. They stay pending.
+    pub fn with_skipped_retweets(self, skips_retweets: bool) -> Self {
+        Connection {
+            skips_retweets,
+            ..self
+        }
+    }
+
+    /// Sets how long to wait for another process to release the database before failing.
+    pub fn with_busy_timeout(self, timeout: Duration) -> Result<Self> {
+        self.conn.busy_timeout(timeout)?;
+        Ok(self)
+    }
+
+    /// Sets the types of media to download. Tweets with no media of these types are prunable.
+    pub fn with_media_types(self, media_types: Vec<MediaType>) -> Self {
+        Connection {
+            media: MediaChoice {
+                media_types,
+                ..self.media
+            },
+            ..self
+        }
+    }
+
+    /// Sets which variant of GIFs and videos photosets point to.
+    pub fn with_video_quality(self, video_quality: VideoQuality) -> Self {
+        Connection {
+            media: MediaChoice {
+                video_quality,
+                ..self.media
+            },
+            ..self
+        }
     }
 
     #[cfg(test)]
@@ -39,12 +259,166 @@ impl Connection {
         &self.conn
     }
 
+    /// Opens the database read-only, without creating or migrating it, for commands that only
+    /// read. If the database does not exist, an empty in-memory database is used instead.
+    ///
+    /// On read-only media, where SQLite cannot create the shared-memory file of the WAL, the
+    /// database is opened as immutable, i.e. without locking or looking for other writers.
+    pub fn open_readonly<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        if !path.exists() {
+            log::trace!("opening in-memory database for reading; path={:?}", path);
+            let conn = rusqlite::Connection::open_in_memory()?;
+            register_functions(&conn)?;
+            let conn = Connection::from_inner(conn);
+            conn.create()?;
+            return Ok(conn);
+        }
+
+        let conn = Connection::open_existing_readonly(path)?;
+        ensure!(
+            conn.schema_version()? == MIGRATIONS.len(),
+            "The database needs to be migrated. Run `phog migrate` first."
+        );
+        Ok(conn)
+    }
+
+    /// Migrates the database through a write connection if it exists and is behind, so that it
+    /// can be opened with `open_readonly`.
+    pub fn migrate_if_needed<P>(path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        if !path.exists()
+            || Connection::open_existing_readonly(path)?
+                .pending_migrations()?
+                .is_empty()
+        {
+            return Ok(());
+        }
+        log::trace!("migrating database before reading; path={:?}", path);
+        Connection::open(path)?.create()
+    }
+
+    /// Opens an existing database read-only as is, without checking its schema version. Unlike
+    /// `open`, this never writes to the file, e.g. to switch the journal mode.
+    pub fn open_existing_readonly(path: &Path) -> Result<Self> {
+        log::trace!("opening database read-only at {:?}", path);
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let mut conn = rusqlite::Connection::open_with_flags(path, flags)?;
+        // Opening succeeds lazily, so read something to find out if the WAL can be used.
+        let probe = conn.query_row("PRAGMA schema_version;", params![], |row| {
+            row.get::<_, i64>(0)
+        });
+        if let Err(rusqlite::Error::SqliteFailure(e, _)) = &probe {
+            if e.code == rusqlite::ErrorCode::CannotOpen {
+                let mut uri = url::Url::from_file_path(path)
+                    .map_err(|_| format_err!("Could not open the database at {:?}", path))?;
+                uri.set_query(Some("immutable=1"));
+                log::trace!("opening database as immutable; uri={}", uri);
+                conn = rusqlite::Connection::open_with_flags(
+                    uri.as_str(),
+                    flags | OpenFlags::SQLITE_OPEN_URI,
+                )?;
+            }
+        }
+        conn.busy_timeout(DEFAULT_BUSY_TIMEOUT)?;
+        register_functions(&conn)?;
+        Ok(Connection::from_inner(conn))
+    }
+
+    /// Opens the database without creating or migrating it. If the database does not exist, an
+    /// empty in-memory database is used instead.
+    pub fn open_for_dry_run<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        if !path.exists() {
+            log::trace!("opening in-memory database for dry run; path={:?}", path);
+            let conn = rusqlite::Connection::open_in_memory()?;
+            register_functions(&conn)?;
+            let conn = Connection::from_inner(conn);
+            conn.create()?;
+            return Ok(conn);
+        }
+
+        log::trace!("opening database for dry run at {:?}", path);
+        let conn = rusqlite::Connection::open(path)?;
+        register_functions(&conn)?;
+        let conn = Connection::from_inner(conn);
+        ensure!(
+            conn.schema_version()? == MIGRATIONS.len(),
+            "The database needs to be migrated. Run `phog migrate` or the command without --dry-run first."
+        );
+        Ok(conn)
+    }
+
     pub fn create(&self) -> Result<()> {
         self.conn.execute_batch(SCHEMA_SQL)?;
         log::trace!("created tables");
+        self.migrate()
+    }
+
+    fn migrate(&self) -> Result<()> {
+        let version = self.schema_version()?;
+        for (from, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+            let to = from + 1;
+            log::trace!("migrating database; from={}, to={}", from, to);
+            let tx = self.conn.unchecked_transaction()?;
+            tx.execute_batch(sql)
+                .with_context(|| format!("Could not migrate database to version {}", to))?;
+            tx.execute(
+                "UPDATE metadata SET value = json_quote(?) WHERE key = 'schema_version';",
+                params![to],
+            )?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the migrations to apply to bring the database to the latest schema, in order.
+    pub fn pending_migrations(&self) -> Result<Vec<Migration>> {
+        let version = self.schema_version()?;
+        Ok(MIGRATIONS
+            .iter()
+            .enumerate()
+            .skip(version)
+            .map(|(from, sql)| Migration {
+                version: from + 1,
+                description: sql
+                    .lines()
+                    .map_while(|line| line.strip_prefix("-- "))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            })
+            .collect())
+    }
+
+    /// Copies the whole database into a new file, e.g. to back it up before migrating.
+    pub fn backup_into(&self, path: &Path) -> Result<()> {
+        ensure!(!path.exists(), "{:?} already exists", path);
+        let path = path
+            .to_str()
+            .ok_or_else(|| format_err!("Backup path is not valid UTF-8: {:?}", path))?;
+        self.conn.execute("VACUUM INTO ?;", params![path])?;
+        log::trace!("backed up database; path={:?}", path);
         Ok(())
     }
 
+    pub fn schema_version(&self) -> Result<usize> {
+        let version: i64 = self.conn.query_row(
+            "SELECT CAST(value AS INTEGER) FROM metadata WHERE key = 'schema_version';",
+            params![],
+            |row| row.get(0),
+        )?;
+        Ok(version as usize)
+    }
+
     pub fn count_tweets(&self) -> Result<u64> {
         let count: i64 = self
             .conn
@@ -52,46 +426,182 @@ impl Connection {
         Ok(count as u64)
     }
 
+    /// Returns the stored JSON of the tweet, decompressed if needed.
+    pub fn get_content(&self, status_id: u64) -> Result<Option<String>> {
+        let content = self
+            .conn
+            .query_row(
+                "SELECT tweet_content(content) FROM tweets WHERE status_id = ?;",
+                params![status_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(content)
+    }
+
+    /// Returns the text of the tweets in the rows, decompressed if needed. Rows without text are
+    /// left out.
+    pub fn get_texts(&self, rowids: &[i64]) -> Result<HashMap<i64, String>> {
+        let rowids = serde_json::to_string(rowids).expect("rowids must be serializable");
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT rowid, json_extract(tweet_content(content), '$.full_text')
+            FROM tweets
+            WHERE rowid IN (SELECT value FROM json_each(?))
+                AND json_extract(tweet_content(content), '$.full_text') IS NOT NULL;
+            "#,
+        )?;
+        let rows = stmt.query_map(params![rowids], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns what is kept of the pruned tweet as a JSON object. Pruned tweets keep only their
+    /// media and a few other columns.
+    pub fn get_pruned_content(&self, status_id: u64) -> Result<Option<String>> {
+        let content = self
+            .conn
+            .query_row(
+                r#"
+                SELECT json_object(
+                    'status_id', status_id,
+                    'user_id', user_id,
+                    'screen_name', screen_name,
+                    'media', json(media),
+                    'in_timeline', json(CASE in_timeline WHEN 1 THEN 'true' ELSE 'false' END),
+                    'recorded_at', recorded_at,
+                    'photos_downloaded_at', photos_downloaded_at,
+                    'pruned_at', pruned_at
+                )
+                FROM pruned_tweets WHERE status_id = ?;
+                "#,
+                params![status_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(content)
+    }
+
+    /// Inserts tweets recorded from URLs or status IDs.
     pub fn insert_loose_tweets(&self, tweets: &[Tweet]) -> Result<usize> {
-        self.conn.execute("BEGIN;", params![])?;
-        let inserted = self.insert_tweets(tweets, false)?;
-        log::trace!("inserted unseen loose tweets; n={}", inserted);
+        self.insert_loose_tweets_from(tweets, TweetSource::Url)
+    }
+
+    pub fn insert_liked_tweets(&self, tweets: &[Tweet]) -> Result<usize> {
+        self.insert_loose_tweets_from(tweets, TweetSource::Likes)
+    }
+
+    fn insert_loose_tweets_from(&self, tweets: &[Tweet], source: TweetSource) -> Result<usize> {
+        self.conn.execute("BEGIN IMMEDIATE;", params![])?;
+        let inserted = self.insert_tweets(tweets, false, source)?;
+        log::trace!(
+            "inserted unseen loose tweets; n={}, source={}",
+            inserted,
+            source.as_str()
+        );
         self.conn.execute("COMMIT;", params![])?;
         Ok(inserted)
     }
 
-    pub fn insert_timeline_tweets(&self, tweets: &[Tweet]) -> Result<usize> {
-        let mut update_tweet_stmt = self.conn.prepare(
+    /// Replaces the content of already recorded tweets with the fetched ones. Pruned tweets are not
+    /// updated as their content has been dropped. Tweets marked as deleted are unmarked since they
+    /// are back.
+    pub fn update_tweet_contents(&self, tweets: &[Tweet]) -> Result<usize> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            UPDATE tweets
+            SET content = ?1,
+                content_format = ?2,
+                user_id = json_extract(?3, '$.user.id_str'),
+                screen_name = json_extract(?3, '$.user.screen_name'),
+                text = json_extract(?3, '$.full_text'),
+                lang = ?4,
+                deleted_at = NULL
+            WHERE status_id = ?5;
+            "#,
+        )?;
+
+        self.conn.execute("BEGIN IMMEDIATE;", params![])?;
+        let mut updated = 0;
+        for tweet in tweets {
+            let content = if self.compresses_content {
+                Value::Blob(compress_content(&tweet.json)?)
+            } else {
+                Value::Text(tweet.json.clone())
+            };
+            updated += stmt.execute(params![
+                content,
+                TWEET_CONTENT_FORMAT.as_str(),
+                tweet.json,
+                tweet.lang,
+                tweet.id.to_string()
+            ])?;
+        }
+        self.conn.execute("COMMIT;", params![])?;
+        log::trace!("updated tweet contents; n={}", updated);
+
+        Ok(updated)
+    }
+
+    /// Marks the recorded tweets as deleted upstream, keeping their content. Returns the number of
+    /// tweets newly marked.
+    pub fn set_deleted_at(&self, status_ids: &[u64]) -> Result<usize> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            UPDATE tweets
+            SET deleted_at = CURRENT_TIMESTAMP
+            WHERE status_id = ? AND deleted_at IS NULL;
+            "#,
+        )?;
+
+        self.conn.execute("BEGIN IMMEDIATE;", params![])?;
+        let mut marked = 0;
+        for status_id in status_ids {
+            marked += stmt.execute(params![status_id.to_string()])?;
+        }
+        self.conn.execute("COMMIT;", params![])?;
+        log::trace!("set deleted_at; n={}", marked);
+
+        Ok(marked)
+    }
+
+    pub fn insert_timeline_tweets(&self, tweets: &[Tweet]) -> Result<InsertCounts> {
+        let mut update_tweet_stmt = self.conn.prepare_cached(
             r#"
-            UPDATE tweets SET in_timeline = 1 WHERE status_id = ?;
+            UPDATE tweets SET in_timeline = 1 WHERE status_id = ? AND in_timeline = 0;
             "#,
         )?;
-        let mut update_pruned_tweet_stmt = self.conn.prepare(
+        let mut update_pruned_tweet_stmt = self.conn.prepare_cached(
             r#"
-            UPDATE pruned_tweets SET in_timeline = 1 WHERE status_id = ?;
+            UPDATE pruned_tweets SET in_timeline = 1 WHERE status_id = ? AND in_timeline = 0;
             "#,
         )?;
 
-        self.conn.execute("BEGIN;", params![])?;
+        self.conn.execute("BEGIN IMMEDIATE;", params![])?;
 
+        let mut updated = 0;
         for tweet in tweets {
-            update_tweet_stmt.execute(params![tweet.id.to_string()])?;
-            update_pruned_tweet_stmt.execute(params![tweet.id.to_string()])?;
+            updated += update_tweet_stmt.execute(params![tweet.id.to_string()])?;
+            updated += update_pruned_tweet_stmt.execute(params![tweet.id.to_string()])?;
         }
         log::trace!(
             "updated in_timeline for tweets and pruned_tweets; n={}",
-            tweets.len()
+            updated
         );
 
-        let inserted = self.insert_tweets(tweets, true)?;
+        let inserted = self.insert_tweets(tweets, true, TweetSource::User)?;
         log::trace!("inserted unseen timeline tweets; n={}", inserted);
 
         self.conn.execute("COMMIT;", params![])?;
 
-        Ok(inserted)
+        Ok(InsertCounts { inserted, updated })
     }
 
-    fn insert_tweets(&self, tweets: &[Tweet], in_timeline: bool) -> Result<usize> {
+    fn insert_tweets(
+        &self,
+        tweets: &[Tweet],
+        in_timeline: bool,
+        source: TweetSource,
+    ) -> Result<usize> {
         fn take_unseen_tweets<'a>(
             conn: &Connection,
             tweets: &'a [Tweet],
@@ -107,10 +617,32 @@ impl Connection {
             Ok(tweets)
         }
 
-        let mut stmt = self.conn.prepare(
+        let mut stmt = self.conn.prepare_cached(
             r#"
-            INSERT OR IGNORE INTO tweets (status_id, content, in_timeline, recorded_at)
-            VALUES (?, ?, ?, ?);
+            INSERT OR IGNORE INTO tweets (
+                status_id,
+                content,
+                content_format,
+                user_id,
+                screen_name,
+                text,
+                lang,
+                source,
+                in_timeline,
+                recorded_at
+            )
+            VALUES (
+                ?1,
+                ?2,
+                ?3,
+                json_extract(?4, '$.user.id_str'),
+                json_extract(?4, '$.user.screen_name'),
+                json_extract(?4, '$.full_text'),
+                ?5,
+                ?6,
+                ?7,
+                ?8
+            );
             "#,
         )?;
 
@@ -120,9 +652,18 @@ impl Connection {
 
         let mut inserted = 0;
         for tweet in take_unseen_tweets(self, tweets)? {
+            let content = if self.compresses_content {
+                Value::Blob(compress_content(&tweet.json)?)
+            } else {
+                Value::Text(tweet.json.clone())
+            };
             inserted += stmt.execute(params![
                 tweet.id.to_string(),
+                content,
+                TWEET_CONTENT_FORMAT.as_str(),
                 tweet.json,
+                tweet.lang,
+                source.as_str(),
                 in_timeline,
                 recorded_at
             ])?;
@@ -132,20 +673,78 @@ impl Connection {
     }
 
     pub fn prune_tweets(&self) -> Result<usize> {
-        struct Row {
-            status_id: String,
-            user_id: String,
-            screen_name: String,
-            media: Option<String>,
-            in_timeline: bool,
-            recorded_at: Option<String>,
-            photos_downloaded_at: Option<String>,
-        }
+        let pruned_at: String =
+            self.conn
+                .query_row("SELECT CURRENT_TIMESTAMP;", params![], |row| row.get(0))?;
+
+        let mut insert_stmt = self.conn.prepare_cached(
+            r#"
+            INSERT OR IGNORE INTO pruned_tweets (
+                status_id,
+                user_id,
+                screen_name,
+                media,
+                in_timeline,
+                recorded_at,
+                photos_downloaded_at,
+                pruned_at
+            )
+            VALUES (
+                :status_id,
+                :user_id,
+                :screen_name,
+                :media,
+                :in_timeline,
+                :recorded_at,
+                :photos_downloaded_at,
+                :pruned_at
+            );
+            "#,
+        )?;
+        let mut delete_stmt = self.conn.prepare_cached(
+            r#"
+            DELETE FROM tweets WHERE status_id = ?;
+            "#,
+        )?;
+
+        self.conn.execute("BEGIN IMMEDIATE;", params![])?;
+        let mut pruned = 0;
+        self.for_each_prunable_row(|row| {
+            insert_stmt.execute(named_params! {
+                ":status_id": row.status_id,
+                ":user_id": row.user_id,
+                ":screen_name": row.screen_name,
+                ":media": row.media,
+                ":in_timeline": row.in_timeline,
+                ":recorded_at": row.recorded_at,
+                ":photos_downloaded_at": row.photos_downloaded_at,
+                ":pruned_at": pruned_at
+            })?;
+            delete_stmt.execute(params![row.status_id])?;
+            pruned += 1;
+            Ok(())
+        })?;
+        self.conn.execute("COMMIT;", params![])?;
+
+        Ok(pruned)
+    }
+
+    /// Returns the number of tweets `prune_tweets` would prune, without pruning them. It only reads
+    /// the database, so it does not wait for or block other writers.
+    pub fn count_prunable_tweets(&self) -> Result<usize> {
+        let mut prunable = 0;
+        self.for_each_prunable_row(|_row| {
+            prunable += 1;
+            Ok(())
+        })?;
+        Ok(prunable)
+    }
 
+    fn for_each_prunable_row(&self, mut f: impl FnMut(PrunableRow) -> Result<()>) -> Result<()> {
         // Returns true is the row has no media,
-        // or the media contains no photos,
-        // or the photos are already downloaded.
-        fn is_prunable_row(row: &Row) -> bool {
+        // or the media contains nothing to download,
+        // or the media is already downloaded.
+        fn is_prunable_row(row: &PrunableRow, media_types: &[MediaType]) -> bool {
             match row.media {
                 None => true,
                 Some(ref media) => match serde_json::from_str::<Option<Vec<MediaEntity>>>(media) {
@@ -158,7 +757,7 @@ impl Connection {
                     }
                     Ok(None) => true,
                     Ok(Some(media_entities)) => {
-                        if media_entities.iter().any(|m| m.type_ == "photo") {
+                        if media_entities.iter().any(|m| m.is_one_of(media_types)) {
                             row.photos_downloaded_at.is_some()
                         } else {
                             true
@@ -168,25 +767,23 @@ impl Connection {
             }
         }
 
-        let mut stmt = self.conn.prepare(
+        let mut stmt = self.conn.prepare_cached(
             r#"
             SELECT
                 status_id,
-                json_extract(tweets.content, '$.user.id_str') AS user_id,
-                json_extract(tweets.content, '$.user.screen_name') AS screen_name,
-                IFNULL(
-                    json_extract(tweets.content, '$.extended_entities.media'),
-                    json_quote(json_extract(tweets.content, '$.extended_entities.media'))
-                ) AS media,
+                user_id,
+                screen_name,
+                tweet_media(tweet_content(tweets.content)) AS media,
                 in_timeline,
                 recorded_at,
                 photos_downloaded_at
             FROM tweets
+            WHERE deleted_at IS NULL
             ORDER BY id;
             "#,
         )?;
         let rows = stmt.query_map(params![], |row| {
-            Ok(Row {
+            Ok(PrunableRow {
                 status_id: row.get_unwrap("status_id"),
                 user_id: row.get_unwrap("user_id"),
                 screen_name: row.get_unwrap("screen_name"),
@@ -197,61 +794,32 @@ impl Connection {
             })
         })?;
 
-        let pruned_at: String =
-            self.conn
-                .query_row("SELECT CURRENT_TIMESTAMP;", params![], |row| row.get(0))?;
+        for row in rows.flatten() {
+            if is_prunable_row(&row, &self.media.media_types) {
+                f(row)?;
+            }
+        }
+        Ok(())
+    }
 
-        let mut insert_stmt = self.conn.prepare(
-            r#"
-            INSERT OR IGNORE INTO pruned_tweets (
-                status_id,
-                user_id,
-                screen_name,
-                media,
-                in_timeline,
-                recorded_at,
-                photos_downloaded_at,
-                pruned_at
-            )
-            VALUES (
-                :status_id,
-                :user_id,
-                :screen_name,
-                :media,
-                :in_timeline,
-                :recorded_at,
-                :photos_downloaded_at,
-                :pruned_at
-            );
-            "#,
-        )?;
-        let mut delete_stmt = self.conn.prepare(
+    /// Returns the IDs of all users whose tweets have been recorded, including pruned ones.
+    pub fn select_distinct_user_ids(&self) -> Result<Vec<u64>> {
+        let mut stmt = self.conn.prepare(
             r#"
-            DELETE FROM tweets WHERE status_id = ?;
+            SELECT DISTINCT user_id FROM seen_tweets WHERE user_id IS NOT NULL;
             "#,
         )?;
+        let rows = stmt.query_map(params![], |row| {
+            let user_id: String = row.get_unwrap(0);
+            Ok(user_id)
+        })?;
 
-        self.conn.execute("BEGIN;", params![])?;
-        let mut pruned = 0;
-        for row in rows.flatten() {
-            if is_prunable_row(&row) {
-                insert_stmt.execute(named_params! {
-                    ":status_id": row.status_id,
-                    ":user_id": row.user_id,
-                    ":screen_name": row.screen_name,
-                    ":media": row.media,
-                    ":in_timeline": row.in_timeline,
-                    ":recorded_at": row.recorded_at,
-                    ":photos_downloaded_at": row.photos_downloaded_at,
-                    ":pruned_at": pruned_at
-                })?;
-                delete_stmt.execute(params![row.status_id])?;
-                pruned += 1;
-            }
-        }
-        self.conn.execute("COMMIT;", params![])?;
-
-        Ok(pruned)
+        let mut user_ids: Vec<u64> = rows
+            .flatten()
+            .filter_map(|user_id| u64::from_str(&user_id).ok())
+            .collect();
+        user_ids.sort_unstable();
+        Ok(user_ids)
     }
 
     pub fn select_max_status_id(&self, user_id: u64) -> Result<Option<String>> {
@@ -274,51 +842,220 @@ impl Connection {
         Ok(max.map(|(status_id, _)| status_id))
     }
 
-    pub fn select_not_downloaded_photos(&self) -> Result<Vec<Photoset>> {
-        #[derive(Eq, Ord, PartialEq, PartialOrd)]
-        struct Row {
-            rowid: i64,
-            screen_name: String,
-            id_str: String,
-            media_json: String,
-        }
-
+    /// Returns the latest status ID of each user whose tweets have been recorded, including pruned
+    /// ones, sorted by user ID.
+    pub fn select_latest_status_ids(&self) -> Result<Vec<UserLatestStatus>> {
         let mut stmt = self.conn.prepare(
             r#"
-            SELECT
-                rowid,
-                json_extract(tweets.content, '$.user.screen_name'),
-                json_extract(tweets.content, '$.id_str'),
-                json_quote(json_extract(tweets.content, '$.extended_entities.media'))
-            FROM tweets
-            WHERE tweets.photos_downloaded_at IS NULL;
+            SELECT user_id, screen_name, status_id FROM tweets
+            UNION ALL
+            SELECT user_id, screen_name, status_id FROM pruned_tweets;
             "#,
         )?;
         let rows = stmt.query_map(params![], |row| {
-            // Use unwrap here to panic if there is data inconsistency.
-            let rowid = row.get_unwrap(0);
-            let screen_name = row.get_unwrap(1);
-            let id_str = row.get_unwrap(2);
-            let media_json = row.get_unwrap(3);
-            Ok(Row {
-                rowid,
-                screen_name,
-                id_str,
-                media_json,
+            let user_id: Option<String> = row.get_unwrap(0);
+            let screen_name: Option<String> = row.get_unwrap(1);
+            let status_id: String = row.get_unwrap(2);
+            Ok((user_id, screen_name, status_id))
+        })?;
+
+        let mut latest: HashMap<u64, UserLatestStatus> = HashMap::new();
+        for (user_id, screen_name, status_id) in rows.flatten() {
+            let user_id = match user_id.and_then(|id| u64::from_str(&id).ok()) {
+                Some(user_id) => user_id,
+                None => continue,
+            };
+            let status_id = match u64::from_str(&status_id) {
+                Ok(status_id) => status_id,
+                Err(_) => continue,
+            };
+            let entry = latest.entry(user_id).or_insert_with(|| UserLatestStatus {
+                user_id,
+                screen_name: None,
+                status_id,
+            });
+            if status_id >= entry.status_id {
+                entry.status_id = status_id;
+                // Users may have changed their screen names; keep the latest one.
+                if screen_name.is_some() {
+                    entry.screen_name = screen_name;
+                }
+            } else if entry.screen_name.is_none() {
+                entry.screen_name = screen_name;
+            }
+        }
+
+        let mut result: Vec<_> = latest.into_values().collect();
+        result.sort_unstable_by_key(|s| s.user_id);
+        Ok(result)
+    }
+
+    /// Selects photosets to download. If `skips_forbidden` is true, tweets whose media was refused
+    /// with HTTP 403 are excluded.
+    pub fn select_not_downloaded_photos(&self, skips_forbidden: bool) -> Result<PhotosetSelection> {
+        let excluded_failed: i64 = self.conn.query_row(
+            r#"
+            SELECT COUNT(*) FROM tweets
+            WHERE photos_downloaded_at IS NULL AND download_failures >= ?;
+            "#,
+            params![MAX_DOWNLOAD_FAILURES],
+            |row| row.get(0),
+        )?;
+        let excluded_forbidden: i64 = if skips_forbidden {
+            self.conn.query_row(
+                r#"
+                SELECT COUNT(*) FROM tweets
+                WHERE photos_downloaded_at IS NULL
+                    AND download_failures < ?
+                    AND media_forbidden_at IS NOT NULL;
+                "#,
+                params![MAX_DOWNLOAD_FAILURES],
+                |row| row.get(0),
+            )?
+        } else {
+            0
+        };
+
+        struct Row {
+            photoset: PhotosetRow,
+            is_retweet: bool,
+        }
+
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT
+                rowid,
+                json_extract(tweet_content(tweets.content), '$.user.screen_name'),
+                json_extract(tweet_content(tweets.content), '$.id_str'),
+                tweet_media(tweet_content(tweets.content)),
+                json_type(tweet_content(tweets.content), '$.retweeted_status') IS NOT NULL,
+                tweets.lang,
+                tweets.source
+            FROM tweets
+            WHERE tweets.photos_downloaded_at IS NULL
+                AND tweets.download_failures < ?
+                AND (NOT ? OR tweets.media_forbidden_at IS NULL);
+            "#,
+        )?;
+        let rows = stmt.query_map(params![MAX_DOWNLOAD_FAILURES, skips_forbidden], |row| {
+            // Use unwrap here to panic if there is data inconsistency.
+            let rowid = row.get_unwrap(0);
+            let screen_name = row.get_unwrap(1);
+            let id_str = row.get_unwrap(2);
+            let media_json = row.get_unwrap(3);
+            let is_retweet = row.get_unwrap(4);
+            let lang = row.get_unwrap(5);
+            let source = row.get_unwrap(6);
+            Ok(Row {
+                photoset: PhotosetRow {
+                    rowid,
+                    screen_name,
+                    id_str,
+                    media_json,
+                    lang,
+                    source,
+                },
+                is_retweet,
             })
         })?;
 
-        let mut photosets = vec![];
+        let mut selection = PhotosetSelection {
+            excluded_failed: excluded_failed as usize,
+            excluded_forbidden: excluded_forbidden as usize,
+            ..PhotosetSelection::default()
+        };
+
+        // Maps sorted photo URLs to the index of the photoset in the selection.
+        let mut seen_photo_urls: HashMap<Vec<String>, usize> = HashMap::new();
 
         for row in rows.flatten() {
-            match build_photoset(row.rowid, row.screen_name, row.id_str, row.media_json) {
-                Ok(Some(photoset)) => photosets.push(photoset),
+            match build_photoset(row.photoset, &self.media) {
+                Ok(Some(_)) if row.is_retweet && self.skips_retweets => {
+                    selection.excluded_retweets += 1;
+                }
+                Ok(Some(photoset)) => {
+                    let mut key = photoset.photo_urls.clone();
+                    key.sort_unstable();
+                    if let Some(&i) = seen_photo_urls.get(&key) {
+                        // The same media recorded under another row, e.g. before and after the
+                        // user changed their screen name. Download it only once.
+                        log::trace!(
+                            "merging duplicate photoset; rowid={}, into={}",
+                            photoset.rowid,
+                            selection.photosets[i].rowid
+                        );
+                        selection.photosets[i].duplicate_rowids.push(photoset.rowid);
+                        selection.duplicates += 1;
+                    } else {
+                        seen_photo_urls.insert(key, selection.photosets.len());
+                        selection.photosets.push(photoset);
+                    }
+                }
                 Ok(None) => (),
-                Err(e) => return Err(e),
+                Err(_e) => {
+                    if cfg!(test) {
+                        panic!("media entity is malformed: {:?}", _e);
+                    }
+                    log::debug!("skipping photoset; error={:?}", _e);
+                    selection.unparseable += 1;
+                }
             }
         }
 
-        Ok(photosets)
+        let mut downloaded_indices = self.select_downloaded_media_indices()?;
+        for photoset in &mut selection.photosets {
+            if let Some(indices) = downloaded_indices.remove(&photoset.id_str) {
+                photoset.downloaded_indices = indices;
+            }
+        }
+
+        Ok(selection)
+    }
+
+    /// Returns the media indices of the photos already downloaded from tweets not fully
+    /// downloaded, by status ID.
+    fn select_downloaded_media_indices(&self) -> Result<HashMap<String, Vec<usize>>> {
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT media_downloads.status_id, media_downloads.media_index
+            FROM media_downloads
+            JOIN tweets ON tweets.status_id = media_downloads.status_id
+            WHERE tweets.photos_downloaded_at IS NULL
+            ORDER BY media_downloads.media_index;
+            "#,
+        )?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        let mut indices: HashMap<String, Vec<usize>> = HashMap::new();
+        for (status_id, index) in rows.flatten() {
+            indices.entry(status_id).or_default().push(index as usize);
+        }
+        Ok(indices)
+    }
+
+    /// Records that the media entity at the 1-based index of the tweet was saved to the path. See
+    /// `Photoset::media_indices`.
+    pub fn set_media_downloaded(
+        &self,
+        status_id: &str,
+        index: usize,
+        path: &Path,
+        sha256: Option<&str>,
+    ) -> Result<usize> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            INSERT OR REPLACE INTO media_downloads (status_id, media_index, downloaded_at, path, sha256)
+            VALUES (?, ?, CURRENT_TIMESTAMP, ?, ?);
+            "#,
+        )?;
+        let n = stmt.execute(params![status_id, index, path.to_string_lossy(), sha256])?;
+        log::trace!(
+            "set media downloaded; status_id={}, index={}",
+            status_id,
+            index
+        );
+        Ok(n)
     }
 
     pub fn select_unseen_status_ids_from(&self, status_ids: &[u64]) -> Result<Vec<u64>> {
@@ -329,45 +1066,401 @@ impl Connection {
         let _handle =
             self.create_autodropping_temp_table("status_ids", "status_id TEXT NOT NULL")?;
 
-        {
-            let mut insert_stmt = self.conn.prepare(
+        // Inserting a chunk per statement is much faster than a row per statement for a paste of
+        // thousands of URLs. Full chunks share a cached statement; the last one gets its own.
+        for chunk in status_ids.chunks(STATUS_IDS_PER_INSERT) {
+            let sql = format!(
+                "INSERT INTO temp.status_ids VALUES {};",
+                vec!["(?)"; chunk.len()].join(", ")
+            );
+            let params =
+                rusqlite::params_from_iter(chunk.iter().map(|status_id| status_id.to_string()));
+            if chunk.len() == STATUS_IDS_PER_INSERT {
+                self.conn.prepare_cached(&sql)?.execute(params)?;
+            } else {
+                // Caching each partial size would evict the statements used repeatedly.
+                self.conn.prepare(&sql)?.execute(params)?;
+            }
+        }
+
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            SELECT status_id FROM temp.status_ids EXCEPT SELECT status_id FROM seen_tweets;
+            "#,
+        )?;
+
+        let rows = stmt.query_map(params![], |row| row.get(0))?.flatten();
+        Ok(rows.flat_map(|s: String| u64::from_str(&s)).collect())
+    }
+
+    pub fn increment_download_failures(&self, rowid: i64) -> Result<usize> {
+        let n = self.conn.execute(
+            r#"
+            UPDATE tweets SET download_failures = download_failures + 1 WHERE rowid = ?;
+            "#,
+            params![rowid],
+        )?;
+        log::trace!("incremented download_failures; rowid={}", rowid);
+        Ok(n)
+    }
+
+    /// Resets the failure counts of tweets excluded from downloads and returns their rowids.
+    pub fn reset_download_failures(&self) -> Result<Vec<i64>> {
+        let rowids: Vec<i64> = {
+            let mut stmt = self.conn.prepare(
                 r#"
-                INSERT OR IGNORE INTO temp.status_ids VALUES (?);
+                SELECT rowid FROM tweets
+                WHERE photos_downloaded_at IS NULL AND download_failures >= ?;
                 "#,
             )?;
-            for status_id in status_ids {
-                insert_stmt.execute([<u64>::to_string(status_id)])?;
+            let rows = stmt.query_map(params![MAX_DOWNLOAD_FAILURES], |row| row.get(0))?;
+            rows.flatten().collect()
+        };
+        self.conn.execute(
+            r#"
+            UPDATE tweets SET download_failures = 0
+            WHERE photos_downloaded_at IS NULL AND download_failures >= ?;
+            "#,
+            params![MAX_DOWNLOAD_FAILURES],
+        )?;
+        log::trace!("reset download_failures; n={}", rowids.len());
+        Ok(rowids)
+    }
+
+    pub fn count_downloaded(&self, rowids: &[i64]) -> Result<usize> {
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT photos_downloaded_at IS NOT NULL FROM tweets WHERE rowid = ?;
+            "#,
+        )?;
+        let mut n = 0;
+        for rowid in rowids {
+            let downloaded: bool = stmt.query_row(params![rowid], |row| row.get(0))?;
+            if downloaded {
+                n += 1;
             }
         }
+        Ok(n)
+    }
+
+    /// Returns the photosets already downloaded and not removed yet that match the filter.
+    pub fn select_downloaded_photos(&self, filter: &MediaFilter) -> Result<Vec<Photoset>> {
+        fn to_json_array<T: ToString>(values: &Option<Vec<T>>) -> Option<String> {
+            values.as_ref().map(|values| {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                serde_json::to_string(&values).expect("strings must be serializable")
+            })
+        }
 
         let mut stmt = self.conn.prepare(
             r#"
-            SELECT status_id FROM temp.status_ids EXCEPT SELECT status_id FROM seen_tweets;
+            SELECT
+                rowid,
+                json_extract(tweet_content(tweets.content), '$.user.screen_name'),
+                json_extract(tweet_content(tweets.content), '$.id_str'),
+                tweet_media(tweet_content(tweets.content)),
+                tweets.lang,
+                tweets.source
+            FROM tweets
+            WHERE tweets.photos_downloaded_at IS NOT NULL
+                AND tweets.media_removed_at IS NULL
+                AND (?1 IS NULL OR tweets.status_id IN (SELECT value FROM json_each(?1)))
+                AND (?2 IS NULL OR lower(json_extract(tweet_content(tweets.content), '$.user.screen_name'))
+                    IN (SELECT lower(value) FROM json_each(?2)))
+                AND (?3 IS NULL OR tweets.photos_downloaded_at < ?3)
+            ORDER BY rowid;
             "#,
         )?;
+        let rows = stmt.query_map(
+            params![
+                to_json_array(&filter.status_ids),
+                to_json_array(&filter.screen_names),
+                filter.downloaded_before,
+            ],
+            |row| {
+                Ok(PhotosetRow {
+                    rowid: row.get_unwrap(0),
+                    screen_name: row.get_unwrap(1),
+                    id_str: row.get_unwrap(2),
+                    media_json: row.get_unwrap(3),
+                    lang: row.get_unwrap(4),
+                    source: row.get_unwrap(5),
+                })
+            },
+        )?;
 
-        let rows = stmt.query_map(params![], |row| row.get(0))?.flatten();
-        Ok(rows.flat_map(|s: String| u64::from_str(&s)).collect())
+        let mut photosets = vec![];
+        for row in rows.flatten() {
+            match build_photoset(row, &self.media) {
+                Ok(Some(photoset)) => photosets.push(photoset),
+                Ok(None) => (),
+                Err(e) => log::debug!("skipping photoset; error={:?}", e),
+            }
+        }
+        Ok(photosets)
     }
 
-    pub fn set_photos_downloaded_at(&self, rowid: i64) -> Result<usize> {
+    pub fn set_media_removed_at(&self, rowid: i64) -> Result<usize> {
         let n = self.conn.execute(
             r#"
             UPDATE tweets
-            SET photos_downloaded_at = CURRENT_TIMESTAMP
+            SET media_removed_at = CURRENT_TIMESTAMP
+            WHERE rowid = ?;
+            "#,
+            params![rowid],
+        )?;
+        // The files recorded for the tweet are gone.
+        self.conn.execute(
+            r#"
+            DELETE FROM media_downloads
+            WHERE status_id = (SELECT status_id FROM tweets WHERE rowid = ?);
+            "#,
+            params![rowid],
+        )?;
+        log::trace!("set media_removed_at; rowid={}", rowid);
+        Ok(n)
+    }
+
+    pub fn set_media_forbidden_at(&self, rowid: i64) -> Result<usize> {
+        let n = self.conn.execute(
+            r#"
+            UPDATE tweets
+            SET media_forbidden_at = CURRENT_TIMESTAMP
             WHERE rowid = ?;
             "#,
             params![rowid],
         )?;
+        log::trace!("set media_forbidden_at; rowid={}", rowid);
+        Ok(n)
+    }
+
+    pub fn set_photos_downloaded_at(&self, rowid: i64) -> Result<usize> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            UPDATE tweets
+            SET photos_downloaded_at = CURRENT_TIMESTAMP
+            WHERE rowid = ?;
+            "#,
+        )?;
+        let n = stmt.execute(params![rowid])?;
         log::trace!("set photo_downloaded_at; rowid={}", rowid);
         Ok(n)
     }
 
+    /// Returns the number of tweets `convert_contents` would convert.
+    pub fn count_convertible_contents(&self) -> Result<usize> {
+        let n: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM tweets WHERE typeof(content) = ?;",
+            params![self.convertible_content_type()],
+            |row| row.get(0),
+        )?;
+        Ok(n as usize)
+    }
+
+    /// Compresses or decompresses the stored content of tweets to match the current setting.
+    pub fn convert_contents(&self) -> Result<usize> {
+        let source_type = self.convertible_content_type();
+        let ids: Vec<i64> = {
+            let mut stmt = self
+                .conn
+                .prepare("SELECT id FROM tweets WHERE typeof(content) = ?;")?;
+            let rows = stmt.query_map(params![source_type], |row| row.get(0))?;
+            rows.flatten().collect()
+        };
+
+        let mut select_stmt = self
+            .conn
+            .prepare("SELECT tweet_content(content) FROM tweets WHERE id = ?;")?;
+        let mut update_stmt = self
+            .conn
+            .prepare("UPDATE tweets SET content = ? WHERE id = ?;")?;
+
+        self.conn.execute("BEGIN IMMEDIATE;", params![])?;
+        for id in &ids {
+            let json: String = select_stmt.query_row(params![id], |row| row.get(0))?;
+            let content = if self.compresses_content {
+                Value::Blob(compress_content(&json)?)
+            } else {
+                Value::Text(json)
+            };
+            update_stmt.execute(params![content, id])?;
+        }
+        self.conn.execute("COMMIT;", params![])?;
+        log::trace!(
+            "converted contents; compressed={}, n={}",
+            self.compresses_content,
+            ids.len()
+        );
+
+        Ok(ids.len())
+    }
+
+    fn convertible_content_type(&self) -> &'static str {
+        if self.compresses_content {
+            "text"
+        } else {
+            "blob"
+        }
+    }
+
+    /// Returns a handle that aborts the statement running on this connection from another thread.
+    pub fn interrupt_handle(&self) -> rusqlite::InterruptHandle {
+        self.conn.get_interrupt_handle()
+    }
+
     pub fn vacuum(&self) -> Result<()> {
         self.conn.execute("VACUUM;", params![])?;
         Ok(())
     }
 
+    /// Runs `PRAGMA integrity_check` and returns the problems found, or an empty vec if there are
+    /// none.
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut messages = vec![];
+        // The check itself can fail if the database is badly corrupted, which is reported as a
+        // problem too.
+        let result = self
+            .conn
+            .prepare("PRAGMA integrity_check;")
+            .and_then(|mut stmt| {
+                for message in stmt.query_map(params![], |row| row.get::<_, String>(0))? {
+                    messages.push(message?);
+                }
+                Ok(())
+            });
+        match result {
+            Ok(()) => {}
+            Err(rusqlite::Error::SqliteFailure(e, msg))
+                if e.code == rusqlite::ErrorCode::DatabaseCorrupt =>
+            {
+                messages.push(msg.unwrap_or_else(|| e.to_string()));
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if messages == ["ok"] {
+            return Ok(vec![]);
+        }
+        // A message may span multiple lines after a header, e.g. `*** in database main ***\nPage
+        // 2: ...`.
+        Ok(messages
+            .iter()
+            .flat_map(|message| message.lines())
+            .filter(|line| !line.starts_with("*** "))
+            .map(String::from)
+            .collect())
+    }
+
+    /// Copies the rows that can still be read into a new database at `path`, one by one so that
+    /// a corrupted row does not stop the rest from being copied. The database at `path` must not
+    /// exist.
+    pub fn salvage_into<P>(&self, path: P) -> Result<SalvageCounts>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        ensure!(!path.exists(), "{:?} already exists", path);
+        let salvage = Connection::open(path)?;
+        salvage.create()?;
+
+        let copy_rows = |table: &str, columns: &[&str]| -> Result<(usize, usize)> {
+            // Rowids may be readable even if some of the rows are not.
+            let rowids: Vec<i64> = match self.conn.prepare(&format!("SELECT rowid FROM {};", table))
+            {
+                Ok(mut stmt) => match stmt.query_map(params![], |row| row.get(0)) {
+                    Ok(rows) => rows.map_while(|row| row.ok()).collect(),
+                    Err(_) => vec![],
+                },
+                Err(_) => vec![],
+            };
+            let mut select = self.conn.prepare(&format!(
+                "SELECT {} FROM {} WHERE rowid = ?;",
+                columns.join(", "),
+                table
+            ))?;
+            let mut insert = salvage.conn.prepare(&format!(
+                "INSERT OR IGNORE INTO {} ({}) VALUES ({});",
+                table,
+                columns.join(", "),
+                vec!["?"; columns.len()].join(", ")
+            ))?;
+            let mut copied = 0;
+            for rowid in &rowids {
+                let values = select.query_row(params![rowid], |row| {
+                    (0..columns.len())
+                        .map(|i| row.get::<_, Value>(i))
+                        .collect::<rusqlite::Result<Vec<_>>>()
+                });
+                match values {
+                    Ok(values) => copied += insert.execute(rusqlite::params_from_iter(values))?,
+                    Err(e) => log::debug!(
+                        "could not read row; table={}, rowid={}, error={:?}",
+                        table,
+                        rowid,
+                        e
+                    ),
+                }
+            }
+            Ok((copied, rowids.len()))
+        };
+
+        salvage.conn.execute("BEGIN IMMEDIATE;", params![])?;
+        let (tweets, total_tweets) = copy_rows(
+            "tweets",
+            &[
+                "id",
+                "status_id",
+                "content",
+                "user_id",
+                "screen_name",
+                "in_timeline",
+                "recorded_at",
+                "photos_downloaded_at",
+                "download_failures",
+                "media_removed_at",
+                "content_format",
+                "media_forbidden_at",
+                "text",
+                "lang",
+                "source",
+                "deleted_at",
+            ],
+        )?;
+        let (pruned_tweets, total_pruned_tweets) = copy_rows(
+            "pruned_tweets",
+            &[
+                "id",
+                "status_id",
+                "user_id",
+                "screen_name",
+                "media",
+                "in_timeline",
+                "recorded_at",
+                "photos_downloaded_at",
+                "pruned_at",
+            ],
+        )?;
+        copy_rows(
+            "media_downloads",
+            &[
+                "status_id",
+                "media_index",
+                "downloaded_at",
+                "path",
+                "sha256",
+            ],
+        )?;
+        // The schema version is set by `create`, so it is left as is.
+        copy_rows("metadata", &["key", "value"])?;
+        salvage.conn.execute("COMMIT;", params![])?;
+
+        Ok(SalvageCounts {
+            tweets,
+            total_tweets,
+            pruned_tweets,
+            total_pruned_tweets,
+        })
+    }
+
     fn create_autodropping_temp_table<'a>(
         &'a self,
         table_name: &str,
@@ -417,12 +1510,183 @@ impl<'a> Drop for TempTableDropHandle<'a> {
     }
 }
 
+/// Returns true if the error is caused by a statement aborted through `interrupt_handle`. The
+/// transaction of the statement has been rolled back.
+pub fn is_interrupted(error: &GenericError) -> bool {
+    matches!(
+        error.downcast_ref::<rusqlite::Error>(),
+        Some(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == rusqlite::ErrorCode::OperationInterrupted
+    )
+}
+
+fn register_functions(conn: &rusqlite::Connection) -> Result<()> {
+    // Returns the content of a tweet as JSON text whether it is compressed or not.
+    conn.create_scalar_function(
+        "tweet_content",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| match ctx.get_raw(0) {
+            ValueRef::Blob(bytes) => decompress_content(bytes)
+                .map(Value::Text)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into())),
+            value => Ok(Value::from(value)),
+        },
+    )?;
+    // Returns the media entities of a tweet as JSON text, or `null` if the tweet has no media.
+    conn.create_scalar_function(
+        "tweet_media",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let content: String = ctx.get(0)?;
+            extract_media_json(&content).map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+        },
+    )?;
+    Ok(())
+}
+
+/// Extracts the media entities from `extended_entities`, falling back to `entities` as some
+/// older tweets carry their media only there.
+fn extract_media_json(content: &str) -> Result<String> {
+    let content: serde_json::Value = serde_json::from_str(content)?;
+    let media = ["/extended_entities/media", "/entities/media"]
+        .iter()
+        .filter_map(|pointer| content.pointer(pointer))
+        .find(|media| !media.is_null());
+    Ok(match media {
+        Some(media) => media.to_string(),
+        None => "null".to_owned(),
+    })
+}
+
+fn compress_content(json: &str) -> Result<Vec<u8>> {
+    Ok(zstd::encode_all(json.as_bytes(), 0)?)
+}
+
+fn decompress_content(bytes: &[u8]) -> Result<String> {
+    Ok(String::from_utf8(zstd::decode_all(bytes)?)?)
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct SalvageCounts {
+    pub tweets: usize,
+    /// Number of tweets found, including ones that could not be copied.
+    pub total_tweets: usize,
+    pub pruned_tweets: usize,
+    /// Number of pruned tweets found, including ones that could not be copied.
+    pub total_pruned_tweets: usize,
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct InsertCounts {
+    /// Number of tweets that were not recorded before.
+    pub inserted: usize,
+    /// Number of already recorded tweets newly marked as in the timeline.
+    pub updated: usize,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct UserLatestStatus {
+    pub user_id: u64,
+    /// The screen name in the latest tweet that has one, or `None` if no tweet has one.
+    pub screen_name: Option<String>,
+    pub status_id: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct PhotosetSelection {
+    pub photosets: Vec<Photoset>,
+    /// Number of tweets skipped because their media could not be parsed.
+    pub unparseable: usize,
+    /// Number of tweets excluded because their photos failed to download too many times.
+    pub excluded_failed: usize,
+    /// Number of tweets excluded because their media was refused with HTTP 403.
+    pub excluded_forbidden: usize,
+    /// Number of retweets excluded by `with_skipped_retweets`.
+    pub excluded_retweets: usize,
+    /// Number of tweets merged into another photoset with the same photos.
+    pub duplicates: usize,
+}
+
+/// Selects downloaded photosets. Unset fields match any photoset.
+#[derive(Debug, Default)]
+pub struct MediaFilter {
+    pub status_ids: Option<Vec<u64>>,
+    /// Screen names, matched case-insensitively.
+    pub screen_names: Option<Vec<String>>,
+    /// Matches photosets downloaded before this date or datetime in UTC, e.g. `2022-01-31`.
+    pub downloaded_before: Option<String>,
+}
+
+/// How a tweet was first recorded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TweetSource {
+    Likes,
+    /// A user timeline.
+    User,
+    /// A URL or status ID.
+    Url,
+}
+
+impl TweetSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TweetSource::Likes => "likes",
+            TweetSource::User => "user",
+            TweetSource::Url => "url",
+        }
+    }
+
+    fn from_str_opt(s: &str) -> Option<Self> {
+        match s {
+            "likes" => Some(TweetSource::Likes),
+            "user" => Some(TweetSource::User),
+            "url" => Some(TweetSource::Url),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Photoset {
     pub rowid: i64,
     pub screen_name: String,
     pub id_str: String,
     pub photo_urls: Vec<String>,
+    /// The longest edge among the photos in pixels, or `None` if any photo lacks dimensions.
+    pub max_long_edge: Option<u64>,
+    /// The language of the tweet, e.g. "en" or "und" (undetermined), if Twitter detected one.
+    pub lang: Option<String>,
+    /// How the tweet was first recorded, or `None` if unknown.
+    pub source: Option<TweetSource>,
+    /// Rows of other tweets with the same photos, marked downloaded together with this one.
+    pub duplicate_rowids: Vec<i64>,
+    /// 1-based indices of the media entities in the tweet that `photo_urls` were taken from. Unlike
+    /// the indices in file names, they don't shift when `download.media-types` changes.
+    pub media_indices: Vec<usize>,
+    /// `media_indices` of the photos saved by an earlier run that failed partway.
+    pub downloaded_indices: Vec<usize>,
+}
+
+impl Photoset {
+    /// Returns the 1-based indices and the URLs of the photos not downloaded yet.
+    pub fn pending_photos(&self) -> impl Iterator<Item = (usize, &str)> + '_ {
+        (1..)
+            .zip(self.photo_urls.iter().zip(&self.media_indices))
+            .filter(|(_, (_, media_index))| !self.downloaded_indices.contains(media_index))
+            .map(|(index, (photo_url, _))| (index, photo_url.as_str()))
+    }
+
+    /// Returns the media index of the photo at the 1-based index in `photo_urls`.
+    pub fn media_index(&self, index: usize) -> usize {
+        self.media_indices[index - 1]
+    }
+
+    /// Returns the rowids of all tweets this photoset was selected from.
+    pub fn rowids(&self) -> impl Iterator<Item = i64> + '_ {
+        std::iter::once(self.rowid).chain(self.duplicate_rowids.iter().copied())
+    }
 }
 
 #[derive(Deserialize)]
@@ -430,26 +1694,118 @@ struct MediaEntity {
     media_url_https: String,
     #[serde(alias = "type")]
     type_: String,
+    original_info: Option<MediaOriginalInfo>,
+    sizes: Option<MediaSizes>,
+    video_info: Option<VideoInfo>,
+}
+
+#[derive(Deserialize)]
+struct VideoInfo {
+    variants: Vec<VideoVariant>,
+}
+
+#[derive(Deserialize)]
+struct VideoVariant {
+    content_type: String,
+    url: String,
+    bitrate: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct MediaOriginalInfo {
+    width: u64,
+    height: u64,
+}
+
+#[derive(Deserialize)]
+struct MediaSizes {
+    large: Option<MediaSize>,
+}
+
+#[derive(Deserialize)]
+struct MediaSize {
+    w: u64,
+    h: u64,
+}
+
+impl MediaEntity {
+    fn is_one_of(&self, media_types: &[MediaType]) -> bool {
+        media_types.iter().any(|t| t.as_str() == self.type_)
+    }
+
+    /// Returns the URL of the file to download: the photo itself, or the MP4 variant of a GIF or
+    /// video chosen by `video_quality`.
+    fn download_url(self, video_quality: VideoQuality) -> Option<String> {
+        if self.type_ == MediaType::Photo.as_str() {
+            return Some(self.media_url_https);
+        }
+        video_quality.select(self.video_info?.variants)
+    }
+
+    /// Returns the length of the longer edge of the original media, or of the large size if the
+    /// original size is unknown.
+    fn long_edge(&self) -> Option<u64> {
+        if let Some(info) = &self.original_info {
+            return Some(info.width.max(info.height));
+        }
+        let large = self.sizes.as_ref()?.large.as_ref()?;
+        Some(large.w.max(large.h))
+    }
+}
+
+/// Columns of a tweet kept in `pruned_tweets` when it is pruned.
+struct PrunableRow {
+    status_id: String,
+    user_id: String,
+    screen_name: String,
+    media: Option<String>,
+    in_timeline: bool,
+    recorded_at: Option<String>,
+    photos_downloaded_at: Option<String>,
 }
 
-fn build_photoset(
+/// Columns of a tweet that a photoset is built from.
+struct PhotosetRow {
     rowid: i64,
     screen_name: String,
     id_str: String,
     media_json: String,
-) -> Result<Option<Photoset>> {
+    lang: Option<String>,
+    source: Option<String>,
+}
+
+/// Which media of a tweet make up its photoset, and which file to download for each of them.
+#[derive(Clone, Debug)]
+struct MediaChoice {
+    media_types: Vec<MediaType>,
+    video_quality: VideoQuality,
+}
+
+fn build_photoset(row: PhotosetRow, choice: &MediaChoice) -> Result<Option<Photoset>> {
+    let PhotosetRow {
+        rowid,
+        screen_name,
+        id_str,
+        media_json,
+        lang,
+        source,
+    } = row;
     match serde_json::from_str::<Option<Vec<MediaEntity>>>(&media_json) {
         Ok(Some(media)) => {
-            let photo_urls: Vec<String> = media
-                .into_iter()
-                .filter_map(|m| {
-                    if m.type_ == "photo" {
-                        Some(m.media_url_https)
-                    } else {
-                        None
-                    }
-                })
+            // Keeps the 1-based index of each entity, which media_downloads is keyed on.
+            let photos: Vec<(usize, MediaEntity)> = (1..)
+                .zip(media)
+                .filter(|(_, m)| m.is_one_of(&choice.media_types))
                 .collect();
+            let max_long_edge = photos
+                .iter()
+                .map(|(_, m)| m.long_edge())
+                .collect::<Option<Vec<u64>>>()
+                .and_then(|edges| edges.into_iter().max());
+            let (media_indices, photo_urls): (Vec<usize>, Vec<String>) = photos
+                .into_iter()
+                .filter_map(|(i, m)| Some((i, m.download_url(choice.video_quality)?)))
+                .unzip();
 
             if photo_urls.is_empty() {
                 Ok(None)
@@ -459,15 +1815,17 @@ fn build_photoset(
                     screen_name,
                     id_str,
                     photo_urls,
+                    max_long_edge,
+                    lang,
+                    source: source.as_deref().and_then(TweetSource::from_str_opt),
+                    duplicate_rowids: vec![],
+                    media_indices,
+                    downloaded_indices: vec![],
                 }))
             }
         }
         Ok(None) => Ok(None),
-        Err(e) => bail!(
-            "Failed to decode media entity (rowid = {}): {:?}",
-            media_json,
-            e
-        ),
+        Err(e) => bail!("Failed to decode media entity (rowid = {}): {:?}", rowid, e),
     }
 }
 
@@ -481,8 +1839,30 @@ mod tests {
         conn
     }
 
-    #[test]
-    fn must_count_tweets() {
+    /// Inserts a tweet by @anon with photos at `urls`.
+    fn insert_photo_tweet(conn: &Connection, status_id: &str, urls: &[&str]) {
+        let media: Vec<_> = urls
+            .iter()
+            .map(|url| serde_json::json!({"type": "photo", "media_url_https": url}))
+            .collect();
+        let content = serde_json::json!({
+            "id_str": status_id,
+            "user": {"id_str": "1", "screen_name": "anon"},
+            "extended_entities": {"media": media},
+        });
+        conn.inner()
+            .execute(
+                r#"
+                INSERT INTO tweets (status_id, content, user_id, screen_name, in_timeline, recorded_at)
+                VALUES (?, ?, '1', 'anon', 0, CURRENT_TIMESTAMP);
+                "#,
+                params![status_id, content.to_string()],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn must_count_tweets() {
         let conn = init_conn();
 
         assert_eq!(conn.count_tweets().unwrap(), 0);
@@ -508,6 +1888,946 @@ mod tests {
         assert_eq!(conn.count_tweets().unwrap(), 3);
     }
 
+    fn tweet(id: u64) -> Tweet {
+        let json = serde_json::json!({
+            "created_at": "Wed Oct 10 20:19:24 +0000 2018",
+            "id": id,
+            "id_str": id.to_string(),
+            "entities": {"hashtags": [], "symbols": [], "urls": [], "user_mentions": []},
+            "favorite_count": 0,
+            "retweet_count": 0,
+            "source": "<a href=\"https://example.com\" rel=\"nofollow\">phog</a>",
+            "full_text": "",
+            "truncated": false,
+        });
+        Tweet {
+            tweet: serde_json::from_value(json.clone()).unwrap(),
+            json: json.to_string(),
+        }
+    }
+
+    #[test]
+    fn must_count_inserted_and_updated_timeline_tweets() {
+        let conn = init_conn();
+
+        assert_eq!(
+            conn.insert_loose_tweets(&[tweet(10), tweet(11)]).unwrap(),
+            2
+        );
+        assert_eq!(
+            conn.insert_timeline_tweets(&[tweet(10), tweet(11), tweet(12)])
+                .unwrap(),
+            InsertCounts {
+                inserted: 1,
+                updated: 2
+            }
+        );
+        assert_eq!(
+            conn.insert_timeline_tweets(&[tweet(10), tweet(11), tweet(12)])
+                .unwrap(),
+            InsertCounts::default()
+        );
+    }
+
+    #[test]
+    fn must_record_small_batches_repeatedly() {
+        let conn = init_conn();
+
+        // Cached statements, including ones on the recreated temp table, must stay usable.
+        for id in 10..15 {
+            assert_eq!(conn.insert_loose_tweets(&[tweet(id)]).unwrap(), 1);
+            assert_eq!(conn.insert_loose_tweets(&[tweet(id)]).unwrap(), 0);
+        }
+        assert_eq!(conn.count_tweets().unwrap(), 5);
+        conn.vacuum().unwrap();
+        assert_eq!(conn.insert_loose_tweets(&[tweet(10)]).unwrap(), 0);
+    }
+
+    #[test]
+    fn must_expose_full_text_as_text_column() {
+        let texts = |conn: &Connection| -> Vec<Option<String>> {
+            let mut stmt = conn
+                .inner()
+                .prepare("SELECT text FROM tweets ORDER BY status_id;")
+                .unwrap();
+            let rows = stmt.query_map(params![], |row| row.get(0)).unwrap();
+            rows.map(|row| row.unwrap()).collect()
+        };
+        let hello = || {
+            let mut tweet = tweet(10);
+            tweet.json = tweet
+                .json
+                .replace(r#""full_text":"""#, r#""full_text":"hello""#);
+            tweet
+        };
+
+        for compresses in [false, true] {
+            let conn = init_conn().with_compressed_content(compresses);
+            conn.insert_loose_tweets(&[hello(), tweet(11)]).unwrap();
+            assert_eq!(
+                texts(&conn),
+                vec![Some("hello".to_owned()), Some("".to_owned())]
+            );
+        }
+
+        // The migration fills in the column from the content, compressed or not.
+        let conn = init_conn().with_compressed_content(true);
+        conn.insert_loose_tweets(&[hello()]).unwrap();
+        conn.inner()
+            .execute_batch(
+                r#"
+                UPDATE metadata SET value = json_quote(5) WHERE key = 'schema_version';
+                ALTER TABLE tweets DROP COLUMN text;
+                ALTER TABLE tweets DROP COLUMN lang;
+                ALTER TABLE tweets DROP COLUMN source;
+                ALTER TABLE tweets DROP COLUMN deleted_at;
+                "#,
+            )
+            .unwrap();
+        conn.migrate().unwrap();
+        assert_eq!(texts(&conn), vec![Some("hello".to_owned())]);
+    }
+
+    #[test]
+    fn must_get_texts() {
+        let conn = init_conn();
+        conn.insert_loose_tweets(&[tweet(10), tweet(11)]).unwrap();
+        conn.inner()
+            .execute(
+                "UPDATE tweets SET content = json_set(content, '$.full_text', 'hello') WHERE status_id = '10';",
+                params![],
+            )
+            .unwrap();
+
+        let texts = conn.get_texts(&[1, 3]).unwrap();
+        assert_eq!(texts.len(), 1);
+        assert_eq!(texts[&1], "hello");
+    }
+
+    #[test]
+    fn must_get_content() {
+        let conn = init_conn().with_compressed_content(true);
+        conn.insert_loose_tweets(&[tweet(10)]).unwrap();
+        conn.inner()
+            .execute_batch(
+                r#"
+                INSERT INTO pruned_tweets (status_id, user_id, screen_name, media, in_timeline, recorded_at, pruned_at)
+                VALUES ('20', '1', 'anon', NULL, 1, '2022-01-01 00:00:00', '2022-01-02 00:00:00');
+                "#,
+            )
+            .unwrap();
+
+        let content = conn.get_content(10).unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&content).unwrap()["id_str"],
+            "10"
+        );
+        assert_eq!(conn.get_content(20).unwrap(), None);
+
+        let pruned = conn.get_pruned_content(20).unwrap().unwrap();
+        let pruned: serde_json::Value = serde_json::from_str(&pruned).unwrap();
+        assert_eq!(pruned["screen_name"], "anon");
+        assert_eq!(pruned["media"], serde_json::Value::Null);
+        assert_eq!(pruned["in_timeline"], true);
+        assert_eq!(conn.get_pruned_content(10).unwrap(), None);
+    }
+
+    #[test]
+    fn must_wait_for_other_connection_to_release_lock() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("db.sqlite3");
+        let conn = Connection::open(&path).unwrap();
+        conn.create().unwrap();
+
+        let other = Connection::open(&path).unwrap();
+        other
+            .inner()
+            .execute("BEGIN IMMEDIATE;", params![])
+            .unwrap();
+
+        let conn = conn.with_busy_timeout(Duration::from_millis(10)).unwrap();
+        assert!(conn.insert_loose_tweets(&[tweet(10)]).is_err());
+
+        let conn = conn.with_busy_timeout(Duration::from_secs(10)).unwrap();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            other.inner().execute("COMMIT;", params![]).unwrap();
+        });
+        assert_eq!(conn.insert_loose_tweets(&[tweet(10)]).unwrap(), 1);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn must_open_readonly() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("db.sqlite3");
+
+        let conn = Connection::open_readonly(&path).unwrap();
+        assert_eq!(conn.count_tweets().unwrap(), 0);
+        assert!(!path.exists());
+
+        let writer = Connection::open(&path).unwrap();
+        writer.create().unwrap();
+        writer.insert_loose_tweets(&[tweet(10)]).unwrap();
+
+        let conn = Connection::open_readonly(&path).unwrap();
+        assert_eq!(conn.count_tweets().unwrap(), 1);
+        // Sees what another connection writes.
+        assert_eq!(writer.insert_loose_tweets(&[tweet(11)]).unwrap(), 1);
+        assert_eq!(conn.count_tweets().unwrap(), 2);
+        assert!(conn.insert_loose_tweets(&[tweet(12)]).is_err());
+
+        writer
+            .inner()
+            .execute_batch(
+                r#"
+                DROP TABLE media_downloads;
+                UPDATE metadata SET value = json_quote(9) WHERE key = 'schema_version';
+                "#,
+            )
+            .unwrap();
+        assert!(Connection::open_readonly(&path).is_err());
+        Connection::migrate_if_needed(&path).unwrap();
+        let conn = Connection::open_readonly(&path).unwrap();
+        assert_eq!(conn.schema_version().unwrap(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn must_select_unseen_status_ids_from_thousands() {
+        let conn = init_conn();
+        conn.insert_loose_tweets(&(1000..1010).map(tweet).collect::<Vec<_>>())
+            .unwrap();
+
+        // Spans several full chunks and a partial one, with duplicates.
+        let status_ids: Vec<u64> = (1..=2345).chain(1..=10).collect();
+        let mut unseen = conn.select_unseen_status_ids_from(&status_ids).unwrap();
+        unseen.sort_unstable();
+
+        let expected: Vec<u64> = (1..=2345).filter(|id| !(1000..1010).contains(id)).collect();
+        assert_eq!(unseen, expected);
+        // The temp table is dropped after use, so calling again gives the same result.
+        assert_eq!(
+            conn.select_unseen_status_ids_from(&status_ids)
+                .unwrap()
+                .len(),
+            expected.len()
+        );
+    }
+
+    #[test]
+    fn must_interrupt_running_statement() {
+        let conn = init_conn();
+        let interrupt_handle = conn.interrupt_handle();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            interrupt_handle.interrupt();
+        });
+        let result: Result<i64> = conn
+            .inner()
+            .query_row(
+                r#"
+                WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 1000000000)
+                SELECT count(*) FROM c;
+                "#,
+                params![],
+                |row| row.get(0),
+            )
+            .map_err(Into::into);
+        interrupter.join().unwrap();
+        assert!(is_interrupted(&result.unwrap_err()));
+        assert!(!is_interrupted(&format_err!("other error")));
+    }
+
+    #[test]
+    fn must_store_lang() {
+        let conn = init_conn();
+        let mut ja_tweet = tweet(11);
+        ja_tweet.tweet.lang = Some("ja".to_owned());
+        conn.insert_loose_tweets(&[tweet(10), ja_tweet]).unwrap();
+        let langs = |conn: &Connection| -> Vec<Option<String>> {
+            let mut stmt = conn
+                .inner()
+                .prepare("SELECT lang FROM tweets ORDER BY status_id;")
+                .unwrap();
+            let rows = stmt.query_map(params![], |row| row.get(0)).unwrap();
+            rows.map(|row| row.unwrap()).collect()
+        };
+        assert_eq!(langs(&conn), vec![None, Some("ja".to_owned())]);
+
+        // The migration fills in the column from the content.
+        conn.inner()
+            .execute_batch(
+                r#"
+                UPDATE tweets SET content = json_set(content, '$.lang', 'en'), lang = NULL;
+                UPDATE metadata SET value = json_quote(6) WHERE key = 'schema_version';
+                ALTER TABLE tweets DROP COLUMN lang;
+                ALTER TABLE tweets DROP COLUMN source;
+                ALTER TABLE tweets DROP COLUMN deleted_at;
+                "#,
+            )
+            .unwrap();
+        conn.migrate().unwrap();
+        assert_eq!(
+            langs(&conn),
+            vec![Some("en".to_owned()), Some("en".to_owned())]
+        );
+    }
+
+    #[test]
+    fn must_salvage_rows_into_new_database() {
+        let temp = tempfile::tempdir().unwrap();
+        let conn = Connection::open(temp.path().join("db.sqlite3")).unwrap();
+        conn.create().unwrap();
+        assert_eq!(conn.integrity_check().unwrap(), Vec::<String>::new());
+
+        conn.insert_loose_tweets(&[tweet(10), tweet(11)]).unwrap();
+        conn.inner()
+            .execute_batch(
+                r#"
+                INSERT INTO pruned_tweets (status_id, user_id, screen_name, in_timeline, recorded_at, pruned_at)
+                VALUES ('20', '1', 'anon', 1, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP);
+                "#,
+            )
+            .unwrap();
+
+        let path = temp.path().join("salvaged.sqlite3");
+        assert_eq!(
+            conn.salvage_into(&path).unwrap(),
+            SalvageCounts {
+                tweets: 2,
+                total_tweets: 2,
+                pruned_tweets: 1,
+                total_pruned_tweets: 1,
+            }
+        );
+        assert!(conn.salvage_into(&path).is_err());
+
+        let salvaged = Connection::open(&path).unwrap();
+        salvaged.create().unwrap();
+        assert_eq!(salvaged.count_tweets().unwrap(), 2);
+        assert!(salvaged.get_content(11).unwrap().is_some());
+        assert!(salvaged.get_pruned_content(20).unwrap().is_some());
+        assert_eq!(salvaged.schema_version().unwrap(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn must_resume_partially_downloaded_photosets() {
+        let conn = init_conn();
+        insert_photo_tweet(
+            &conn,
+            "10",
+            &["https://example.com/a.jpg", "https://example.com/b.jpg"],
+        );
+
+        conn.set_media_downloaded("10", 1, Path::new("/photos/a.jpg"), Some("abc"))
+            .unwrap();
+        let photoset = conn
+            .select_not_downloaded_photos(false)
+            .unwrap()
+            .photosets
+            .remove(0);
+        assert_eq!(photoset.downloaded_indices, vec![1]);
+        assert_eq!(
+            photoset.pending_photos().collect::<Vec<_>>(),
+            vec![(2, "https://example.com/b.jpg")]
+        );
+
+        // Tweets downloaded before the migration get a row per media entity.
+        conn.set_photos_downloaded_at(photoset.rowid).unwrap();
+        conn.inner()
+            .execute_batch(
+                r#"
+                DROP TABLE media_downloads;
+                UPDATE metadata SET value = json_quote(9) WHERE key = 'schema_version';
+                "#,
+            )
+            .unwrap();
+        conn.migrate().unwrap();
+        let indices: Vec<i64> = conn
+            .inner()
+            .prepare("SELECT media_index FROM media_downloads WHERE status_id = '10' ORDER BY 1;")
+            .unwrap()
+            .query_map(params![], |row| row.get(0))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(indices, vec![1, 2]);
+
+        conn.set_media_removed_at(photoset.rowid).unwrap();
+        let rows: i64 = conn
+            .inner()
+            .query_row("SELECT COUNT(*) FROM media_downloads;", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(rows, 0);
+    }
+
+    #[test]
+    fn must_resume_by_media_index_after_media_types_change() {
+        let conn = init_conn().with_media_types(vec![MediaType::Photo]);
+        conn.inner()
+            .execute_batch(
+                r#"
+                INSERT INTO tweets (status_id, content, in_timeline, recorded_at)
+                VALUES (
+                    '10',
+                    json_object(
+                        'id_str', '10',
+                        'user', json_object('id_str', '1', 'screen_name', 'anon'),
+                        'extended_entities', json_object(
+                            'media', json_array(
+                                json_object(
+                                    'type', 'video',
+                                    'media_url_https', 'https://example.com/v.jpg',
+                                    'video_info', json_object('variants', json_array(json_object(
+                                        'content_type', 'video/mp4',
+                                        'url', 'https://example.com/v.mp4',
+                                        'bitrate', 256000
+                                    )))
+                                ),
+                                json_object('type', 'photo', 'media_url_https', 'https://example.com/a.jpg'),
+                                json_object('type', 'photo', 'media_url_https', 'https://example.com/b.jpg')
+                            )
+                        )
+                    ),
+                    0,
+                    CURRENT_TIMESTAMP
+                );
+                "#,
+            )
+            .unwrap();
+
+        let photoset = conn
+            .select_not_downloaded_photos(false)
+            .unwrap()
+            .photosets
+            .remove(0);
+        assert_eq!(photoset.media_indices, vec![2, 3]);
+        conn.set_media_downloaded("10", photoset.media_index(1), Path::new("a.jpg"), None)
+            .unwrap();
+
+        // The video now comes first in the file names, but a.jpg is still the one downloaded.
+        let conn = conn.with_media_types(vec![MediaType::Photo, MediaType::Video]);
+        let photoset = conn
+            .select_not_downloaded_photos(false)
+            .unwrap()
+            .photosets
+            .remove(0);
+        assert_eq!(
+            photoset.pending_photos().collect::<Vec<_>>(),
+            vec![
+                (1, "https://example.com/v.mp4"),
+                (3, "https://example.com/b.jpg")
+            ]
+        );
+    }
+
+    #[test]
+    fn must_exclude_repeatedly_failed_photosets() {
+        let conn = init_conn();
+
+        insert_photo_tweet(&conn, "10", &["https://example.com/a.jpg"]);
+
+        let rowid = conn.select_not_downloaded_photos(false).unwrap().photosets[0].rowid;
+        for _ in 0..MAX_DOWNLOAD_FAILURES {
+            conn.increment_download_failures(rowid).unwrap();
+        }
+
+        let selection = conn.select_not_downloaded_photos(false).unwrap();
+        assert!(selection.photosets.is_empty());
+        assert_eq!(selection.excluded_failed, 1);
+
+        assert_eq!(conn.reset_download_failures().unwrap(), vec![rowid]);
+        let selection = conn.select_not_downloaded_photos(false).unwrap();
+        assert_eq!(selection.photosets.len(), 1);
+        assert_eq!(selection.excluded_failed, 0);
+        assert_eq!(conn.count_downloaded(&[rowid]).unwrap(), 0);
+    }
+
+    #[test]
+    fn must_exclude_forbidden_photosets_if_skipped() {
+        let conn = init_conn();
+
+        insert_photo_tweet(&conn, "10", &["https://example.com/a.jpg"]);
+
+        let rowid = conn.select_not_downloaded_photos(true).unwrap().photosets[0].rowid;
+        conn.set_media_forbidden_at(rowid).unwrap();
+
+        let selection = conn.select_not_downloaded_photos(true).unwrap();
+        assert!(selection.photosets.is_empty());
+        assert_eq!(selection.excluded_forbidden, 1);
+
+        let selection = conn.select_not_downloaded_photos(false).unwrap();
+        assert_eq!(selection.photosets.len(), 1);
+        assert_eq!(selection.excluded_forbidden, 0);
+    }
+
+    #[test]
+    fn must_exclude_retweets_if_skipped() {
+        let conn = init_conn();
+
+        conn.inner()
+            .execute_batch(
+                r#"
+                INSERT INTO tweets (status_id, content, in_timeline, recorded_at) VALUES
+                    ('10', json_object(
+                        'id_str', '10',
+                        'user', json_object('id_str', '1', 'screen_name', 'anon'),
+                        'retweeted_status', json_object('id_str', '9'),
+                        'extended_entities', json_object(
+                            'media', json_array(
+                                json_object('type', 'photo', 'media_url_https', 'https://example.com/a.jpg')
+                            )
+                        )
+                    ), 0, CURRENT_TIMESTAMP),
+                    ('12', json_object(
+                        'id_str', '12',
+                        'user', json_object('id_str', '1', 'screen_name', 'anon'),
+                        'retweeted_status', json_object('id_str', '8')
+                    ), 0, CURRENT_TIMESTAMP);
+                "#,
+            )
+            .unwrap();
+        insert_photo_tweet(&conn, "11", &["https://example.com/b.jpg"]);
+
+        let selection = conn.select_not_downloaded_photos(false).unwrap();
+        assert_eq!(selection.photosets.len(), 2);
+        assert_eq!(selection.excluded_retweets, 0);
+
+        let conn = conn.with_skipped_retweets(true);
+        let selection = conn.select_not_downloaded_photos(false).unwrap();
+        assert_eq!(selection.photosets.len(), 1);
+        assert_eq!(selection.photosets[0].id_str, "11");
+        // Retweets without media to download are not counted.
+        assert_eq!(selection.excluded_retweets, 1);
+    }
+
+    #[test]
+    fn must_select_distinct_user_ids() {
+        let conn = init_conn();
+        conn.inner()
+            .execute_batch(
+                r#"
+                INSERT INTO tweets (status_id, content, in_timeline, recorded_at) VALUES
+                    ('1', json_object('user', json_object('id_str', '20')), 0, CURRENT_TIMESTAMP),
+                    ('2', json_object('user', json_object('id_str', '3')), 1, CURRENT_TIMESTAMP),
+                    ('3', json_object('user', json_object('id_str', '20')), 1, CURRENT_TIMESTAMP),
+                    ('4', json_object(), 0, CURRENT_TIMESTAMP);
+                UPDATE tweets SET
+                    user_id = json_extract(content, '$.user.id_str'),
+                    screen_name = json_extract(content, '$.user.screen_name');
+                "#,
+            )
+            .unwrap();
+
+        assert_eq!(conn.select_distinct_user_ids().unwrap(), vec![3, 20]);
+    }
+
+    #[test]
+    fn must_query_seen_tweets_without_phog_functions() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("db.sqlite3");
+        let conn = Connection::open(&path)
+            .unwrap()
+            .with_compressed_content(true);
+        conn.create().unwrap();
+        let mut tweet = tweet(10);
+        tweet.json = tweet
+            .json
+            .replacen('{', r#"{"user":{"id_str":"1","screen_name":"anon"},"#, 1);
+        conn.insert_loose_tweets(&[tweet]).unwrap();
+
+        // A plain connection has no tweet_content() to decompress the content with.
+        let plain = rusqlite::Connection::open(&path).unwrap();
+        let row: (String, String) = plain
+            .query_row(
+                "SELECT seen_tweets.user_id, screen_name FROM seen_tweets JOIN tweets USING (status_id);",
+                params![],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(row, ("1".to_owned(), "anon".to_owned()));
+    }
+
+    #[test]
+    fn must_select_latest_status_ids() {
+        let conn = init_conn();
+        conn.inner()
+            .execute_batch(
+                r#"
+                INSERT INTO tweets (status_id, content, in_timeline, recorded_at) VALUES
+                    ('10', json_object('user', json_object('id_str', '20', 'screen_name', 'old')), 0, CURRENT_TIMESTAMP),
+                    ('30', json_object('user', json_object('id_str', '20', 'screen_name', 'new')), 1, CURRENT_TIMESTAMP),
+                    ('20', json_object('user', json_object('id_str', '3', 'screen_name', 'c')), 1, CURRENT_TIMESTAMP),
+                    ('40', json_object(), 0, CURRENT_TIMESTAMP);
+                INSERT INTO pruned_tweets (status_id, user_id, screen_name, in_timeline, recorded_at, pruned_at) VALUES
+                    ('25', '3', 'c', 1, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP);
+                UPDATE tweets SET
+                    user_id = json_extract(content, '$.user.id_str'),
+                    screen_name = json_extract(content, '$.user.screen_name');
+                "#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            conn.select_latest_status_ids().unwrap(),
+            vec![
+                UserLatestStatus {
+                    user_id: 3,
+                    screen_name: Some("c".to_owned()),
+                    status_id: 25,
+                },
+                UserLatestStatus {
+                    user_id: 20,
+                    screen_name: Some("new".to_owned()),
+                    status_id: 30,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn must_merge_photosets_with_same_photos() {
+        let conn = init_conn();
+
+        conn.inner()
+            .execute_batch(
+                r#"
+                INSERT INTO tweets (status_id, content, in_timeline, recorded_at)
+                SELECT
+                    column1,
+                    json_object(
+                        'id_str', column1,
+                        'user', json_object('id_str', '1', 'screen_name', column2),
+                        'extended_entities', json_object(
+                            'media', json_array(
+                                json_object('type', 'photo', 'media_url_https', column3)
+                            )
+                        )
+                    ),
+                    0,
+                    CURRENT_TIMESTAMP
+                FROM (VALUES
+                    ('10', 'old_name', 'https://example.com/a.jpg'),
+                    ('11', 'new_name', 'https://example.com/a.jpg'),
+                    ('12', 'new_name', 'https://example.com/b.jpg')
+                );
+                "#,
+            )
+            .unwrap();
+
+        let selection = conn.select_not_downloaded_photos(false).unwrap();
+        assert_eq!(selection.photosets.len(), 2);
+        assert_eq!(selection.duplicates, 1);
+        let merged = &selection.photosets[0];
+        assert_eq!(merged.rowids().count(), 2);
+
+        for rowid in merged.rowids() {
+            conn.set_photos_downloaded_at(rowid).unwrap();
+        }
+        let selection = conn.select_not_downloaded_photos(false).unwrap();
+        assert_eq!(selection.photosets.len(), 1);
+        assert_eq!(selection.duplicates, 0);
+    }
+
+    #[test]
+    fn must_select_downloaded_photos() {
+        let conn = init_conn();
+
+        conn.inner()
+            .execute_batch(
+                r#"
+                INSERT INTO tweets (status_id, content, in_timeline, recorded_at, photos_downloaded_at)
+                SELECT
+                    column1,
+                    json_object(
+                        'id_str', column1,
+                        'user', json_object('id_str', '1', 'screen_name', column2),
+                        'extended_entities', json_object(
+                            'media', json_array(
+                                json_object('type', 'photo', 'media_url_https', 'https://example.com/' || column1 || '.jpg')
+                            )
+                        )
+                    ),
+                    0,
+                    CURRENT_TIMESTAMP,
+                    column3
+                FROM (VALUES
+                    ('10', 'Alice', '2022-01-01 00:00:00'),
+                    ('11', 'bob', '2022-02-01 00:00:00'),
+                    ('12', 'bob', NULL)
+                );
+                "#,
+            )
+            .unwrap();
+
+        let status_ids = |filter: MediaFilter| -> Vec<String> {
+            conn.select_downloaded_photos(&filter)
+                .unwrap()
+                .into_iter()
+                .map(|s| s.id_str)
+                .collect()
+        };
+
+        assert_eq!(status_ids(MediaFilter::default()), vec!["10", "11"]);
+        assert_eq!(
+            status_ids(MediaFilter {
+                status_ids: Some(vec![11, 12]),
+                ..MediaFilter::default()
+            }),
+            vec!["11"]
+        );
+        assert_eq!(
+            status_ids(MediaFilter {
+                screen_names: Some(vec!["alice".to_owned()]),
+                ..MediaFilter::default()
+            }),
+            vec!["10"]
+        );
+        assert_eq!(
+            status_ids(MediaFilter {
+                downloaded_before: Some("2022-01-15".to_owned()),
+                ..MediaFilter::default()
+            }),
+            vec!["10"]
+        );
+
+        let rowid = conn
+            .select_downloaded_photos(&MediaFilter::default())
+            .unwrap()[0]
+            .rowid;
+        conn.set_media_removed_at(rowid).unwrap();
+        assert_eq!(status_ids(MediaFilter::default()), vec!["11"]);
+        // Removed media must not be downloaded again.
+        assert_eq!(
+            conn.select_not_downloaded_photos(false)
+                .unwrap()
+                .photosets
+                .len(),
+            1
+        );
+    }
+
+    fn photoset_row(media_json: &str) -> PhotosetRow {
+        PhotosetRow {
+            rowid: 1,
+            screen_name: "user".to_owned(),
+            id_str: "10".to_owned(),
+            media_json: media_json.to_owned(),
+            lang: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn must_build_photoset_with_dimensions() {
+        let media_json = r#"[
+            {"type": "photo", "media_url_https": "https://example.com/a.jpg",
+             "original_info": {"width": 1200, "height": 1600}},
+            {"type": "photo", "media_url_https": "https://example.com/b.jpg",
+             "sizes": {"large": {"w": 800, "h": 400, "resize": "fit"}}},
+            {"type": "video", "media_url_https": "https://example.com/c.jpg"}
+        ]"#;
+        let choice = MediaChoice {
+            media_types: DEFAULT_MEDIA_TYPES.to_vec(),
+            video_quality: VideoQuality::Highest,
+        };
+        let photoset = build_photoset(photoset_row(media_json), &choice)
+            .unwrap()
+            .unwrap();
+        assert_eq!(photoset.photo_urls.len(), 2);
+        assert_eq!(photoset.max_long_edge, Some(1600));
+
+        let media_json = r#"[{"type": "photo", "media_url_https": "https://example.com/a.jpg"}]"#;
+        let photoset = build_photoset(photoset_row(media_json), &choice)
+            .unwrap()
+            .unwrap();
+        assert_eq!(photoset.max_long_edge, None);
+    }
+
+    #[test]
+    fn must_build_photoset_of_media_types() {
+        let media_json = r#"[
+            {"type": "photo", "media_url_https": "https://example.com/photo.jpg"},
+            {"type": "animated_gif", "media_url_https": "https://example.com/gif_thumb.jpg",
+             "video_info": {"variants": [
+                {"content_type": "video/mp4", "url": "https://example.com/gif.mp4", "bitrate": 0}
+             ]}},
+            {"type": "video", "media_url_https": "https://example.com/video_thumb.jpg",
+             "video_info": {"variants": [
+                {"content_type": "application/x-mpegURL", "url": "https://example.com/video.m3u8"},
+                {"content_type": "video/mp4", "url": "https://example.com/video_low.mp4", "bitrate": 256000},
+                {"content_type": "video/mp4", "url": "https://example.com/video_high.mp4", "bitrate": 2176000}
+             ]}}
+        ]"#;
+        let urls = |media_types: &[MediaType]| -> Vec<String> {
+            let choice = MediaChoice {
+                media_types: media_types.to_vec(),
+                video_quality: VideoQuality::Highest,
+            };
+            build_photoset(photoset_row(media_json), &choice)
+                .unwrap()
+                .map(|s| s.photo_urls)
+                .unwrap_or_default()
+        };
+
+        use MediaType::*;
+        let photo = "https://example.com/photo.jpg";
+        let gif = "https://example.com/gif.mp4";
+        let video = "https://example.com/video_high.mp4";
+        assert_eq!(urls(&[]), Vec::<String>::new());
+        assert_eq!(urls(&[Photo]), vec![photo]);
+        assert_eq!(urls(&[AnimatedGif]), vec![gif]);
+        assert_eq!(urls(&[Video]), vec![video]);
+        assert_eq!(urls(&[Photo, AnimatedGif]), vec![photo, gif]);
+        assert_eq!(urls(&[Photo, Video]), vec![photo, video]);
+        assert_eq!(urls(&[AnimatedGif, Video]), vec![gif, video]);
+        assert_eq!(urls(&[Photo, AnimatedGif, Video]), vec![photo, gif, video]);
+    }
+
+    #[test]
+    fn must_select_video_variant_by_quality() {
+        let variants = || -> Vec<VideoVariant> {
+            serde_json::from_str(
+                r#"[
+                    {"content_type": "application/x-mpegURL", "url": "https://example.com/v.m3u8"},
+                    {"content_type": "video/mp4", "url": "https://example.com/832.mp4", "bitrate": 832000},
+                    {"content_type": "video/mp4", "url": "https://example.com/256.mp4", "bitrate": 256000},
+                    {"content_type": "video/mp4", "url": "https://example.com/2176.mp4", "bitrate": 2176000}
+                ]"#,
+            )
+            .unwrap()
+        };
+        let select = |quality: &str| quality.parse::<VideoQuality>().unwrap().select(variants());
+
+        let url = |kbps: u64| Some(format!("https://example.com/{}.mp4", kbps));
+        assert_eq!(select("highest"), url(2176));
+        assert_eq!(select("lowest"), url(256));
+        assert_eq!(select("<=1000kbps"), url(832));
+        assert_eq!(select("<= 832kbps"), url(832));
+        // Falls back to the lowest if all variants are above the limit.
+        assert_eq!(select("<=100kbps"), url(256));
+        assert_eq!(VideoQuality::Highest.select(vec![]), None);
+
+        assert!("best".parse::<VideoQuality>().is_err());
+        assert!("<=fastkbps".parse::<VideoQuality>().is_err());
+    }
+
+    #[test]
+    fn must_prune_tweets_without_media_to_download() {
+        let conn = init_conn().with_media_types(vec![MediaType::Video]);
+
+        conn.inner()
+            .execute_batch(
+                r#"
+                INSERT INTO tweets (status_id, content, in_timeline, recorded_at)
+                VALUES (
+                    '10',
+                    json_object(
+                        'user', json_object('id_str', '1', 'screen_name', 'anon'),
+                        'extended_entities', json_object(
+                            'media', json_array(
+                                json_object('type', 'photo', 'media_url_https', '')
+                            )
+                        )
+                    ),
+                    0,
+                    CURRENT_TIMESTAMP
+                ), (
+                    '11',
+                    json_object(
+                        'user', json_object('id_str', '1', 'screen_name', 'anon'),
+                        'extended_entities', json_object(
+                            'media', json_array(
+                                json_object('type', 'video', 'media_url_https', '')
+                            )
+                        )
+                    ),
+                    0,
+                    CURRENT_TIMESTAMP
+                );
+                UPDATE tweets SET
+                    user_id = json_extract(content, '$.user.id_str'),
+                    screen_name = json_extract(content, '$.user.screen_name');
+                "#,
+            )
+            .unwrap();
+
+        // Only the tweet with the photo, which is not to be downloaded, is prunable.
+        assert_eq!(conn.prune_tweets().unwrap(), 1);
+        assert_eq!(conn.count_tweets().unwrap(), 1);
+    }
+
+    #[test]
+    fn must_fall_back_to_entities_media() {
+        assert_eq!(
+            extract_media_json(r#"{"entities":{"media":[{"type":"photo"}]}}"#).unwrap(),
+            r#"[{"type":"photo"}]"#
+        );
+        assert_eq!(
+            extract_media_json(
+                r#"{"extended_entities":{"media":[{"type":"video"}]},"entities":{"media":[{"type":"photo"}]}}"#
+            )
+            .unwrap(),
+            r#"[{"type":"video"}]"#
+        );
+        assert_eq!(extract_media_json(r#"{"entities":{}}"#).unwrap(), "null");
+    }
+
+    #[test]
+    fn must_update_tweet_contents() {
+        let conn = init_conn();
+        conn.insert_loose_tweets(&[tweet(10)]).unwrap();
+
+        let mut refreshed = tweet(10);
+        refreshed.json = refreshed
+            .json
+            .replace("\"favorite_count\":0", "\"favorite_count\":5");
+        assert_eq!(
+            conn.update_tweet_contents(&[refreshed, tweet(11)]).unwrap(),
+            1
+        );
+
+        let favorite_count: i64 = conn
+            .inner()
+            .query_row(
+                "SELECT json_extract(tweet_content(content), '$.favorite_count') FROM tweets;",
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(favorite_count, 5);
+    }
+
+    #[test]
+    fn must_keep_tweets_deleted_upstream() {
+        let conn = init_conn();
+        let tweet_with_user = |id| {
+            let mut tweet = tweet(id);
+            let mut json: serde_json::Value = serde_json::from_str(&tweet.json).unwrap();
+            json["user"] = serde_json::json!({"id_str": "1", "screen_name": "anon"});
+            tweet.json = json.to_string();
+            tweet
+        };
+        conn.insert_loose_tweets(&[tweet_with_user(10), tweet_with_user(11)])
+            .unwrap();
+
+        assert_eq!(conn.set_deleted_at(&[10, 12]).unwrap(), 1);
+        assert_eq!(conn.set_deleted_at(&[10]).unwrap(), 0);
+
+        // Tweets without media are prunable, but deleted ones are kept.
+        assert_eq!(conn.prune_tweets().unwrap(), 1);
+        assert_eq!(conn.count_tweets().unwrap(), 1);
+
+        // Refetching the tweet unmarks it.
+        assert_eq!(
+            conn.update_tweet_contents(&[tweet_with_user(10)]).unwrap(),
+            1
+        );
+        assert_eq!(conn.prune_tweets().unwrap(), 1);
+    }
+
     #[test]
     fn must_prune_tweets() {
         let conn = init_conn();
@@ -566,6 +2886,7 @@ mod tests {
                     -- Tweet with photos and other media (photos are not yet downloaded)
                     '20',
                     json_object(
+                        'id_str', '20',
                         'user', json_object('id_str', '1', 'screen_name', 'anon'),
                         'extended_entities', json_object(
                             'media', json_array(
@@ -577,7 +2898,25 @@ mod tests {
                     0,
                     CURRENT_TIMESTAMP,
                     NULL
+                ), (
+                    -- Older tweet with photos only in entities (photos are not yet downloaded)
+                    '21',
+                    json_object(
+                        'id_str', '21',
+                        'user', json_object('id_str', '1', 'screen_name', 'anon'),
+                        'entities', json_object(
+                            'media', json_array(
+                                json_object('type', 'photo', 'media_url_https', 'https://example.com/a.jpg')
+                            )
+                        )
+                    ),
+                    0,
+                    CURRENT_TIMESTAMP,
+                    NULL
                 );
+                UPDATE tweets SET
+                    user_id = json_extract(content, '$.user.id_str'),
+                    screen_name = json_extract(content, '$.user.screen_name');
                 COMMIT;
                 "#,
             )
@@ -594,9 +2933,137 @@ mod tests {
                 .collect()
         }
 
-        assert_eq!(query_status_ids(&conn), vec!["10", "11", "12", "20"]);
+        assert_eq!(query_status_ids(&conn), vec!["10", "11", "12", "20", "21"]);
+        assert_eq!(conn.count_prunable_tweets().unwrap(), 3);
+        assert_eq!(query_status_ids(&conn), vec!["10", "11", "12", "20", "21"]);
         assert_eq!(conn.prune_tweets().unwrap(), 3);
-        assert_eq!(query_status_ids(&conn), vec!["20"]);
+        assert_eq!(query_status_ids(&conn), vec!["20", "21"]);
+
+        let photosets = conn.select_not_downloaded_photos(false).unwrap().photosets;
+        assert_eq!(photosets[1].id_str, "21");
+        assert_eq!(photosets[1].photo_urls, vec!["https://example.com/a.jpg"]);
+    }
+
+    #[test]
+    fn must_convert_contents() {
+        let conn = init_conn().with_compressed_content(true);
+
+        conn.inner()
+            .execute_batch(
+                r#"
+                INSERT INTO tweets (
+                    status_id,
+                    content,
+                    in_timeline,
+                    recorded_at,
+                    photos_downloaded_at
+                )
+                VALUES
+                    ('10', '{"user":{"id_str":"1"}}', 1, CURRENT_TIMESTAMP, NULL),
+                    ('11', '{"user":{"id_str":"1"}}', 1, CURRENT_TIMESTAMP, NULL);
+                UPDATE tweets SET
+                    user_id = json_extract(content, '$.user.id_str'),
+                    screen_name = json_extract(content, '$.user.screen_name');
+                "#,
+            )
+            .unwrap();
+
+        fn content_types(conn: &Connection) -> Vec<String> {
+            let mut stmt = conn
+                .inner()
+                .prepare("SELECT typeof(content) FROM tweets ORDER BY id;")
+                .unwrap();
+            stmt.query_map(params![], |row| row.get(0))
+                .unwrap()
+                .flatten()
+                .collect()
+        }
+
+        assert_eq!(conn.convert_contents().unwrap(), 2);
+        assert_eq!(content_types(&conn), vec!["blob", "blob"]);
+        assert_eq!(conn.convert_contents().unwrap(), 0);
+        assert_eq!(conn.select_max_status_id(1).unwrap(), Some("11".to_owned()));
+
+        let conn = conn.with_compressed_content(false);
+        assert_eq!(conn.convert_contents().unwrap(), 2);
+        assert_eq!(content_types(&conn), vec!["text", "text"]);
+        let content: String = conn
+            .inner()
+            .query_row(
+                "SELECT content FROM tweets WHERE status_id = '10';",
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(content, r#"{"user":{"id_str":"1"}}"#);
+    }
+
+    #[test]
+    fn must_back_up_and_apply_pending_migrations() {
+        let temp = tempfile::tempdir().unwrap();
+        let conn = Connection::open(temp.path().join("db.sqlite3")).unwrap();
+        conn.create().unwrap();
+        conn.insert_loose_tweets(&[tweet(10)]).unwrap();
+        assert_eq!(conn.pending_migrations().unwrap(), vec![]);
+
+        // Rolls the database back to version 7.
+        conn.inner()
+            .execute_batch(
+                r#"
+                UPDATE metadata SET value = json_quote(7) WHERE key = 'schema_version';
+                ALTER TABLE tweets DROP COLUMN source;
+                ALTER TABLE tweets DROP COLUMN deleted_at;
+                "#,
+            )
+            .unwrap();
+        let pending = conn.pending_migrations().unwrap();
+        assert_eq!(
+            pending.iter().map(|m| m.version).collect::<Vec<_>>(),
+            (8..=latest_schema_version()).collect::<Vec<_>>()
+        );
+        assert!(pending[0]
+            .description
+            .starts_with("Stores how each tweet was first recorded"));
+        assert!(pending[0].description.ends_with("only if they are in one."));
+
+        let backup_path = temp.path().join("db.sqlite3.v7");
+        conn.backup_into(&backup_path).unwrap();
+        assert!(conn.backup_into(&backup_path).is_err());
+
+        conn.create().unwrap();
+        assert_eq!(conn.schema_version().unwrap(), latest_schema_version());
+        assert_eq!(conn.pending_migrations().unwrap(), vec![]);
+
+        let backup = Connection::open(&backup_path).unwrap();
+        assert_eq!(backup.schema_version().unwrap(), 7);
+        assert_eq!(backup.count_tweets().unwrap(), 1);
+    }
+
+    #[test]
+    fn must_migrate_from_initial_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.inner().execute_batch(SCHEMA_SQL).unwrap();
+        conn.inner()
+            .execute_batch(
+                r#"
+                INSERT INTO tweets (status_id, content, in_timeline, recorded_at)
+                VALUES ('10', '{"user":{"id_str":"1"}}', 1, CURRENT_TIMESTAMP);
+                "#,
+            )
+            .unwrap();
+
+        conn.create().unwrap();
+
+        assert_eq!(conn.schema_version().unwrap(), MIGRATIONS.len());
+        assert_eq!(conn.count_tweets().unwrap(), 1);
+        let content_format: String = conn
+            .inner()
+            .query_row("SELECT content_format FROM tweets;", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(content_format, ContentFormat::V1_1.as_str());
+        assert_eq!(conn.select_max_status_id(1).unwrap(), Some("10".to_owned()));
     }
 }
 
@@ -1,60 +1,198 @@
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use indicatif::HumanBytes;
 use rusqlite::params;
+use serde_json::{json, Map, Value as JsonValue};
 
 use crate::config;
 use crate::database::Connection;
 
 pub struct DatabaseInfo {
     conn: rusqlite::Connection,
+    login: Option<String>,
+}
+
+struct Entry {
+    key: &'static str,
+    label: &'static str,
+    value: Value,
+}
+
+enum Value {
+    Bytes(u64),
+    Count(u64),
+    Path(PathBuf),
+    Text(String),
+    Error(String),
+    Unknown,
 }
 
 impl DatabaseInfo {
+    /// Adds the verified login to the entries.
+    pub fn with_login(self, login: Option<String>) -> Self {
+        DatabaseInfo { login, ..self }
+    }
+
     pub fn format(&self) -> String {
+        self.entries()
+            .iter()
+            .map(|e| format!("{:14} : {}", e.label, e.value.format()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn to_json(&self) -> String {
+        let map: Map<String, JsonValue> = self
+            .entries()
+            .into_iter()
+            .map(|e| (e.key.to_owned(), e.value.to_json()))
+            .collect();
+        serde_json::to_string_pretty(&map).expect("map must be serializable")
+    }
+
+    pub fn to_tsv(&self) -> String {
+        self.entries()
+            .iter()
+            .map(|e| format!("{}\t{}", e.key, e.value.to_tsv()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn entries(&self) -> Vec<Entry> {
         let path = config::database_path();
 
-        format!(
-            "\
-            DB path        : {path:?}\n\
-            DB size        : {size}\n\
-            Tweets         : {tweets}\n\
-            Pruned tweets  : {pruned_tweets}\
-            ",
-            path = path,
-            size = file_size(&path),
-            tweets = self.tweets(),
-            pruned_tweets = self.pruned_tweets(),
-        )
-    }
-
-    fn tweets(&self) -> String {
-        self.conn
-            .query_row("SELECT COUNT(*) FROM tweets;", params![], |row| {
-                row.get(0).map(|i: i64| i.to_string())
-            })
-            .unwrap_or_else(|e| format!("(Error: {:?})", e))
+        let mut entries = vec![
+            Entry {
+                key: "db_path",
+                label: "DB path",
+                value: Value::Path(path.clone()),
+            },
+            Entry {
+                key: "db_size",
+                label: "DB size",
+                value: file_size(&path),
+            },
+            Entry {
+                key: "tweets",
+                label: "Tweets",
+                value: self.count("SELECT COUNT(*) FROM tweets;"),
+            },
+            Entry {
+                key: "pruned_tweets",
+                label: "Pruned tweets",
+                value: self.count("SELECT COUNT(*) FROM pruned_tweets;"),
+            },
+            Entry {
+                key: "deleted_tweets",
+                label: "Deleted tweets",
+                value: self.count("SELECT COUNT(*) FROM tweets WHERE deleted_at IS NOT NULL;"),
+            },
+        ];
+        if let Some(login) = &self.login {
+            entries.push(Entry {
+                key: "login",
+                label: "Logged in as",
+                value: Value::Text(login.clone()),
+            });
+        }
+        entries
     }
 
-    fn pruned_tweets(&self) -> String {
+    fn count(&self, sql: &str) -> Value {
         self.conn
-            .query_row("SELECT COUNT(*) FROM pruned_tweets;", params![], |row| {
-                row.get(0).map(|i: i64| i.to_string())
-            })
-            .unwrap_or_else(|e| format!("(Error: {:?})", e))
+            .query_row(sql, params![], |row| row.get(0))
+            .map(|i: i64| Value::Count(i as u64))
+            .unwrap_or_else(|e| Value::Error(format!("{:?}", e)))
+    }
+}
+
+impl Value {
+    fn format(&self) -> String {
+        match self {
+            Value::Bytes(n) => HumanBytes(*n).to_string(),
+            Value::Count(n) => n.to_string(),
+            Value::Path(path) => format!("{:?}", path),
+            Value::Text(text) => text.clone(),
+            Value::Error(e) => format!("(Error: {})", e),
+            Value::Unknown => "(Unknown)".to_owned(),
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        match self {
+            Value::Bytes(n) | Value::Count(n) => json!(n),
+            Value::Path(path) => json!(path.to_string_lossy()),
+            Value::Text(text) => json!(text),
+            Value::Error(_) | Value::Unknown => JsonValue::Null,
+        }
+    }
+
+    fn to_tsv(&self) -> String {
+        match self {
+            Value::Bytes(n) | Value::Count(n) => n.to_string(),
+            Value::Path(path) => path.to_string_lossy().into_owned(),
+            Value::Text(text) => text.clone(),
+            Value::Error(_) | Value::Unknown => String::new(),
+        }
     }
 }
 
 impl From<Connection> for DatabaseInfo {
     fn from(conn: Connection) -> Self {
-        DatabaseInfo { conn: conn.into() }
+        DatabaseInfo {
+            conn: conn.into(),
+            login: None,
+        }
     }
 }
 
-fn file_size(path: &Path) -> String {
+fn file_size(path: &Path) -> Value {
     match fs::metadata(path) {
-        Ok(metadata) => HumanBytes(metadata.len()).to_string(),
-        _ => "(Unknown)".to_owned(),
+        Ok(metadata) => Value::Bytes(metadata.len()),
+        _ => Value::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value as JsonValue;
+
+    use super::DatabaseInfo;
+    use crate::database::Connection;
+
+    fn init_info() -> DatabaseInfo {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.create().unwrap();
+        conn.into()
+    }
+
+    #[test]
+    fn formats_render_same_entries() {
+        let info = init_info();
+
+        let plain = info.format();
+        assert!(plain.contains("Tweets         : 0"));
+        assert!(plain.contains("Pruned tweets  : 0"));
+        assert!(plain.contains("Deleted tweets : 0"));
+
+        let tsv = info.to_tsv();
+        assert!(tsv.lines().any(|line| line == "tweets\t0"));
+        assert!(tsv.lines().any(|line| line == "pruned_tweets\t0"));
+
+        let json: JsonValue = serde_json::from_str(&info.to_json()).unwrap();
+        assert_eq!(json["tweets"], 0);
+        assert_eq!(json["pruned_tweets"], 0);
+        assert_eq!(json["deleted_tweets"], 0);
+        assert!(json.get("login").is_none());
+    }
+
+    #[test]
+    fn login_is_shown_if_checked() {
+        let info = init_info().with_login(Some("@phog".to_owned()));
+        assert!(info.format().contains("Logged in as   : @phog"));
+        assert!(info.to_tsv().lines().any(|line| line == "login\t@phog"));
+        let json: JsonValue = serde_json::from_str(&info.to_json()).unwrap();
+        assert_eq!(json["login"], "@phog");
     }
 }
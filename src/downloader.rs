@@ -1,98 +1,342 @@
+use std::collections::{BTreeSet, VecDeque};
 use std::fs::{self, File};
 use std::io::{self, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use curl::easy::{Easy2, Handler, WriteError};
-use curl::multi::Multi;
+use curl::multi::{Easy2Handle, Multi};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use ring::digest;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::database::Photoset;
 use crate::result::*;
+use crate::shutdown::ShutdownFlag;
+use crate::twitter::posted_at;
 
 const MAX_CONCURRENCY: usize = 4;
+pub const DEFAULT_MAX_RETRIES: u32 = 2;
 
 pub type OnDownloadedPhotoset = Box<dyn Fn(&Photoset)>;
+pub type OnDownloadedPhoto = Box<dyn Fn(&Photoset, &DownloadedFile)>;
+
+/// Maps a photo URL of a photoset and its 1-based index to the path to save the photo to,
+/// either relative to the download directory or absolute. `build_photo_path` is the default.
+pub type PhotoPathMapper = Box<dyn Fn(&Photoset, &str, usize) -> PathBuf>;
 
 pub struct Downloader {
+    dir: PathBuf,
+    photo_path: PhotoPathMapper,
     on_downloaded_photoset: OnDownloadedPhotoset,
+    on_downloaded_photo: Option<OnDownloadedPhoto>,
     single_photo_photosets: Vec<Photoset>,
     multi_photo_photosets: Vec<Photoset>,
+    max_rate_bytes: u64,
+    max_file_bytes: u64,
+    max_retries: u32,
+    deadline: Option<Instant>,
+    shutdown: Option<ShutdownFlag>,
+}
+
+#[derive(Debug, Default)]
+pub struct DownloadSummary {
+    pub downloaded: usize,
+    pub failed: usize,
+    pub skipped_by_deadline: usize,
+    pub skipped_by_shutdown: usize,
+    /// Photosets with a file larger than the maximum file size. They are neither downloaded nor
+    /// counted as failed.
+    pub skipped_by_size: usize,
+    pub failures: Vec<TransferFailure>,
+    pub outcomes: Vec<DownloadOutcome>,
+}
+
+impl DownloadSummary {
+    /// Returns the rowids of the photosets that failed to download.
+    pub fn failed_rowids(&self) -> BTreeSet<i64> {
+        self.failures.iter().map(|f| f.rowid).collect()
+    }
+
+    /// Returns the rowids of the photosets whose photos were refused with HTTP 403, which usually
+    /// means they are protected rather than missing.
+    pub fn forbidden_rowids(&self) -> BTreeSet<i64> {
+        self.failures
+            .iter()
+            .filter(|f| f.is_forbidden())
+            .map(|f| f.rowid)
+            .collect()
+    }
+}
+
+/// The result of downloading a photoset, in the order the downloads finished.
+#[derive(Debug, Serialize)]
+pub struct DownloadOutcome {
+    pub status_id: String,
+    pub screen_name: String,
+    pub success: bool,
+    pub files: Vec<DownloadedFile>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DownloadedFile {
+    /// 1-based index of the photo in the photoset.
+    pub index: usize,
+    /// 1-based index of the media entity in the tweet, which differs from `index` if
+    /// download.media-types leaves some media out.
+    pub media_index: usize,
+    pub url: String,
+    pub path: PathBuf,
+    pub bytes: Option<u64>,
+    /// The SHA-256 hash of the file in lowercase hex.
+    pub sha256: Option<String>,
+}
+
+impl DownloadOutcome {
+    fn succeeded(photoset: &Photoset, files: Vec<DownloadedFile>) -> Self {
+        DownloadOutcome {
+            status_id: photoset.id_str.clone(),
+            screen_name: photoset.screen_name.clone(),
+            success: true,
+            files,
+            error: None,
+        }
+    }
+
+    /// `files` are the photos saved before the photoset failed. They are not downloaded again.
+    fn failed(
+        photoset: &Photoset,
+        failures: &[TransferFailure],
+        files: Vec<DownloadedFile>,
+    ) -> Self {
+        let error = failures
+            .iter()
+            .map(|f| format!("{}: {}", f.url, f.reason))
+            .collect::<Vec<_>>()
+            .join("; ");
+        DownloadOutcome {
+            status_id: photoset.id_str.clone(),
+            screen_name: photoset.screen_name.clone(),
+            success: false,
+            files,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TransferFailure {
+    pub rowid: i64,
+    pub url: String,
+    pub path: PathBuf,
+    pub reason: String,
+    /// The HTTP response code received before the transfer failed, or 0 if there was none.
+    pub response_code: u32,
+}
+
+impl TransferFailure {
+    pub fn is_forbidden(&self) -> bool {
+        self.response_code == 403
+    }
 }
 
 impl Downloader {
-    pub fn new(photosets: Vec<Photoset>, on_downloaded_photoset: OnDownloadedPhotoset) -> Self {
-        let (single_photo_photosets, multi_photo_photosets) =
-            photosets.into_iter().partition(|s| s.photo_urls.len() == 1);
+    pub fn new(
+        photosets: Vec<Photoset>,
+        photo_path: PhotoPathMapper,
+        on_downloaded_photoset: OnDownloadedPhotoset,
+    ) -> Self {
+        let (single_photo_photosets, multi_photo_photosets) = photosets
+            .into_iter()
+            .partition(|s| s.pending_photos().count() == 1);
         Downloader {
+            dir: PathBuf::new(),
+            photo_path,
             on_downloaded_photoset,
+            on_downloaded_photo: None,
             single_photo_photosets,
             multi_photo_photosets,
+            max_rate_bytes: 0,
+            max_file_bytes: 0,
+            max_retries: DEFAULT_MAX_RETRIES,
+            deadline: None,
+            shutdown: None,
+        }
+    }
+
+    /// Calls `on_downloaded_photo` for each saved photo, including those of photosets that fail
+    /// partway.
+    pub fn with_on_downloaded_photo(self, on_downloaded_photo: OnDownloadedPhoto) -> Self {
+        Downloader {
+            on_downloaded_photo: Some(on_downloaded_photo),
+            ..self
+        }
+    }
+
+    /// Saves photos under the directory instead of the current working directory.
+    pub fn with_dir(self, dir: PathBuf) -> Self {
+        Downloader { dir, ..self }
+    }
+
+    /// Caps the receive speed of each transfer in bytes per second. 0 means unlimited.
+    pub fn with_max_rate_bytes(self, max_rate_bytes: u64) -> Self {
+        Downloader {
+            max_rate_bytes,
+            ..self
+        }
+    }
+
+    /// Skips photosets with a file larger than this many bytes. 0 means unlimited.
+    pub fn with_max_file_bytes(self, max_file_bytes: u64) -> Self {
+        Downloader {
+            max_file_bytes,
+            ..self
+        }
+    }
+
+    /// Sets how many times a failed transfer is retried before its photoset is given up.
+    pub fn with_max_retries(self, max_retries: u32) -> Self {
+        Downloader {
+            max_retries,
+            ..self
         }
     }
 
-    pub fn start(&self) -> Result<()> {
+    /// Stops starting new transfers once the deadline has passed.
+    pub fn with_deadline(self, deadline: Option<Instant>) -> Self {
+        Downloader { deadline, ..self }
+    }
+
+    /// Stops starting new transfers once SIGINT or SIGTERM arrives. Transfers in progress are
+    /// completed so that no partial files are left behind.
+    pub fn with_shutdown_flag(self, shutdown: ShutdownFlag) -> Self {
+        Downloader {
+            shutdown: Some(shutdown),
+            ..self
+        }
+    }
+
+    pub fn start(&self) -> Result<DownloadSummary> {
+        let mut summary = DownloadSummary::default();
         log::trace!("downloading single-photo photosets");
-        self.download_single_photo_photosets()
+        self.download_single_photo_photosets(&mut summary)
             .context("Error occurred while downloading single-file photosets")?;
         log::trace!("downloading multi-photo photosets");
-        self.download_multi_photo_photosets()
+        self.download_multi_photo_photosets(&mut summary)
             .context("Error occurred while downloading multi-file photosets")?;
-        Ok(())
+        Ok(summary)
     }
 
-    fn download_single_photo_photosets(&self) -> Result<()> {
+    fn download_single_photo_photosets(&self, summary: &mut DownloadSummary) -> Result<()> {
+        type Job<'p> = (&'p Photoset, u32);
+
         fn add_jobs<'p>(
+            downloader: &Downloader,
             multi: &Multi,
-            handles: &mut Vec<(curl::multi::Easy2Handle<FileWriter>, &'p Photoset)>,
-            single_sets_iter: &mut impl Iterator<Item = &'p Photoset>,
-        ) -> Result<bool> {
-            let mut added = false;
-            for _ in 0..MAX_CONCURRENCY.saturating_sub(handles.len()) {
-                if let Some(single_set) = single_sets_iter.next() {
-                    let path = build_photo_path(single_set, &single_set.photo_urls[0], 1);
-                    let mut easy2 = Easy2::new(FileWriter::new(path));
-                    easy2.get(true)?;
-                    easy2.url(&single_set.photo_urls[0])?;
+            handles: &mut Vec<(Easy2Handle<FileWriter>, Job<'p>)>,
+            queue: &mut VecDeque<Job<'p>>,
+        ) -> Result<()> {
+            while handles.len() < MAX_CONCURRENCY && !downloader.stops_starting_transfers() {
+                if let Some((single_set, attempts)) = queue.pop_front() {
+                    let (index, photo_url) = single_photo(single_set);
+                    let path = downloader.dest_path(single_set, photo_url, index);
+                    let easy2 = downloader.new_easy2(path, photo_url)?;
                     let handle = multi.add2(easy2)?;
-                    log::trace!("added download job; url={}", &single_set.photo_urls[0]);
-                    handles.push((handle, single_set));
-                    added = true;
+                    log::trace!(
+                        "added download job; url={}, attempts={}",
+                        photo_url,
+                        attempts
+                    );
+                    handles.push((handle, (single_set, attempts)));
                 } else {
                     break;
                 }
             }
-            Ok(added)
+            Ok(())
         }
 
         let multi = Multi::new();
         let mut handles = vec![];
-        let mut single_sets_iter = self.single_photo_photosets.iter().peekable();
+        let mut queue: VecDeque<Job> = self.single_photo_photosets.iter().map(|s| (s, 0)).collect();
 
         loop {
-            add_jobs(&multi, &mut handles, &mut single_sets_iter)?;
+            add_jobs(self, &multi, &mut handles, &mut queue)?;
             let transfers_in_progress = multi.perform()?;
             multi.messages(|message| {
                 let mut i = 0;
                 while i < handles.len() {
-                    let (handle, photoset) = &mut handles[i];
+                    let (handle, (photoset, attempts)) = &mut handles[i];
                     if let Some(result) = message.result_for2(handle) {
-                        if result.is_ok() {
-                            if let Err(e) = handle.get_mut().finish() {
-                                log::debug!("failed to write output file; error={:?}", e);
-                            } else {
-                                (self.on_downloaded_photoset)(photoset);
-                            }
-                        } else {
+                        let (index, url) = single_photo(photoset);
+                        if let Err(e) = result {
                             log::debug!(
                                 "transfer failed; error={:?}; io_result={:?}",
-                                result.unwrap_err(),
+                                e,
                                 handle.get_ref().io_result,
                             );
+                            let response_code = handle.response_code().unwrap_or(0);
+                            if is_too_large(&e, handle.get_ref()) {
+                                log::debug!("skipped too large file; url={}", url);
+                                summary.skipped_by_size += 1;
+                            } else if *attempts < self.max_retries
+                                && is_retryable(&e, response_code)
+                            {
+                                queue.push_back((photoset, *attempts + 1));
+                            } else {
+                                summary.failed += 1;
+                                summary.failures.push(TransferFailure {
+                                    rowid: photoset.rowid,
+                                    url: url.to_owned(),
+                                    path: self.dest_path(photoset, url, index),
+                                    reason: describe_transfer_error(
+                                        &e,
+                                        response_code,
+                                        &handle.get_ref().io_result,
+                                    ),
+                                    response_code,
+                                });
+                                let failures = &summary.failures[summary.failures.len() - 1..];
+                                summary.outcomes.push(DownloadOutcome::failed(
+                                    photoset,
+                                    failures,
+                                    vec![],
+                                ));
+                            }
+                        } else {
+                            match self.finish_photo(photoset, index, handle.get_mut()) {
+                                Ok(file) => {
+                                    (self.on_downloaded_photoset)(photoset);
+                                    summary.downloaded += 1;
+                                    summary
+                                        .outcomes
+                                        .push(DownloadOutcome::succeeded(photoset, vec![file]));
+                                }
+                                Err(e) => {
+                                    log::debug!("failed to write output file; error={:?}", e);
+                                    summary.failed += 1;
+                                    summary.failures.push(TransferFailure {
+                                        rowid: photoset.rowid,
+                                        url: url.to_owned(),
+                                        path: self.dest_path(photoset, url, index),
+                                        reason: format!("Could not save the file: {}", e),
+                                        response_code: 0,
+                                    });
+                                    let failures = &summary.failures[summary.failures.len() - 1..];
+                                    summary.outcomes.push(DownloadOutcome::failed(
+                                        photoset,
+                                        failures,
+                                        vec![],
+                                    ));
+                                }
+                            }
                         }
                         // Drop handle to close file.
-                        let (handle, _photoset) = handles.remove(i);
+                        let (handle, _job) = handles.remove(i);
                         let _ = multi.remove2(handle);
                         // The elements after i has been shifted. Continue from i.
                         continue;
@@ -100,8 +344,14 @@ impl Downloader {
                     i += 1;
                 }
             });
-            if transfers_in_progress == 0 && single_sets_iter.peek().is_none() {
-                break;
+            if transfers_in_progress == 0 && handles.is_empty() {
+                if queue.is_empty() {
+                    break;
+                }
+                if self.stops_starting_transfers() {
+                    self.count_skipped(summary, queue.len());
+                    break;
+                }
             }
             multi.wait(&mut [], Duration::from_secs(1))?;
         }
@@ -109,66 +359,276 @@ impl Downloader {
         Ok(())
     }
 
-    fn download_multi_photo_photosets(&self) -> Result<()> {
-        'each_multi_set: for multi_set in self.multi_photo_photosets.iter() {
-            let multi = Multi::new();
-            let mut handles = vec![];
-
-            for (index, photo_url) in (1..).zip(multi_set.photo_urls.iter()) {
-                let path = build_photo_path(multi_set, photo_url, index);
-                let mut easy2 = Easy2::new(FileWriter::new(path));
-                easy2.get(true)?;
-                easy2.url(photo_url)?;
-                let handle = multi.add2(easy2)?;
-                log::trace!("added download job; url={}", &photo_url);
-                handles.push(handle);
+    fn download_multi_photo_photosets(&self, summary: &mut DownloadSummary) -> Result<()> {
+        for (i, multi_set) in self.multi_photo_photosets.iter().enumerate() {
+            if self.stops_starting_transfers() {
+                self.count_skipped(summary, self.multi_photo_photosets.len() - i);
+                break;
+            }
+            let failures_before = summary.failures.len();
+            let mut files = vec![];
+            match self.download_multi_photo_photoset(
+                multi_set,
+                &mut summary.failures,
+                &mut files,
+            )? {
+                PhotosetResult::Downloaded => {
+                    (self.on_downloaded_photoset)(multi_set);
+                    summary.downloaded += 1;
+                    summary
+                        .outcomes
+                        .push(DownloadOutcome::succeeded(multi_set, files));
+                }
+                PhotosetResult::Failed => {
+                    summary.failed += 1;
+                    let failures = &summary.failures[failures_before..];
+                    summary
+                        .outcomes
+                        .push(DownloadOutcome::failed(multi_set, failures, files));
+                }
+                PhotosetResult::TooLarge => summary.skipped_by_size += 1,
             }
+        }
 
-            loop {
-                let transfers_in_progress = multi.perform()?;
-                let mut any_transfer_failed = false;
-                multi.messages(|message| {
-                    if let Some(Err(e)) = message.result() {
-                        any_transfer_failed = true;
-                        log::debug!("transfer failed; error={:?}", e);
-                    }
-                });
-                if any_transfer_failed {
-                    for handle in handles.into_iter() {
-                        multi.remove2(handle)?;
+        Ok(())
+    }
+
+    /// Downloads the pending photos of the photoset. Saved photos are added to `files`; if the
+    /// photoset fails, the photos completed so far are still saved.
+    fn download_multi_photo_photoset(
+        &self,
+        multi_set: &Photoset,
+        failures: &mut Vec<TransferFailure>,
+        files: &mut Vec<DownloadedFile>,
+    ) -> Result<PhotosetResult> {
+        let multi = Multi::new();
+        let mut handles = vec![];
+        // Indices of the photos whose transfers have completed.
+        let mut completed = BTreeSet::new();
+
+        for (index, photo_url) in multi_set.pending_photos() {
+            let path = self.dest_path(multi_set, photo_url, index);
+            let easy2 = self.new_easy2(path, photo_url)?;
+            let handle = multi.add2(easy2)?;
+            log::trace!("added download job; url={}", &photo_url);
+            handles.push((handle, (index, 0)));
+        }
+
+        loop {
+            let transfers_in_progress = multi.perform()?;
+            let mut failed_transfers = vec![];
+            multi.messages(|message| {
+                for (i, (handle, (index, _attempts))) in handles.iter().enumerate() {
+                    match message.result_for2(handle) {
+                        Some(Err(e)) => {
+                            log::debug!("transfer failed; error={:?}", e);
+                            failed_transfers.push((i, e));
+                        }
+                        Some(Ok(())) => {
+                            completed.insert(*index);
+                        }
+                        None => (),
                     }
-                    continue 'each_multi_set;
                 }
-                if transfers_in_progress == 0 {
-                    break;
+            });
+            let mut any_transfer_given_up = false;
+            let mut any_transfer_too_large = false;
+            let mut any_transfer_retried = false;
+            // Remove from the back so that the remaining positions stay valid.
+            for (i, e) in failed_transfers.into_iter().rev() {
+                let (mut handle, (index, attempts)) = handles.remove(i);
+                let response_code = handle.response_code().unwrap_or(0);
+                let photo_url = &multi_set.photo_urls[index - 1];
+                let path = self.dest_path(multi_set, photo_url, index);
+                if is_too_large(&e, handle.get_ref()) {
+                    log::debug!("skipped too large file; url={}", photo_url);
+                    multi.remove2(handle)?;
+                    any_transfer_too_large = true;
+                } else if attempts < self.max_retries
+                    && is_retryable(&e, response_code)
+                    && !self.stops_starting_transfers()
+                {
+                    multi.remove2(handle)?;
+                    let handle = multi.add2(self.new_easy2(path, photo_url)?)?;
+                    log::trace!(
+                        "added download job; url={}, attempts={}",
+                        &photo_url,
+                        attempts + 1
+                    );
+                    handles.push((handle, (index, attempts + 1)));
+                    any_transfer_retried = true;
+                } else {
+                    failures.push(TransferFailure {
+                        rowid: multi_set.rowid,
+                        url: photo_url.clone(),
+                        path,
+                        reason: describe_transfer_error(
+                            &e,
+                            response_code,
+                            &handle.get_ref().io_result,
+                        ),
+                        response_code,
+                    });
+                    multi.remove2(handle)?;
+                    any_transfer_given_up = true;
                 }
-                multi.wait(&mut [], Duration::from_secs(1))?;
             }
+            if any_transfer_given_up || any_transfer_too_large {
+                for (mut handle, (index, _attempts)) in handles.into_iter() {
+                    // Keeps the completed photos of a failed photoset so that only the rest are
+                    // retried. Those of a photoset too large are discarded as it is never saved.
+                    if any_transfer_given_up && completed.contains(&index) {
+                        match self.finish_photo(multi_set, index, handle.get_mut()) {
+                            Ok(file) => files.push(file),
+                            Err(e) => log::debug!("failed to write output file; error={:?}", e),
+                        }
+                    }
+                    multi.remove2(handle)?;
+                }
+                // A failure is reported even if another file of the photoset is too large.
+                return Ok(if any_transfer_given_up {
+                    PhotosetResult::Failed
+                } else {
+                    PhotosetResult::TooLarge
+                });
+            }
+            if transfers_in_progress == 0 && !any_transfer_retried {
+                break;
+            }
+            multi.wait(&mut [], Duration::from_secs(1))?;
+        }
 
-            let mut all_finish_succeeds = true;
-            for mut handle in handles.into_iter() {
-                if let Err(e) = handle.get_mut().finish() {
+        let mut all_finish_succeeds = true;
+        for (mut handle, (index, _attempts)) in handles.into_iter() {
+            match self.finish_photo(multi_set, index, handle.get_mut()) {
+                Ok(file) => files.push(file),
+                Err(e) => {
                     all_finish_succeeds = false;
                     log::debug!("failed to write output file; error={:?}", e);
-                };
-                multi.remove2(handle)?;
-            }
-            if all_finish_succeeds {
-                (self.on_downloaded_photoset)(multi_set);
+                    let photo_url = &multi_set.photo_urls[index - 1];
+                    failures.push(TransferFailure {
+                        rowid: multi_set.rowid,
+                        url: photo_url.clone(),
+                        path: self.dest_path(multi_set, photo_url, index),
+                        reason: format!("Could not save the file: {}", e),
+                        response_code: 0,
+                    });
+                }
             }
+            multi.remove2(handle)?;
         }
+        Ok(if all_finish_succeeds {
+            PhotosetResult::Downloaded
+        } else {
+            PhotosetResult::Failed
+        })
+    }
 
-        Ok(())
+    /// Moves the completed photo into place and reports it to `on_downloaded_photo`.
+    fn finish_photo(
+        &self,
+        photoset: &Photoset,
+        index: usize,
+        writer: &mut FileWriter,
+    ) -> io::Result<DownloadedFile> {
+        writer.finish()?;
+        let url = photoset.photo_urls[index - 1].clone();
+        let path = self.dest_path(photoset, &url, index);
+        let bytes = fs::metadata(&path).map(|m| m.len()).ok();
+        let file = DownloadedFile {
+            index,
+            media_index: photoset.media_index(index),
+            url,
+            path,
+            bytes,
+            sha256: writer.sha256.clone(),
+        };
+        if let Some(on_downloaded_photo) = &self.on_downloaded_photo {
+            on_downloaded_photo(photoset, &file);
+        }
+        Ok(file)
+    }
+
+    fn dest_path(&self, photoset: &Photoset, photo_url: &str, index: usize) -> PathBuf {
+        self.dir.join((self.photo_path)(photoset, photo_url, index))
+    }
+
+    fn is_past_deadline(&self) -> bool {
+        self.deadline
+            .map(|deadline| Instant::now() >= deadline)
+            .unwrap_or(false)
+    }
+
+    fn is_shutdown_requested(&self) -> bool {
+        self.shutdown
+            .as_ref()
+            .map(|shutdown| shutdown.is_requested())
+            .unwrap_or(false)
+    }
+
+    fn stops_starting_transfers(&self) -> bool {
+        self.is_shutdown_requested() || self.is_past_deadline()
+    }
+
+    fn count_skipped(&self, summary: &mut DownloadSummary, n: usize) {
+        if self.is_shutdown_requested() {
+            summary.skipped_by_shutdown += n;
+        } else {
+            summary.skipped_by_deadline += n;
+        }
+    }
+
+    fn new_easy2(&self, path: PathBuf, url: &str) -> Result<Easy2<FileWriter>> {
+        let mut easy2 = Easy2::new(FileWriter::new(path).with_max_bytes(self.max_file_bytes));
+        easy2.get(true)?;
+        easy2.url(url)?;
+        // Treat HTTP errors as transfer failures instead of saving error pages.
+        easy2.fail_on_error(true)?;
+        if self.max_rate_bytes > 0 {
+            easy2.max_recv_speed(self.max_rate_bytes)?;
+        }
+        if self.max_file_bytes > 0 {
+            // Aborts before receiving the body if Content-Length is known. Otherwise FileWriter
+            // aborts once the cap is exceeded.
+            easy2.max_filesize(self.max_file_bytes)?;
+        }
+        Ok(easy2)
     }
 }
 
+/// Returns the only pending photo of a single-photo photoset.
+fn single_photo(photoset: &Photoset) -> (usize, &str) {
+    photoset
+        .pending_photos()
+        .next()
+        .expect("single-photo photoset must have a pending photo")
+}
+
+enum PhotosetResult {
+    Downloaded,
+    Failed,
+    TooLarge,
+}
+
 struct FileWriter {
     file: FileWriterFile,
     io_result: io::Result<()>,
+    /// 0 means unlimited.
+    max_bytes: u64,
+    written_bytes: u64,
+    exceeded_max_bytes: bool,
+    digest: digest::Context,
+    /// The hash of the written data in lowercase hex, set once the file is finished.
+    sha256: Option<String>,
 }
 
 impl Handler for FileWriter {
     fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        if self.max_bytes > 0 && self.written_bytes + data.len() as u64 > self.max_bytes {
+            log::debug!("file exceeds max bytes; max_bytes={}", self.max_bytes);
+            self.exceeded_max_bytes = true;
+            return Ok(0);
+        }
         match self.write_to_file(data) {
             Some(n) => Ok(n),
             None => {
@@ -185,15 +645,29 @@ impl FileWriter {
         FileWriter {
             file: FileWriterFile::Unopened { dest_path: path },
             io_result: Ok(()),
+            max_bytes: 0,
+            written_bytes: 0,
+            exceeded_max_bytes: false,
+            digest: digest::Context::new(&digest::SHA256),
+            sha256: None,
         }
     }
 
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
     pub fn write_to_file(&mut self, data: &[u8]) -> Option<usize> {
         if self.io_result.is_err() {
             return None;
         }
         match self.file().and_then(|f| f.write(data)) {
-            Ok(n) => Some(n),
+            Ok(n) => {
+                self.written_bytes += n as u64;
+                self.digest.update(&data[..n]);
+                Some(n)
+            }
             Err(e) => {
                 self.io_result = io::Result::Err(e);
                 None
@@ -203,10 +677,7 @@ impl FileWriter {
 
     pub fn finish(&mut self) -> io::Result<()> {
         if self.io_result.is_err() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Attempted to finish incomplete file",
-            ));
+            return Err(io::Error::other("Attempted to finish incomplete file"));
         }
 
         let mut file = FileWriterFile::Closed;
@@ -219,6 +690,8 @@ impl FileWriter {
         {
             drop(part_file);
             fs::rename(part_path, dest_path)?;
+            let hash = self.digest.clone().finish();
+            self.sha256 = Some(hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect());
         }
         Ok(())
     }
@@ -243,6 +716,9 @@ impl FileWriter {
         use FileWriterFile::*;
 
         if let Unopened { dest_path } = &self.file {
+            if let Some(dir) = dest_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(dir)?;
+            }
             let part_path = make_part_path(dest_path)?;
             let part_file = File::create(&part_path)?;
             self.file = Opened {
@@ -253,10 +729,7 @@ impl FileWriter {
         }
 
         match self.file {
-            Closed => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Attempted to use closed file",
-            )),
+            Closed => Err(io::Error::other("Attempted to use closed file")),
             Opened {
                 ref mut part_file, ..
             } => Ok(part_file),
@@ -287,7 +760,7 @@ pub fn build_photo_path(photoset: &Photoset, photo_url: &str, index: usize) -> P
     let url = Url::parse(photo_url).expect("photo_url must be valid");
     let name = url
         .path_segments()
-        .and_then(|segs| segs.last())
+        .and_then(|mut segs| segs.next_back())
         .expect("photo_url must have filename");
     PathBuf::from(format!(
         "@{}-{}-img{}-{}",
@@ -295,20 +768,242 @@ pub fn build_photo_path(photoset: &Photoset, photo_url: &str, index: usize) -> P
     ))
 }
 
+/// Returns true if the file name looks like one made by `build_photo_path`.
+pub fn is_photo_file_name(name: &str) -> bool {
+    static RE: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"^@\w+-\d+-img\d+-[^/\\]+$").expect("regex must compile"));
+    RE.is_match(name)
+}
+
+/// Subdirectories to save photos in, set by `download.date-layout`, e.g. `{user}/{year}/{month}`.
+/// Tokens are `{user}`, `{year}`, `{month}` and `{day}`; dates are when the tweet was posted, in
+/// UTC.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub struct DirLayout {
+    template: String,
+}
+
+const DATE_TOKENS: &[&str] = &["{year}", "{month}", "{day}"];
+
+impl DirLayout {
+    /// Tweets whose date cannot be told from their status ID are saved in this directory.
+    pub const UNKNOWN_DATE_DIR: &'static str = "unknown-date";
+
+    /// Returns the directory relative to the download directory.
+    pub fn dir_for(&self, photoset: &Photoset) -> PathBuf {
+        let posted_at = photoset.id_str.parse().ok().and_then(posted_at);
+        let Some(posted_at) = posted_at else {
+            // Keeps the segments before the first date token.
+            let mut dir: PathBuf = self
+                .template
+                .split('/')
+                .take_while(|segment| !DATE_TOKENS.iter().any(|t| segment.contains(t)))
+                .map(|segment| segment.replace("{user}", &photoset.screen_name))
+                .collect();
+            dir.push(Self::UNKNOWN_DATE_DIR);
+            return dir;
+        };
+        let dir = self
+            .template
+            .replace("{user}", &photoset.screen_name)
+            .replace("{year}", &posted_at.format("%Y").to_string())
+            .replace("{month}", &posted_at.format("%m").to_string())
+            .replace("{day}", &posted_at.format("%d").to_string());
+        PathBuf::from(dir)
+    }
+}
+
+impl FromStr for DirLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        static TOKEN_RE: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"\{[^{}]*\}").expect("regex must compile"));
+
+        for token in TOKEN_RE.find_iter(s) {
+            if token.as_str() != "{user}" && !DATE_TOKENS.contains(&token.as_str()) {
+                return Err(format!(
+                    "Unknown token {} in {:?} (expected {{user}}, {{year}}, {{month}} or {{day}})",
+                    token.as_str(),
+                    s
+                ));
+            }
+        }
+        if TOKEN_RE.replace_all(s, "").contains(['{', '}']) {
+            return Err(format!("Unmatched brace in {:?}", s));
+        }
+        if s.starts_with('/')
+            || s.split('/')
+                .any(|segment| segment.is_empty() || segment == "..")
+        {
+            return Err(format!(
+                "{:?} must be relative segments separated by / without empty ones or ..",
+                s
+            ));
+        }
+        Ok(DirLayout {
+            template: s.to_owned(),
+        })
+    }
+}
+
+impl TryFrom<String> for DirLayout {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn describe_transfer_error(
+    error: &curl::Error,
+    response_code: u32,
+    io_result: &io::Result<()>,
+) -> String {
+    if error.is_http_returned_error() {
+        let reason = hyper::StatusCode::from_u16(response_code as u16)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or("Unknown status");
+        let hint = match response_code {
+            403 => " (the media may be protected)",
+            404 | 410 => " (the media may have been deleted)",
+            429 => " (try again later or lower the download rate)",
+            500..=599 => " (the server is having trouble; try again later)",
+            _ => "",
+        };
+        format!("HTTP {} {}{}", response_code, reason, hint)
+    } else if error.is_couldnt_resolve_host() {
+        "Could not resolve the host (check your network or DNS settings)".to_owned()
+    } else if error.is_couldnt_connect() {
+        "Could not connect to the server".to_owned()
+    } else if error.is_operation_timedout() {
+        "Connection timed out".to_owned()
+    } else if error.is_ssl_connect_error()
+        || error.is_peer_failed_verification()
+        || error.is_ssl_certproblem()
+        || error.is_ssl_cacert()
+    {
+        format!("TLS error: {}", error.description())
+    } else if error.is_partial_file() || error.is_recv_error() || error.is_got_nothing() {
+        "Connection was interrupted".to_owned()
+    } else if error.is_write_error() {
+        match io_result {
+            Err(e) => format!("Could not write the file: {}", e),
+            Ok(()) => "Could not write the file".to_owned(),
+        }
+    } else {
+        error.description().to_owned()
+    }
+}
+
+/// Returns true if the transfer was aborted because the file exceeded the maximum file size,
+/// either by Content-Length or while streaming.
+fn is_too_large(error: &curl::Error, writer: &FileWriter) -> bool {
+    error.is_filesize_exceeded() || (error.is_write_error() && writer.exceeded_max_bytes)
+}
+
+fn is_retryable(error: &curl::Error, response_code: u32) -> bool {
+    if error.is_http_returned_error() {
+        // Client errors other than timeouts and rate limiting won't go away by retrying.
+        !(400..500).contains(&response_code) || response_code == 408 || response_code == 429
+    } else {
+        !error.is_write_error()
+    }
+}
+
 fn make_part_path(path: &Path) -> io::Result<PathBuf> {
     let mut file_name = path
         .file_name()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Destination path lacks file name"))?
+        .ok_or_else(|| io::Error::other("Destination path lacks file name"))?
         .to_owned();
     file_name.push(".part");
     Ok(path.with_file_name(file_name))
 }
 
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use std::fs;
+    use std::path::Path;
+
+    use url::Url;
+
+    use crate::database::Photoset;
+
+    /// Returns a photoset of @user with status ID `rowid * 10`. Use the struct update syntax to
+    /// change other fields.
+    pub fn photoset(rowid: i64, photo_urls: Vec<String>) -> Photoset {
+        Photoset {
+            rowid,
+            screen_name: "user".to_owned(),
+            id_str: (rowid * 10).to_string(),
+            media_indices: (1..=photo_urls.len()).collect(),
+            photo_urls,
+            max_long_edge: None,
+            lang: None,
+            source: None,
+            duplicate_rowids: vec![],
+            downloaded_indices: vec![],
+        }
+    }
+
+    /// Returns a file URL to a new file whose content is its name.
+    pub fn file_url(dir: &Path, name: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, name).unwrap();
+        Url::from_file_path(path).unwrap().to_string()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
 
-    use super::make_part_path;
+    use super::fixtures::photoset;
+    use super::{is_photo_file_name, make_part_path, DirLayout};
+    use crate::database::Photoset;
+
+    #[test]
+    fn dir_layout() {
+        let photoset = |id_str: &str| Photoset {
+            id_str: id_str.to_owned(),
+            ..photoset(1, vec![])
+        };
+        let dir = |template: &str, id_str: &str| {
+            template
+                .parse::<DirLayout>()
+                .unwrap()
+                .dir_for(&photoset(id_str))
+        };
+
+        // Posted at 2018-10-10 20:19:24 UTC.
+        let id = "1050118621198921728";
+        assert_eq!(dir("{year}/{month}", id), PathBuf::from("2018/10"));
+        assert_eq!(dir("{user}/{year}", id), PathBuf::from("user/2018"));
+        assert_eq!(dir("{year}-{month}-{day}", id), PathBuf::from("2018-10-10"));
+        assert_eq!(
+            dir("{user}/{year}", "20"),
+            PathBuf::from("user/unknown-date")
+        );
+        assert_eq!(dir("{year}/{user}", "x"), PathBuf::from("unknown-date"));
+
+        assert!("{year}/{hour}".parse::<DirLayout>().is_err());
+        assert!("{year".parse::<DirLayout>().is_err());
+        assert!("/{year}".parse::<DirLayout>().is_err());
+        assert!("../{year}".parse::<DirLayout>().is_err());
+        assert!("{year}//{month}".parse::<DirLayout>().is_err());
+    }
+
+    #[test]
+    fn photo_file_name() {
+        assert!(is_photo_file_name(
+            "@user_1-0123456789012345678-img2-XXXXXXXXXXXXXXX.jpg"
+        ));
+        assert!(!is_photo_file_name("user-0123-img1-a.jpg"));
+        assert!(!is_photo_file_name("@user-0123-img1-"));
+        assert!(!is_photo_file_name("notes.txt"));
+    }
 
     #[test]
     fn part_path() {
@@ -335,6 +1030,272 @@ mod tests {
     }
 }
 
+#[cfg(test)]
+mod outcome_tests {
+    use std::path::PathBuf;
+
+    use super::fixtures::photoset;
+    use super::{DownloadOutcome, TransferFailure};
+
+    #[test]
+    fn failed_outcome_serializes_reasons() {
+        let photoset = photoset(1, vec!["https://example.com/a.jpg".to_owned()]);
+        let failures = [TransferFailure {
+            rowid: 1,
+            url: "https://example.com/a.jpg".to_owned(),
+            path: PathBuf::from("@user-10-img1-a.jpg"),
+            reason: "HTTP 404 Not Found".to_owned(),
+            response_code: 404,
+        }];
+        let json =
+            serde_json::to_value(DownloadOutcome::failed(&photoset, &failures, vec![])).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "status_id": "10",
+                "screen_name": "user",
+                "success": false,
+                "files": [],
+                "error": "https://example.com/a.jpg: HTTP 404 Not Found",
+            })
+        );
+    }
+}
+
+#[cfg(test)]
+mod photo_path_tests {
+    use std::cell::{Cell, RefCell};
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use tempfile::tempdir;
+    use url::Url;
+
+    use super::fixtures::{file_url, photoset};
+    use super::{build_photo_path, Downloader};
+    use crate::database::Photoset;
+
+    #[test]
+    fn custom_mapper_writes_into_nested_dirs() {
+        let src = tempdir().unwrap();
+        let dest = tempdir().unwrap();
+        let photo_url = |name: &str| file_url(src.path(), name);
+        let photosets = vec![
+            Photoset {
+                screen_name: "user1".to_owned(),
+                ..photoset(1, vec![photo_url("a.jpg")])
+            },
+            Photoset {
+                screen_name: "user2".to_owned(),
+                ..photoset(2, vec![photo_url("b.jpg"), photo_url("c.jpg")])
+            },
+        ];
+
+        let downloaded = Rc::new(Cell::new(0));
+        let summary = Downloader::new(
+            photosets,
+            Box::new(|photoset, _photo_url, index| {
+                Path::new(&photoset.screen_name)
+                    .join(&photoset.id_str)
+                    .join(format!("{}.jpg", index))
+            }),
+            Box::new({
+                let downloaded = downloaded.clone();
+                move |_photoset| downloaded.set(downloaded.get() + 1)
+            }),
+        )
+        .with_dir(dest.path().to_owned())
+        .start()
+        .unwrap();
+
+        assert_eq!(summary.downloaded, 2);
+        assert_eq!(downloaded.get(), 2);
+        let read = |path: &str| fs::read_to_string(dest.path().join(path)).unwrap();
+        assert_eq!(read("user1/10/1.jpg"), "a.jpg");
+        assert_eq!(read("user2/20/1.jpg"), "b.jpg");
+        assert_eq!(read("user2/20/2.jpg"), "c.jpg");
+        assert_eq!(
+            summary.outcomes[0].files[0].path,
+            dest.path().join("user1/10/1.jpg")
+        );
+    }
+
+    #[test]
+    fn keeps_photos_saved_before_photoset_fails() {
+        let src = tempdir().unwrap();
+        let dest = tempdir().unwrap();
+        let missing_url = Url::from_file_path(src.path().join("missing.jpg"))
+            .unwrap()
+            .to_string();
+        let photo_urls = vec![file_url(src.path(), "a.jpg"), missing_url];
+        let photoset = |downloaded_indices| Photoset {
+            downloaded_indices,
+            ..photoset(1, photo_urls.clone())
+        };
+        let mapper = |photoset: &Photoset, _photo_url: &str, index: usize| {
+            Path::new(&photoset.id_str).join(format!("{}.jpg", index))
+        };
+
+        let saved = Rc::new(RefCell::new(vec![]));
+        let summary = Downloader::new(vec![photoset(vec![])], Box::new(mapper), Box::new(|_| {}))
+            .with_on_downloaded_photo(Box::new({
+                let saved = saved.clone();
+                move |_photoset, file| saved.borrow_mut().push(file.index)
+            }))
+            .with_dir(dest.path().to_owned())
+            .with_max_retries(0)
+            .start()
+            .unwrap();
+
+        assert_eq!(summary.failed, 1);
+        assert_eq!(*saved.borrow(), vec![1]);
+        let files = &summary.outcomes[0].files;
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            files[0].sha256.as_deref(),
+            Some("509b0d4641a7c3ba088ffa28559d1f57207ea980447bc1773b1d406d788386ee")
+        );
+        assert_eq!(
+            fs::read_to_string(dest.path().join("10/1.jpg")).unwrap(),
+            "a.jpg"
+        );
+
+        // The next run downloads only the missing photo.
+        file_url(src.path(), "missing.jpg");
+        fs::remove_file(dest.path().join("10/1.jpg")).unwrap();
+        let summary = Downloader::new(vec![photoset(vec![1])], Box::new(mapper), Box::new(|_| {}))
+            .with_dir(dest.path().to_owned())
+            .start()
+            .unwrap();
+        assert_eq!(summary.downloaded, 1);
+        assert!(!dest.path().join("10/1.jpg").exists());
+        assert_eq!(
+            fs::read_to_string(dest.path().join("10/2.jpg")).unwrap(),
+            "missing.jpg"
+        );
+    }
+
+    #[test]
+    fn downloads_into_dir_without_changing_cwd() {
+        let src = tempdir().unwrap();
+        let dest = tempdir().unwrap();
+        let cwd = env::current_dir().unwrap();
+        let photoset = photoset(1, vec![file_url(src.path(), "a.jpg")]);
+        let expected_path =
+            dest.path()
+                .join(build_photo_path(&photoset, &photoset.photo_urls[0], 1));
+
+        let summary = Downloader::new(vec![photoset], Box::new(build_photo_path), Box::new(|_| {}))
+            .with_dir(dest.path().to_owned())
+            .start()
+            .unwrap();
+
+        assert_eq!(summary.downloaded, 1);
+        assert_eq!(fs::read_to_string(&expected_path).unwrap(), "a.jpg");
+        assert_eq!(summary.outcomes[0].files[0].path, expected_path);
+        assert_eq!(env::current_dir().unwrap(), cwd);
+    }
+}
+
+#[cfg(test)]
+mod file_size_tests {
+    use std::fs;
+
+    use curl::easy::Handler;
+    use tempfile::tempdir;
+
+    use super::fixtures::{file_url, photoset};
+    use super::{build_photo_path, Downloader, FileWriter};
+
+    #[test]
+    fn skips_files_larger_than_content_length_cap() {
+        let src = tempdir().unwrap();
+        let dest = tempdir().unwrap();
+        // Files contain their names, so "a.jpg" has 5 bytes and "large.jpg" has 9.
+        let small = file_url(src.path(), "a.jpg");
+        let large = file_url(src.path(), "large.jpg");
+        let photosets = vec![
+            photoset(1, vec![small.clone()]),
+            photoset(2, vec![large.clone()]),
+            photoset(3, vec![small, large]),
+        ];
+
+        let summary = Downloader::new(photosets, Box::new(build_photo_path), Box::new(|_| {}))
+            .with_dir(dest.path().to_owned())
+            .with_max_file_bytes(5)
+            .start()
+            .unwrap();
+
+        assert_eq!(summary.downloaded, 1);
+        assert_eq!(summary.failed, 0);
+        assert_eq!(summary.skipped_by_size, 2);
+        assert!(summary.failures.is_empty());
+        // Only the photoset with the small file is saved, and no part files are left.
+        assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn aborts_streaming_write_over_cap() {
+        let dest = tempdir().unwrap();
+        let path = dest.path().join("a.jpg");
+        let mut writer = FileWriter::new(path.clone()).with_max_bytes(4);
+
+        assert_eq!(writer.write(b"abc").unwrap(), 3);
+        assert!(!writer.exceeded_max_bytes);
+        // Returning a different size than given makes curl abort with a write error.
+        assert_ne!(writer.write(b"de").unwrap(), 2);
+        assert!(writer.exceeded_max_bytes);
+
+        drop(writer);
+        assert!(!path.exists());
+        assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 0);
+    }
+}
+
+#[cfg(test)]
+mod transfer_error_tests {
+    use super::{describe_transfer_error, is_retryable};
+
+    // Error codes from curl.h.
+    const CURLE_COULDNT_RESOLVE_HOST: u32 = 6;
+    const CURLE_HTTP_RETURNED_ERROR: u32 = 22;
+    const CURLE_OPERATION_TIMEDOUT: u32 = 28;
+    const CURLE_SSL_CONNECT_ERROR: u32 = 35;
+
+    fn describe(code: u32, response_code: u32) -> String {
+        describe_transfer_error(&curl::Error::new(code), response_code, &Ok(()))
+    }
+
+    #[test]
+    fn describe_errors() {
+        assert_eq!(
+            describe(CURLE_HTTP_RETURNED_ERROR, 404),
+            "HTTP 404 Not Found (the media may have been deleted)"
+        );
+        assert_eq!(
+            describe(CURLE_HTTP_RETURNED_ERROR, 403),
+            "HTTP 403 Forbidden (the media may be protected)"
+        );
+        assert!(describe(CURLE_COULDNT_RESOLVE_HOST, 0).starts_with("Could not resolve the host"));
+        assert_eq!(
+            describe(CURLE_OPERATION_TIMEDOUT, 0),
+            "Connection timed out"
+        );
+        assert!(describe(CURLE_SSL_CONNECT_ERROR, 0).starts_with("TLS error: "));
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let http_error = curl::Error::new(CURLE_HTTP_RETURNED_ERROR);
+        assert!(!is_retryable(&http_error, 404));
+        assert!(is_retryable(&http_error, 429));
+        assert!(is_retryable(&http_error, 503));
+        assert!(is_retryable(&curl::Error::new(CURLE_OPERATION_TIMEDOUT), 0));
+    }
+}
+
 #[cfg(test)]
 mod file_writer_tests {
     use std::fs;
@@ -192,7 +192,7 @@ async fn request_with_json_response(request: Request<Body>) -> Result<Response<V
     let json_values: Vec<serde_json::Value> = serde_json::from_slice(&body)?;
     let response = tweets
         .into_iter()
-        .zip(json_values.into_iter())
+        .zip(json_values)
         .map(|(tweet, json_value)| Tweet {
             tweet,
             json: serde_json::to_string(&json_value).expect("json_value must be serializable"),
@@ -0,0 +1,110 @@
+//! Exit statuses of phog, documented in README.md.
+
+use crate::result::*;
+
+pub const SUCCESS: i32 = 0;
+pub const FAILURE: i32 = 1;
+pub const CONFIG_ERROR: i32 = 2;
+pub const NETWORK_ERROR: i32 = 3;
+pub const PARTIAL_FAILURE: i32 = 4;
+pub const DEADLINE_EXCEEDED: i32 = 5;
+
+/// Returns the exit status for the error, looking for the first categorizable cause.
+pub fn from_error(error: &GenericError) -> i32 {
+    // Finds the kind attached with `wrap_err` at any depth.
+    if let Some(kind) = error.downcast_ref::<ErrorKind>() {
+        return from_error_kind(*kind);
+    }
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<egg_mode::error::Error>() {
+            return from_egg_mode_error(e);
+        }
+        if cause.is::<curl::Error>() || cause.is::<hyper::Error>() {
+            return NETWORK_ERROR;
+        }
+    }
+    FAILURE
+}
+
+fn from_error_kind(kind: ErrorKind) -> i32 {
+    match kind {
+        ErrorKind::Config => CONFIG_ERROR,
+        ErrorKind::Network => NETWORK_ERROR,
+        ErrorKind::PartialFailure => PARTIAL_FAILURE,
+        ErrorKind::Deadline => DEADLINE_EXCEEDED,
+    }
+}
+
+fn from_egg_mode_error(error: &egg_mode::error::Error) -> i32 {
+    use egg_mode::error::Error as E;
+
+    // https://developer.twitter.com/en/support/twitter-api/error-troubleshooting
+    const AUTH_ERROR_CODES: &[i32] = &[32, 89, 215];
+
+    match error {
+        E::BadStatus(status) if status == &hyper::StatusCode::UNAUTHORIZED => CONFIG_ERROR,
+        E::TwitterError(_, errors)
+            if errors
+                .errors
+                .iter()
+                .any(|e| AUTH_ERROR_CODES.contains(&e.code)) =>
+        {
+            CONFIG_ERROR
+        }
+        E::TwitterError(..) | E::RateLimit(_) | E::BadStatus(_) | E::NetError(_) => NETWORK_ERROR,
+        _ if crate::twitter::is_timeout(error) => NETWORK_ERROR,
+        _ => FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn categorize_errors() {
+        assert_eq!(from_error(&format_err!("unknown")), FAILURE);
+        assert_eq!(
+            from_error(&format_err!("no login").wrap_err(ErrorKind::Config)),
+            CONFIG_ERROR
+        );
+        assert_eq!(
+            from_error(
+                &GenericError::new(egg_mode::error::Error::RateLimit(0)).wrap_err("fetching")
+            ),
+            NETWORK_ERROR
+        );
+        assert_eq!(
+            from_error(&GenericError::new(egg_mode::error::Error::BadStatus(
+                hyper::StatusCode::UNAUTHORIZED
+            ))),
+            CONFIG_ERROR
+        );
+        assert_eq!(
+            from_error(&GenericError::new(egg_mode::error::Error::IOError(
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out")
+            ))),
+            NETWORK_ERROR
+        );
+        assert_eq!(
+            from_error(&GenericError::new(curl::Error::new(6))),
+            NETWORK_ERROR
+        );
+        assert_eq!(
+            from_error(&format_err!("1 failed").wrap_err(ErrorKind::PartialFailure)),
+            PARTIAL_FAILURE
+        );
+        assert_eq!(
+            from_error(&format_err!("stopped").wrap_err(ErrorKind::Deadline)),
+            DEADLINE_EXCEEDED
+        );
+        assert_eq!(
+            from_error(
+                &format_err!("no login")
+                    .wrap_err(ErrorKind::Config)
+                    .wrap_err("while recording")
+            ),
+            CONFIG_ERROR
+        );
+    }
+}
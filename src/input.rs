@@ -18,10 +18,7 @@ pub fn read_to_string(buf: &mut String) -> io::Result<usize> {
             buf.push_str(data.as_ref());
             Ok(data.len())
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Stub stdin data is not set",
-            ))
+            Err(io::Error::other("Stub stdin data is not set"))
         }
     }
     #[cfg(not(test))]
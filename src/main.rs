@@ -9,16 +9,26 @@ mod database;
 mod database_info;
 mod downloader;
 mod egg_mode_ext;
+mod exit_status;
 mod input;
 mod recording;
 mod result;
 mod rt;
+mod shutdown;
 mod spinner;
 mod twitter;
 
-fn main() -> result::Result<()> {
-    color_eyre::install()?;
-    pretty_env_logger::init_timed();
-    config::init()?;
-    cli::run()
+fn main() {
+    let result = color_eyre::install()
+        .and_then(|_| {
+            pretty_env_logger::init_timed();
+            config::init()
+        })
+        .and_then(|_| cli::run());
+    if let Err(e) = result {
+        // Same as returning the error from main, but with a categorized exit status.
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit_status::from_error(&e));
+    }
+    std::process::exit(exit_status::SUCCESS);
 }
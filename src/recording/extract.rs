@@ -6,19 +6,31 @@ use crate::result::*;
 
 pub struct Extract<'a> {
     pub db: &'a Connection,
+    refetch: bool,
 }
 
 impl<'a> Extract<'a> {
     pub fn new(db: &'a Connection) -> Self {
-        Self { db }
+        Self { db, refetch: false }
     }
 
-    pub fn from_clipboard_watcher(&self) -> Result<()> {
+    /// Fetches already recorded tweets again to refresh their content.
+    pub fn with_refetch(self, refetch: bool) -> Self {
+        Self { refetch, ..self }
+    }
+
+    /// Records tweets from the clipboard whenever it changes. If `once` is true, stops after
+    /// recording at least one tweet.
+    pub fn from_clipboard_watcher(&self, once: bool) -> Result<()> {
         println!("Watching the clipboard for tweet URLs... (Ctrl-C to stop)");
         let changes_rx = clipboard::spawn_watcher();
         loop {
             if let Some(text) = changes_rx.recv().expect("recv must succeed") {
-                record::with_string(self.db, text)?;
+                let n = record::with_string(self.db, text, self.refetch)?;
+                if once && n > 0 {
+                    log::trace!("stopping watching clipboard; recorded={}", n);
+                    break;
+                }
             } else {
                 println!("Stopped.");
                 break;
@@ -29,7 +41,19 @@ impl<'a> Extract<'a> {
 
     pub fn from_clipboard(&self) -> Result<()> {
         log::trace!("extracting from clipboard");
-        record::with_string(self.db, clipboard::read()?)
+        // Some backends fail on an empty clipboard; treat it as having no URLs.
+        let text = clipboard::read().unwrap_or_else(|e| {
+            eprintln!("Warning: {}", e);
+            String::new()
+        });
+        record::with_string(self.db, text, self.refetch)?;
+        Ok(())
+    }
+
+    pub fn from_status_ids(&self, status_ids: &[u64]) -> Result<()> {
+        log::trace!("extracting from status ids; n={}", status_ids.len());
+        record::with_status_ids(self.db, status_ids, self.refetch)?;
+        Ok(())
     }
 
     pub fn from_stdin(&self) -> Result<()> {
@@ -38,7 +62,8 @@ impl<'a> Extract<'a> {
             Ok(())
         } else {
             log::trace!("extracting from stdin; stdin=!tty");
-            record::with_string(self.db, read_from_stdin()?)
+            record::with_string(self.db, read_from_stdin()?, self.refetch)?;
+            Ok(())
         }
     }
 }
@@ -1,26 +1,60 @@
-use crate::common::{count, print_rate_limit};
+use std::fmt;
+
+use chrono::{TimeZone, Utc};
+use egg_mode::user::UserID;
+use egg_mode::RateLimit;
+use indicatif::ProgressBar;
+
+use crate::common::{count, is_past_deadline, print_rate_limit};
 use crate::database::Connection;
 use crate::egg_mode_ext::Tweet;
 use crate::result::*;
-use crate::rt::block_on;
 use crate::spinner::new_spinner;
-use crate::twitter::{extract_screen_names, Client};
+use crate::twitter::{extract_screen_names, status_id_to_datetime, Client, Endpoint};
 
 pub const MAX_DEPTH: usize = 20;
 
 pub struct Fetch<'a> {
     db: &'a Connection,
     client: Client,
+    quiet: bool,
+    limit: Option<usize>,
 }
 
 impl<'a> Fetch<'a> {
     pub fn new(db: &'a Connection, client: Client) -> Self {
-        Self { db, client }
+        Self {
+            db,
+            client,
+            quiet: false,
+            limit: None,
+        }
+    }
+
+    /// Stops printing progress lines to stderr after each page of a timeline.
+    pub fn with_quiet(self, quiet: bool) -> Self {
+        Self { quiet, ..self }
+    }
+
+    /// Stops fetching each user timeline once this many tweets are fetched.
+    pub fn with_limit(self, limit: Option<usize>) -> Self {
+        Self { limit, ..self }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
     }
 
-    pub fn from_likes(&self, screen_name_like: Vec<String>) -> Result<()> {
+    /// Returns the number of newly recorded tweets.
+    pub fn from_likes(&self, screen_name_like: Vec<String>) -> Result<usize> {
+        let mut recorded = 0;
         let screen_names = extract_screen_names(&screen_name_like);
-        for screen_name in screen_names {
+        for (i, screen_name) in screen_names.iter().enumerate() {
+            if is_past_deadline() {
+                print_skipped_by_deadline(screen_names.len() - i);
+                break;
+            }
+            bail_if_exhausted(&self.client, Endpoint::Likes)?;
             let spinner = new_spinner(format!("Fetching likes from {}", &screen_name));
             let result = self.client.fetch_likes(screen_name.clone());
             spinner.finish_and_clear();
@@ -28,7 +62,7 @@ impl<'a> Fetch<'a> {
             let response = match result {
                 Ok(response) => response,
                 Err(e) => {
-                    print_non_fatal_error_or_bail(e, &screen_name)?;
+                    print_non_fatal_error_or_bail(e, &format!("screen_name=@{}", screen_name))?;
                     continue;
                 }
             };
@@ -42,12 +76,13 @@ impl<'a> Fetch<'a> {
                 &screen_name,
             );
 
-            let n = self.db.insert_loose_tweets(&tweets)?;
+            let n = self.db.insert_liked_tweets(&tweets)?;
+            recorded += n;
 
             println!("Recorded {}.", count(n, "tweet"));
         }
 
-        Ok(())
+        Ok(recorded)
     }
 
     pub fn from_user(
@@ -55,48 +90,93 @@ impl<'a> Fetch<'a> {
         screen_name_like: Vec<String>,
         uses_since_id: bool,
         depth: usize,
-    ) -> Result<()> {
-        let screen_names = extract_screen_names(&screen_name_like);
-        'each_user: for screen_name in screen_names.iter() {
-            log::trace!("starting fetching timeline; user={}", screen_name);
+    ) -> Result<usize> {
+        let users = extract_screen_names(&screen_name_like)
+            .into_iter()
+            .map(TimelineUser::ScreenName);
+        self.from_timelines(users, uses_since_id, depth)
+    }
+
+    /// Fetches tweets from the users already recorded in the database by their numeric IDs, so
+    /// that renamed users are still found.
+    pub fn from_user_ids_in_db(&self, uses_since_id: bool, depth: usize) -> Result<usize> {
+        let user_ids = self.db.select_distinct_user_ids()?;
+        println!("Found {} in the database.", count(user_ids.len(), "user"));
+        let users = user_ids.into_iter().map(TimelineUser::Id);
+        self.from_timelines(users, uses_since_id, depth)
+    }
+
+    fn from_timelines(
+        &self,
+        mut users: impl Iterator<Item = TimelineUser>,
+        uses_since_id: bool,
+        depth: usize,
+    ) -> Result<usize> {
+        let mut recorded = 0;
+        'each_user: while let Some(user) = users.next() {
+            if is_past_deadline() {
+                print_skipped_by_deadline(1 + users.count());
+                break;
+            }
+            log::trace!("starting fetching timeline; user={}", user);
+            bail_if_exhausted(&self.client, Endpoint::UserTimeline)?;
 
-            let spinner = new_spinner(format!("Fetching tweets from {}", &screen_name));
+            let spinner = new_spinner(format!("Fetching tweets from {}", &user));
 
             let timeline = self
                 .client
-                .user_timeline(screen_name.clone())
-                .with_page_size(200);
-            let result = block_on(timeline.start());
+                .user_timeline(user.to_user_id())
+                .with_page_size(self.limit.map_or(200, |limit| limit.min(200) as i32));
+            let result = self
+                .client
+                .block_on_endpoint(Endpoint::UserTimeline, timeline.start());
 
             let (mut timeline, response) = match result {
                 Ok(timeline_and_response) => timeline_and_response,
                 Err(e) => {
                     spinner.finish_and_clear();
-                    print_non_fatal_error_or_bail(e.into(), screen_name)?;
+                    print_non_fatal_error_or_bail(e.into(), &user.param())?;
                     continue 'each_user;
                 }
             };
 
             print_rate_limit(&response.rate_limit_status);
+            self.client
+                .update_rate_limit(Endpoint::UserTimeline, response.rate_limit_status);
             let mut tweets = response.response;
 
             log::trace!(
                 "fetched timeline; user={}, page=1, tweets_in_page={}",
-                screen_name,
+                user,
                 tweets.len()
             );
+            self.print_progress(
+                &spinner,
+                &user,
+                1,
+                tweets.len(),
+                &response.rate_limit_status,
+            );
 
             let since_id = if uses_since_id {
-                find_since_id(&*tweets, self.db)
+                find_since_id(&tweets, self.db)
             } else {
                 None
             };
 
+            let mut pages = 1;
+            let mut stop_reason = StopReason::Depth;
+
             // Label on block is experimental. Use one-time loop instead.
             #[allow(clippy::single_element_loop)]
             'fetch_more: for _once in &[1usize] {
+                if self.truncate_to_limit(&mut tweets) {
+                    stop_reason = StopReason::Limit;
+                    break 'fetch_more;
+                }
                 if let Some(since_id) = since_id {
                     if tweets.iter().all(|tweet| tweet.id <= since_id) {
+                        stop_reason = StopReason::SinceId;
                         break 'fetch_more;
                     }
                 }
@@ -104,37 +184,54 @@ impl<'a> Fetch<'a> {
                 let mut reached_max_depth = false;
 
                 for page in 2..=depth {
+                    if is_past_deadline() {
+                        stop_reason = StopReason::Deadline;
+                        break 'fetch_more;
+                    }
                     log::trace!(
                         "fetching timeline; user={}, page={}, since_id={:?}",
-                        screen_name,
+                        user,
                         page,
                         since_id
                     );
-                    let result = block_on(timeline.older(since_id));
+                    let result = self
+                        .client
+                        .block_on_endpoint(Endpoint::UserTimeline, timeline.older(since_id));
                     let (timeline2, response) = match result {
                         Ok(timeline_and_response) => timeline_and_response,
                         Err(e) => {
                             spinner.finish_and_clear();
-                            print_non_fatal_error_or_bail(e.into(), screen_name)?;
+                            print_non_fatal_error_or_bail(e.into(), &user.param())?;
                             continue 'each_user;
                         }
                     };
                     print_rate_limit(&response.rate_limit_status);
+                    self.client
+                        .update_rate_limit(Endpoint::UserTimeline, response.rate_limit_status);
+                    pages = page;
                     timeline = timeline2;
                     let older_tweets = response.response;
                     let older_tweets_len = older_tweets.len();
                     tweets.extend(older_tweets);
+                    self.print_progress(
+                        &spinner,
+                        &user,
+                        page,
+                        tweets.len(),
+                        &response.rate_limit_status,
+                    );
 
                     if response.rate_limit_status.remaining == 0 && older_tweets_len != 0 {
-                        bail!(
+                        return Err(format_err!(
                             "Rate limit exceeded while fetching tweets from {}",
-                            screen_name
-                        );
+                            user
+                        ))
+                        .wrap_err(ErrorKind::Network);
                     }
 
                     log::trace!(
                     "fetched timeline; user={}, page={}, since_id={:?}, tweets_in_page={}, total_tweets_fetched={}",
-                    screen_name,
+                    user,
                     page,
                     since_id,
                     older_tweets_len,
@@ -142,6 +239,15 @@ impl<'a> Fetch<'a> {
                 );
 
                     if older_tweets_len == 0 {
+                        stop_reason = if since_id.is_some() {
+                            StopReason::SinceId
+                        } else {
+                            StopReason::EmptyPage
+                        };
+                        break 'fetch_more;
+                    }
+                    if self.truncate_to_limit(&mut tweets) {
+                        stop_reason = StopReason::Limit;
                         break 'fetch_more;
                     }
 
@@ -149,6 +255,7 @@ impl<'a> Fetch<'a> {
                 }
 
                 if reached_max_depth {
+                    stop_reason = StopReason::MaxDepth;
                     // GET statuses/user_timeline should have returned up to 3200 tweets, but it returned more.
                     // https://developer.twitter.com/en/docs/tweets/timelines/api-reference/get-statuses-user_timeline
                     eprintln!(
@@ -159,48 +266,156 @@ impl<'a> Fetch<'a> {
 
             spinner.finish_and_clear();
 
-            let min_id_message = if let Some(since_id) = since_id {
-                format!(", using since_id={}", since_id)
-            } else {
-                String::new()
+            // Tells why later runs fetch fewer tweets than the first one.
+            let min_id_message = match since_id {
+                Some(since_id) => format!(
+                    " newer than the last recorded one (posted at {})",
+                    status_id_to_datetime(since_id).format("%Y-%m-%d %H:%M UTC")
+                ),
+                None if !uses_since_id => {
+                    " (fetching full history as --all or --depth is given)".to_owned()
+                }
+                None => String::new(),
             };
+            log::trace!("fetched timeline; user={}, since_id={:?}", user, since_id);
 
             println!(
                 "Fetched {} from {}{}.",
                 count(tweets.len(), "tweet"),
-                &screen_name,
+                &user,
                 min_id_message
             );
+            println!(
+                "Stopped after {}: {}.",
+                count(pages, "page"),
+                stop_reason.describe()
+            );
 
-            let n = self.db.insert_timeline_tweets(&tweets)?;
+            let counts = self.db.insert_timeline_tweets(&tweets)?;
+            recorded += counts.inserted;
 
-            println!("Recorded {}.", count(n, "tweet"));
+            if counts.updated > 0 {
+                println!(
+                    "Recorded {}, updated {}.",
+                    count(counts.inserted, "new tweet"),
+                    counts.updated
+                );
+            } else {
+                println!("Recorded {}.", count(counts.inserted, "tweet"));
+            }
+        }
+
+        Ok(recorded)
+    }
+
+    /// Drops the oldest tweets beyond the limit. Returns true if the limit is reached.
+    fn truncate_to_limit(&self, tweets: &mut Vec<Tweet>) -> bool {
+        match self.limit {
+            Some(limit) if tweets.len() >= limit => {
+                tweets.truncate(limit);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Prints a progress line that stays in the terminal and logs, unlike the spinner.
+    fn print_progress(
+        &self,
+        spinner: &ProgressBar,
+        user: &TimelineUser,
+        pages: usize,
+        tweets: usize,
+        rate_limit: &RateLimit,
+    ) {
+        if self.quiet {
+            return;
         }
+        spinner.suspend(|| {
+            eprintln!(
+                "Progress: {}: {}, {} so far, rate limit {}/{}",
+                user,
+                count(pages, "page"),
+                count(tweets, "tweet"),
+                rate_limit.remaining,
+                rate_limit.limit
+            )
+        });
+    }
+}
 
-        Ok(())
+enum StopReason {
+    Deadline,
+    Depth,
+    EmptyPage,
+    Limit,
+    MaxDepth,
+    SinceId,
+}
+
+impl StopReason {
+    fn describe(&self) -> &'static str {
+        match self {
+            StopReason::Deadline => "reached the deadline",
+            StopReason::Depth => "reached the depth limit",
+            StopReason::EmptyPage => "reached the end of the timeline",
+            StopReason::Limit => "reached the tweet limit",
+            StopReason::MaxDepth => "reached the maximum depth",
+            StopReason::SinceId => "reached the last recorded tweet",
+        }
     }
 }
 
-fn print_non_fatal_error_or_bail(e: GenericError, screen_name: &str) -> Result<()> {
+enum TimelineUser {
+    ScreenName(String),
+    Id(u64),
+}
+
+impl TimelineUser {
+    fn to_user_id(&self) -> UserID {
+        match self {
+            TimelineUser::ScreenName(screen_name) => screen_name.clone().into(),
+            TimelineUser::Id(id) => (*id).into(),
+        }
+    }
+
+    /// Returns the parameter identifying the user in error messages.
+    fn param(&self) -> String {
+        match self {
+            TimelineUser::ScreenName(screen_name) => format!("screen_name=@{}", screen_name),
+            TimelineUser::Id(id) => format!("user_id={}", id),
+        }
+    }
+}
+
+impl fmt::Display for TimelineUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimelineUser::ScreenName(screen_name) => write!(f, "{}", screen_name),
+            TimelineUser::Id(id) => write!(f, "user ID {}", id),
+        }
+    }
+}
+
+fn print_skipped_by_deadline(users: usize) {
+    println!("Skipped {} due to the deadline.", count(users, "user"));
+}
+
+/// `user` is the parameter identifying the user, e.g. `screen_name=@phog`.
+fn print_non_fatal_error_or_bail(e: GenericError, user: &str) -> Result<()> {
     use egg_mode::error::Error as E;
 
     if let Some(egg_mode_error) = e.downcast_ref::<E>() {
         match egg_mode_error {
             E::TwitterError(_, twitter_errors) => {
-                eprintln!(
-                    "Error: Twitter error: {} (screen_name=@{})",
-                    twitter_errors, screen_name
-                );
+                eprintln!("Error: Twitter error: {} ({})", twitter_errors, user);
                 Ok(())
             }
             E::BadStatus(code) => {
                 let hint = if code == &hyper::StatusCode::UNAUTHORIZED {
-                    format!(
-                        " (screen_name=@{}; maybe the user is protected or suspended)",
-                        screen_name
-                    )
+                    format!(" ({}; maybe the user is protected or suspended)", user)
                 } else {
-                    format!(" (screen_name=@{})", screen_name)
+                    format!(" ({})", user)
                 };
                 eprintln!("Error: {}{}", egg_mode_error, hint);
                 Ok(())
@@ -212,6 +427,19 @@ fn print_non_fatal_error_or_bail(e: GenericError, screen_name: &str) -> Result<(
     }
 }
 
+/// Bails before calling the endpoint if its rate limit is known to be exhausted.
+fn bail_if_exhausted(client: &Client, endpoint: Endpoint) -> Result<()> {
+    if let Some(reset) = client.exhausted_until(endpoint) {
+        return Err(format_err!(
+            "Rate limit exceeded for {}, reset at {} .",
+            endpoint.path(),
+            Utc.timestamp(reset as i64, 0)
+        ))
+        .wrap_err(ErrorKind::Network);
+    }
+    Ok(())
+}
+
 fn find_since_id(tweets: &[Tweet], db: &Connection) -> Option<u64> {
     if let Some(tweet) = tweets.first() {
         if let Some(user) = &tweet.user {
@@ -1,13 +1,41 @@
-use crate::common::{count, print_rate_limit};
+use crate::common::{
+    api_timeout, count, is_dry_run, is_past_deadline, print_api_calls, print_rate_limit,
+    request_limiter,
+};
 use crate::config;
 use crate::database::Connection;
 use crate::result::*;
 use crate::twitter::{self, UrlMap};
 
-pub fn with_string(db: &Connection, text: String) -> Result<()> {
-    let url_map = extract_url(&text)?;
+/// Pastes larger than this are truncated before extracting URLs, unless
+/// `record.max-input-bytes` is set.
+pub const DEFAULT_MAX_INPUT_BYTES: usize = 1024 * 1024;
+
+/// Returns the number of tweets recorded or refreshed. If `refetch` is true, already recorded
+/// tweets are fetched again and their content is updated.
+pub fn with_string(db: &Connection, mut text: String, refetch: bool) -> Result<usize> {
+    let max_input_bytes = config::settings()?
+        .record
+        .max_input_bytes
+        .unwrap_or(DEFAULT_MAX_INPUT_BYTES);
+    let original_len = text.len();
+    if truncate_input(&mut text, max_input_bytes) {
+        eprintln!(
+            "Warning: Input is too large ({} bytes). Only the first {} bytes are used.",
+            original_len,
+            text.len()
+        );
+    }
+    with_url_map(db, extract_url(&text)?, refetch)
+}
+
+pub fn with_status_ids(db: &Connection, status_ids: &[u64], refetch: bool) -> Result<usize> {
+    with_url_map(db, UrlMap::from_status_ids(status_ids), refetch)
+}
+
+fn with_url_map(db: &Connection, url_map: UrlMap, refetch: bool) -> Result<usize> {
     if url_map.is_empty() {
-        return Ok(());
+        return Ok(0);
     }
 
     let status_ids: Vec<u64> = url_map.keys().copied().collect();
@@ -17,27 +45,62 @@ pub fn with_string(db: &Connection, text: String) -> Result<()> {
         result
     };
 
-    for status_id in &status_ids {
-        if !unseen_status_ids.contains(status_id) {
+    if !refetch {
+        for status_id in &status_ids {
+            if !unseen_status_ids.contains(status_id) {
+                let url = url_map.get(status_id).expect("status_id is in url_map");
+                println!("Already recorded {}", url);
+            }
+        }
+    }
+    let target_status_ids = if refetch {
+        &status_ids
+    } else {
+        &unseen_status_ids
+    };
+
+    if is_dry_run() {
+        for status_id in target_status_ids {
             let url = url_map.get(status_id).expect("status_id is in url_map");
-            println!("Already recorded {}", url);
+            if unseen_status_ids.contains(status_id) {
+                println!("Would fetch {}", url);
+            } else {
+                println!("Would refetch {}", url);
+            }
         }
+        return Ok(target_status_ids.len());
     }
 
-    let client = twitter::Client::new(config::credentials()?);
+    let client = twitter::Client::new(config::credentials()?)
+        .with_api_timeout(api_timeout()?)
+        .with_request_limiter(request_limiter()?);
+    let mut fetched = 0;
     let tweets = {
-        let mut acc = Vec::with_capacity(unseen_status_ids.len());
-        for chunk in unseen_status_ids.chunks(100) {
+        let mut acc = Vec::with_capacity(target_status_ids.len());
+        for chunk in target_status_ids.chunks(100) {
+            if is_past_deadline() {
+                break;
+            }
             let response = client.fetch_tweets(chunk)?;
             print_rate_limit(&response.rate_limit_status);
             acc.extend(response.response);
+            fetched += chunk.len();
         }
         acc
     };
+    print_api_calls(&client);
+    if fetched < target_status_ids.len() {
+        println!(
+            "Skipped {} due to the deadline.",
+            count(target_status_ids.len() - fetched, "tweet")
+        );
+    }
+    // Only the looked-up tweets can be told fetched or gone.
+    let target_status_ids = &target_status_ids[..fetched];
 
-    for status_id in unseen_status_ids {
-        let url = url_map.get(&status_id).expect("status_id is in url_map");
-        if tweets.iter().any(|t| t.id == status_id) {
+    for status_id in target_status_ids {
+        let url = url_map.get(status_id).expect("status_id is in url_map");
+        if tweets.iter().any(|t| t.id == *status_id) {
             println!("Fetched {}", url);
         } else {
             eprintln!("Warning: Could not fetch {}", url);
@@ -45,17 +108,90 @@ pub fn with_string(db: &Connection, text: String) -> Result<()> {
     }
 
     let n = db.insert_loose_tweets(&tweets)?;
+    if refetch {
+        // Statuses lookup omits tweets that are deleted or protected; keep them but mark them.
+        let gone_status_ids: Vec<u64> = target_status_ids
+            .iter()
+            .filter(|id| !unseen_status_ids.contains(id) && !tweets.iter().any(|t| t.id == **id))
+            .copied()
+            .collect();
+        let deleted = db.set_deleted_at(&gone_status_ids)?;
+        if deleted > 0 {
+            println!("Marked {} as deleted upstream.", count(deleted, "tweet"));
+        }
+
+        let seen_tweets: Vec<_> = tweets
+            .into_iter()
+            .filter(|t| !unseen_status_ids.contains(&t.id))
+            .collect();
+        let refreshed = db.update_tweet_contents(&seen_tweets)?;
+        println!(
+            "Recorded {}, refreshed {}.",
+            count(n, "new tweet"),
+            refreshed
+        );
+        return Ok(n + refreshed);
+    }
     println!("Recorded {}.", count(n, "tweet"));
 
-    Ok(())
+    Ok(n)
+}
+
+/// Truncates the text to at most `max_bytes` bytes at a char boundary. Returns true if the text
+/// was truncated.
+fn truncate_input(text: &mut String, max_bytes: usize) -> bool {
+    if text.len() <= max_bytes {
+        return false;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    log::trace!("truncated input; len={}", end);
+    true
 }
 
 fn extract_url(text: &str) -> Result<UrlMap> {
-    let (url_map, total_urls) = UrlMap::extract(text);
+    let (url_map, counts) = UrlMap::extract(text);
     println!(
         "Extracted {} out of {}.",
         count(url_map.len(), "unique status ID"),
-        count(total_urls, "tweet URL"),
+        count(counts.status_urls, "tweet URL"),
     );
+    // Only tweet URLs are recorded; tell why the rest of a mixed paste was left out.
+    if counts.other_twitter_urls > 0 {
+        println!(
+            "Skipped {} (profiles, etc.).",
+            count(counts.other_twitter_urls, "other Twitter URL")
+        );
+    }
+    if counts.non_twitter_urls > 0 {
+        println!(
+            "Skipped {}.",
+            count(counts.non_twitter_urls, "non-Twitter URL")
+        );
+    }
     Ok(url_map)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::truncate_input;
+
+    #[test]
+    fn oversized_input_is_truncated() {
+        let mut text = "https://twitter.com/a/status/1 ".repeat(100);
+        assert!(truncate_input(&mut text, 64));
+        assert_eq!(text.len(), 64);
+
+        let mut text = "short".to_owned();
+        assert!(!truncate_input(&mut text, 64));
+        assert_eq!(text, "short");
+
+        // Never splits a multibyte char.
+        let mut text = "あいう".to_owned();
+        assert!(truncate_input(&mut text, 4));
+        assert_eq!(text, "あ");
+    }
+}
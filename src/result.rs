@@ -1 +1,28 @@
+use std::fmt;
+
 pub use color_eyre::eyre::{bail, ensure, format_err, Error as GenericError, Result, WrapErr};
+
+/// Categories of errors that determine the exit status. Attach one to an error with
+/// `wrap_err` when the category cannot be told from the error type.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The config file or the login information is missing or invalid.
+    Config,
+    /// Twitter or the network could not be reached, or the rate limit was exceeded.
+    Network,
+    /// The command finished but some items failed.
+    PartialFailure,
+    /// The command stopped halfway at the time set with `--deadline`.
+    Deadline,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Config => write!(f, "Configuration error"),
+            ErrorKind::Network => write!(f, "Network error"),
+            ErrorKind::PartialFailure => write!(f, "Partial failure"),
+            ErrorKind::Deadline => write!(f, "Deadline exceeded"),
+        }
+    }
+}
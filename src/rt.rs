@@ -1,4 +1,5 @@
 use std::future::Future;
+use std::time::Duration;
 
 use once_cell::sync::Lazy;
 use tokio::runtime::{Builder, Runtime};
@@ -6,6 +7,10 @@ use tokio::runtime::{Builder, Runtime};
 static RUNTIME: Lazy<Runtime> =
     Lazy::new(|| Builder::new_multi_thread().enable_all().build().unwrap());
 
-pub fn block_on<F: Future>(future: F) -> F::Output {
-    RUNTIME.block_on(future)
+/// Blocks on the future, giving up once the timeout elapses.
+pub fn block_on_with_timeout<F: Future>(
+    future: F,
+    timeout: Duration,
+) -> Result<F::Output, tokio::time::error::Elapsed> {
+    RUNTIME.block_on(async { tokio::time::timeout(timeout, future).await })
 }
@@ -0,0 +1,121 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::SigId;
+
+use crate::exit_status;
+use crate::result::*;
+
+/// Signals that request a graceful shutdown: Ctrl-C and termination by systemd, Docker, etc.
+const SHUTDOWN_SIGNALS: [i32; 2] = [SIGINT, SIGTERM];
+
+/// A flag set when SIGINT or SIGTERM arrives. Long-running loops should check it and stop
+/// cooperatively. A second signal exits immediately in case the shutdown gets stuck.
+///
+/// The signals are caught until the flag is dropped.
+pub struct ShutdownFlag {
+    requested: Arc<AtomicBool>,
+    sig_ids: Vec<SigId>,
+}
+
+impl ShutdownFlag {
+    pub fn register() -> Result<Self> {
+        let requested = Arc::new(AtomicBool::new(false));
+        let mut sig_ids = Vec::with_capacity(SHUTDOWN_SIGNALS.len() * 2);
+        for signal in SHUTDOWN_SIGNALS {
+            // The shutdown must be registered first so that the first signal only sets the flag.
+            sig_ids.push(signal_hook::flag::register_conditional_shutdown(
+                signal,
+                exit_status::FAILURE,
+                requested.clone(),
+            )?);
+            sig_ids.push(signal_hook::flag::register(signal, requested.clone())?);
+        }
+        Ok(ShutdownFlag { requested, sig_ids })
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Runs `f`, calling `on_request` from another thread if a shutdown is requested meanwhile.
+    /// Use it to stop work that cannot check the flag by itself, e.g. a long SQLite statement.
+    pub fn run_interruptible<T>(
+        &self,
+        on_request: impl FnOnce() + Send,
+        f: impl FnOnce() -> T,
+    ) -> T {
+        // Stops the watcher even if `f` panics; otherwise the scope would wait for it forever.
+        struct Done<'a>(&'a AtomicBool);
+
+        impl Drop for Done<'_> {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(Ordering::SeqCst) {
+                    if self.is_requested() {
+                        on_request();
+                        return;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+            });
+            let _done = Done(&done);
+            f()
+        })
+    }
+}
+
+impl Drop for ShutdownFlag {
+    fn drop(&mut self) {
+        for sig_id in self.sig_ids.drain(..) {
+            signal_hook::low_level::unregister(sig_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use super::ShutdownFlag;
+
+    #[test]
+    fn run_interruptible_calls_on_request() {
+        let flag = ShutdownFlag {
+            requested: Arc::new(AtomicBool::new(false)),
+            sig_ids: vec![],
+        };
+        assert_eq!(flag.run_interruptible(|| panic!("not requested"), || 1), 1);
+
+        flag.requested.store(true, Ordering::SeqCst);
+        let stopped = AtomicBool::new(false);
+        flag.run_interruptible(
+            || stopped.store(true, Ordering::SeqCst),
+            || {
+                while !stopped.load(Ordering::SeqCst) {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn run_interruptible_propagates_panic() {
+        let flag = ShutdownFlag {
+            requested: Arc::new(AtomicBool::new(false)),
+            sig_ids: vec![],
+        };
+        let result = std::panic::catch_unwind(|| flag.run_interruptible(|| {}, || panic!("boom")));
+        assert!(result.is_err());
+    }
+}
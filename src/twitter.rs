@@ -1,6 +1,13 @@
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::io;
 use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, TimeZone, Utc};
 use egg_mode::auth::{self, KeyPair, Token};
 use egg_mode::user::UserID;
 use linkify::{LinkFinder, LinkKind};
@@ -9,13 +16,58 @@ use regex::Regex;
 use crate::config::Credentials;
 use crate::egg_mode_ext::{likes, lookup, user_timeline, Timeline};
 use crate::result::*;
-use crate::rt::block_on;
+use crate::rt::block_on_with_timeout;
 
 pub use crate::egg_mode_ext::Tweet;
-pub use egg_mode::Response;
+pub use egg_mode::{RateLimit, Response};
+
+/// Timeout of each API request. egg_mode's HTTP client has none, so a stalled connection would
+/// hang otherwise.
+pub const DEFAULT_API_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Twitter's rate limits are counted in 15-minute windows.
+pub const DEFAULT_REQUEST_WINDOW: Duration = Duration::from_secs(15 * 60);
 
 pub struct Client {
     token: Token,
+    rate_limits: RefCell<HashMap<Endpoint, RateLimit>>,
+    api_calls: RefCell<BTreeMap<Endpoint, usize>>,
+    api_timeout: Duration,
+    request_limiter: Option<Arc<RequestLimiter>>,
+}
+
+/// A token bucket shared by all API requests, so that they never exceed `max_requests` per
+/// `window` however many of them run at once. Requests beyond the rate wait for their turn.
+#[derive(Debug)]
+pub struct RequestLimiter {
+    max_requests: u32,
+    window: Duration,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Endpoint {
+    Likes,
+    Lookup,
+    UserTimeline,
+    VerifyCredentials,
+}
+
+impl Endpoint {
+    pub fn path(&self) -> &'static str {
+        match self {
+            Endpoint::Likes => "favorites/list",
+            Endpoint::Lookup => "statuses/lookup",
+            Endpoint::UserTimeline => "statuses/user_timeline",
+            Endpoint::VerifyCredentials => "account/verify_credentials",
+        }
+    }
 }
 
 impl Client {
@@ -24,55 +76,250 @@ impl Client {
             consumer: KeyPair::new(credentials.consumer_key, credentials.consumer_secret),
             access: KeyPair::new(credentials.access_token, credentials.access_token_secret),
         };
-        Client { token }
+        Client {
+            token,
+            rate_limits: RefCell::new(HashMap::new()),
+            api_calls: RefCell::new(BTreeMap::new()),
+            api_timeout: DEFAULT_API_TIMEOUT,
+            request_limiter: None,
+        }
+    }
+
+    pub fn with_api_timeout(self, api_timeout: Duration) -> Self {
+        Client {
+            api_timeout,
+            ..self
+        }
+    }
+
+    /// Paces all API requests made through the client. `None` means unlimited.
+    pub fn with_request_limiter(self, request_limiter: Option<Arc<RequestLimiter>>) -> Self {
+        Client {
+            request_limiter,
+            ..self
+        }
+    }
+
+    /// Returns the configured maximum number of requests per window, if any.
+    pub fn request_limit(&self) -> Option<(u32, Duration)> {
+        self.request_limiter
+            .as_ref()
+            .map(|limiter| (limiter.max_requests, limiter.window))
+    }
+
+    /// Blocks on the API request, failing with a timed-out `IOError` if it takes longer than the
+    /// API timeout. Waits first if the request limiter has no requests left.
+    fn block_on<T, F>(&self, future: F) -> egg_mode::error::Result<T>
+    where
+        F: Future<Output = egg_mode::error::Result<T>>,
+    {
+        if let Some(limiter) = &self.request_limiter {
+            limiter.acquire();
+        }
+        block_on_api(future, self.api_timeout)
+    }
+
+    /// Same as `block_on`, counting the request to the endpoint. All requests made through the
+    /// client go through here.
+    pub fn block_on_endpoint<T, F>(
+        &self,
+        endpoint: Endpoint,
+        future: F,
+    ) -> egg_mode::error::Result<T>
+    where
+        F: Future<Output = egg_mode::error::Result<T>>,
+    {
+        *self.api_calls.borrow_mut().entry(endpoint).or_default() += 1;
+        self.block_on(future)
+    }
+
+    /// Returns the number of requests made through the client by endpoint.
+    pub fn api_calls(&self) -> BTreeMap<Endpoint, usize> {
+        self.api_calls.borrow().clone()
     }
 
     pub fn fetch_likes<T: Into<UserID>>(&self, id: T) -> Result<Response<Vec<Tweet>>> {
-        let response = block_on(likes(id, &self.token))?;
+        let response = self.block_on_endpoint(Endpoint::Likes, likes(id, &self.token))?;
+        self.update_rate_limit(Endpoint::Likes, response.rate_limit_status);
         Ok(response)
     }
 
     pub fn fetch_tweets(&self, status_ids: &[u64]) -> Result<Response<Vec<Tweet>>> {
-        let response = block_on(lookup(status_ids.to_vec(), &self.token))?;
+        let response =
+            self.block_on_endpoint(Endpoint::Lookup, lookup(status_ids.to_vec(), &self.token))?;
+        self.update_rate_limit(Endpoint::Lookup, response.rate_limit_status);
         Ok(response)
     }
 
+    /// Remembers the last-seen rate limit of the endpoint. Timeline responses are not seen by the
+    /// client, so callers paginating a `Timeline` should pass its rate limits here.
+    pub fn update_rate_limit(&self, endpoint: Endpoint, rate_limit: RateLimit) {
+        log::trace!(
+            "updating rate limit; endpoint={}, remaining={}, reset={}",
+            endpoint.path(),
+            rate_limit.remaining,
+            rate_limit.reset
+        );
+        self.rate_limits.borrow_mut().insert(endpoint, rate_limit);
+    }
+
+    /// Returns the last-seen rate limits by endpoint.
+    pub fn rate_limits(&self) -> BTreeMap<Endpoint, RateLimit> {
+        self.rate_limits
+            .borrow()
+            .iter()
+            .map(|(&endpoint, &rate_limit)| (endpoint, rate_limit))
+            .collect()
+    }
+
+    /// Returns the reset time (in UNIX time) if the endpoint has no calls left in the current
+    /// rate limit window.
+    pub fn exhausted_until(&self, endpoint: Endpoint) -> Option<i32> {
+        let now = chrono::Utc::now().timestamp();
+        self.rate_limits
+            .borrow()
+            .get(&endpoint)
+            .filter(|rate_limit| rate_limit.remaining <= 0 && i64::from(rate_limit.reset) > now)
+            .map(|rate_limit| rate_limit.reset)
+    }
+
     pub fn user_timeline<T: Into<UserID>>(&self, id: T) -> Timeline {
         user_timeline(id, true, false, &self.token)
     }
 
     pub fn verify_tokens(&self) -> Result<()> {
-        Ok(block_on(auth::verify_tokens(&self.token)).map(|_| ())?)
+        self.verified_screen_name().map(|_| ())
+    }
+
+    /// Verifies the tokens and returns the screen name of the user they belong to.
+    pub fn verified_screen_name(&self) -> Result<String> {
+        let response = self.block_on_endpoint(
+            Endpoint::VerifyCredentials,
+            auth::verify_tokens(&self.token),
+        )?;
+        Ok(response.response.screen_name)
     }
 }
 
+impl RequestLimiter {
+    /// Returns `None` if `max_requests` is 0, i.e. unlimited.
+    pub fn new(max_requests: u32, window: Duration) -> Option<Self> {
+        if max_requests == 0 {
+            return None;
+        }
+        Some(RequestLimiter {
+            max_requests,
+            window,
+            bucket: Mutex::new(Bucket {
+                tokens: f64::from(max_requests),
+                refilled_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// Blocks until a request is allowed.
+    pub fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().expect("bucket must not be poisoned");
+                let now = Instant::now();
+                let refill = now.duration_since(bucket.refilled_at).as_secs_f64()
+                    / self.window.as_secs_f64()
+                    * f64::from(self.max_requests);
+                bucket.tokens = (bucket.tokens + refill).min(f64::from(self.max_requests));
+                bucket.refilled_at = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                self.window
+                    .mul_f64((1.0 - bucket.tokens) / f64::from(self.max_requests))
+            };
+            log::trace!("waiting for request limiter; wait={:?}", wait);
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Blocks on the API request, failing with a timed-out `IOError` if it takes longer than
+/// `timeout`.
+pub fn block_on_api<T, F>(future: F, timeout: Duration) -> egg_mode::error::Result<T>
+where
+    F: Future<Output = egg_mode::error::Result<T>>,
+{
+    block_on_with_timeout(future, timeout).unwrap_or_else(|_| {
+        log::trace!("API request timed out; timeout={:?}", timeout);
+        Err(egg_mode::error::Error::IOError(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("API request timed out after {} seconds", timeout.as_secs()),
+        )))
+    })
+}
+
+/// Returns true if the error is a timed-out API request.
+pub fn is_timeout(error: &egg_mode::error::Error) -> bool {
+    matches!(error, egg_mode::error::Error::IOError(e) if e.kind() == io::ErrorKind::TimedOut)
+}
+
 pub struct UrlMap {
     map: BTreeMap<u64, String>,
 }
 
+/// Numbers of URLs found in a text by kind.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct UrlCounts {
+    /// URLs of tweets, including duplicates.
+    pub status_urls: usize,
+    /// Twitter URLs other than tweets, e.g. profiles and searches.
+    pub other_twitter_urls: usize,
+    /// URLs of other sites, e.g. Mastodon or Bluesky.
+    pub non_twitter_urls: usize,
+}
+
 impl UrlMap {
-    pub fn extract(text: &str) -> (Self, usize) {
+    pub fn extract(text: &str) -> (Self, UrlCounts) {
         let mut map = BTreeMap::new();
         let re = Regex::new(
             r"(?i)https?://(?:mobile\.|www\.)?twitter\.com/(?:[^/]+|i/web)/status(?:es)?/(\d+)",
         )
         .expect("regex must compile");
+        let twitter_re = Regex::new(r"(?i)^https?://(?:[0-9a-z-]+\.)*twitter\.com(?:[/:?#]|$)")
+            .expect("regex must compile");
         let mut finder = LinkFinder::new();
         finder.kinds(&[LinkKind::Url]);
-        let mut extracted_urls = 0;
+        let mut counts = UrlCounts::default();
 
         for link in finder.links(text) {
-            extracted_urls += 1;
             let url = link.as_str();
-            if let Some(cap) = re.captures(url) {
-                let status_id = cap.get(1).expect("capture group must exist").as_str();
-                if let Ok(status_id) = status_id.parse::<u64>() {
-                    map.insert(status_id, url.to_owned());
-                }
+            let status_id = re.captures(url).and_then(|cap| {
+                cap.get(1)
+                    .expect("capture group must exist")
+                    .as_str()
+                    .parse::<u64>()
+                    .ok()
+            });
+            if let Some(status_id) = status_id {
+                counts.status_urls += 1;
+                map.insert(status_id, url.to_owned());
+            } else if twitter_re.is_match(url) {
+                counts.other_twitter_urls += 1;
+            } else {
+                counts.non_twitter_urls += 1;
             }
         }
 
-        (UrlMap { map }, extracted_urls)
+        (UrlMap { map }, counts)
+    }
+
+    pub fn from_status_ids(status_ids: &[u64]) -> Self {
+        let map = status_ids
+            .iter()
+            .map(|&status_id| {
+                let url = format!("https://twitter.com/i/web/status/{}", status_id);
+                (status_id, url)
+            })
+            .collect();
+        UrlMap { map }
     }
 }
 
@@ -84,9 +331,25 @@ impl Deref for UrlMap {
     }
 }
 
+/// Returns when the tweet was posted, derived from its Snowflake status ID. Tweets posted before
+/// November 2010 do not have Snowflake IDs and are reported as posted at the Twitter epoch.
+pub fn status_id_to_datetime(status_id: u64) -> DateTime<Utc> {
+    const TWITTER_EPOCH_MILLIS: i64 = 1288834974657;
+    Utc.timestamp_millis((status_id >> 22) as i64 + TWITTER_EPOCH_MILLIS)
+}
+
+/// Returns when the tweet was posted, or `None` if the status ID is older than Snowflake IDs.
+pub fn posted_at(status_id: u64) -> Option<DateTime<Utc>> {
+    const FIRST_SNOWFLAKE_ID: u64 = 29_700_859_247;
+    (status_id >= FIRST_SNOWFLAKE_ID).then(|| status_id_to_datetime(status_id))
+}
+
+/// Extracts screen names from the texts in lowercase, dropping duplicates but preserving the
+/// first-seen order. Screen names are case-insensitive on Twitter.
 pub fn extract_screen_names(texts: &[String]) -> Vec<String> {
-    let re = Regex::new(r"(?i)^(?:https?://(?:mobile\.|www\.)?twitter\.com/|@)?([0-9a-z_]+)")
+    let re = Regex::new(r"(?i)^(?:(?:https?://)?(?:mobile\.|www\.)?twitter\.com/|@)?([0-9a-z_]+)")
         .expect("regex must compile");
+    let mut seen = HashSet::new();
     texts
         .iter()
         .filter_map(|text| {
@@ -94,8 +357,167 @@ pub fn extract_screen_names(texts: &[String]) -> Vec<String> {
                 cap.get(1)
                     .expect("capture group must exist")
                     .as_str()
-                    .to_owned()
+                    .to_lowercase()
             })
         })
+        .filter(|screen_name| seen.insert(screen_name.clone()))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        block_on_api, extract_screen_names, is_timeout, status_id_to_datetime, Client, Endpoint,
+        RateLimit, RequestLimiter, UrlCounts, UrlMap,
+    };
+    use crate::config::Credentials;
+
+    fn strings(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|&s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn screen_names() {
+        assert_eq!(
+            extract_screen_names(&strings(&[
+                "user1",
+                "@user2",
+                "https://twitter.com/user3",
+                "https://mobile.twitter.com/user4/status/1",
+            ])),
+            strings(&["user1", "user2", "user3", "user4"])
+        );
+    }
+
+    #[test]
+    fn status_id_datetime() {
+        assert_eq!(
+            status_id_to_datetime(1212092628029698048).to_rfc3339(),
+            "2019-12-31T19:26:16.771+00:00"
+        );
+    }
+
+    #[test]
+    fn screen_names_deduplicated() {
+        assert_eq!(
+            extract_screen_names(&strings(&["@User", "user", "twitter.com/USER"])),
+            strings(&["user"])
+        );
+        assert_eq!(
+            extract_screen_names(&strings(&["b", "A", "@B", "a"])),
+            strings(&["b", "a"])
+        );
+    }
+
+    #[test]
+    fn urls_counted_by_kind() {
+        let text = "https://twitter.com/a/status/1 https://mobile.twitter.com/b/status/2?s=20 \
+            https://twitter.com/a/status/1 https://twitter.com/a https://twitter.com/search?q=x \
+            https://mastodon.social/@a/109 https://bsky.app/profile/a/post/x https://nottwitter.com/";
+        let (url_map, counts) = UrlMap::extract(text);
+        assert_eq!(url_map.keys().copied().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(
+            counts,
+            UrlCounts {
+                status_urls: 3,
+                other_twitter_urls: 2,
+                non_twitter_urls: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn api_request_times_out() {
+        let result = block_on_api(
+            std::future::pending::<egg_mode::error::Result<()>>(),
+            Duration::from_millis(10),
+        );
+        assert!(is_timeout(&result.unwrap_err()));
+
+        let result = block_on_api(async { Ok(1) }, Duration::from_secs(1));
+        assert_eq!(result.unwrap(), 1);
+    }
+
+    #[test]
+    fn requests_are_paced() {
+        assert!(RequestLimiter::new(0, Duration::from_secs(1)).is_none());
+
+        // 2 requests are allowed at once, then 1 every 50ms.
+        let limiter = RequestLimiter::new(2, Duration::from_millis(100)).unwrap();
+        let start = Instant::now();
+        limiter.acquire();
+        limiter.acquire();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limiter.acquire();
+        limiter.acquire();
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn requests_are_paced_across_threads() {
+        let limiter = Arc::new(RequestLimiter::new(2, Duration::from_millis(100)).unwrap());
+        let start = Instant::now();
+        let handles: Vec<_> = (0..6)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || limiter.acquire())
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // 2 at once, then 4 more at 50ms intervals.
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    fn client() -> Client {
+        Client::new(Credentials {
+            consumer_key: String::new(),
+            consumer_secret: String::new(),
+            access_token: String::new(),
+            access_token_secret: String::new(),
+        })
+    }
+
+    #[test]
+    fn api_calls_counted() {
+        let client = client();
+        assert!(client.api_calls().is_empty());
+        for endpoint in [
+            Endpoint::UserTimeline,
+            Endpoint::Likes,
+            Endpoint::UserTimeline,
+        ] {
+            client
+                .block_on_endpoint(endpoint, async { Ok(()) })
+                .unwrap();
+        }
+        let api_calls = client.api_calls();
+        assert_eq!(api_calls[&Endpoint::UserTimeline], 2);
+        assert_eq!(api_calls[&Endpoint::Likes], 1);
+        assert_eq!(api_calls.get(&Endpoint::Lookup), None);
+    }
+
+    #[test]
+    fn rate_limits() {
+        let client = client();
+        let reset = chrono::Utc::now().timestamp() as i32 + 60;
+        let rate_limit = |remaining| RateLimit {
+            limit: 900,
+            remaining,
+            reset,
+        };
+
+        assert!(client.rate_limits().is_empty());
+        client.update_rate_limit(Endpoint::Likes, rate_limit(10));
+        assert_eq!(client.exhausted_until(Endpoint::Likes), None);
+        client.update_rate_limit(Endpoint::Likes, rate_limit(0));
+        assert_eq!(client.exhausted_until(Endpoint::Likes), Some(reset));
+        assert_eq!(client.exhausted_until(Endpoint::UserTimeline), None);
+        assert_eq!(client.rate_limits()[&Endpoint::Likes].remaining, 0);
+    }
+}